@@ -1,24 +1,43 @@
+use std::collections::HashMap;
 use std::error::Error;
 
 use lambda_runtime::{error::HandlerError, lambda, Context};
+use log::error;
 use serde::{Deserialize, Serialize};
-use tokio::runtime::Runtime;
 
-use tg_bot_playground::start;
+use tg_bot_playground::{handle_webhook, send_digests};
 
+/// Source of the events sent by EventBridge rules, they trigger sending digests
+const SCHEDULED_EVENT_SOURCE: &str = "aws.events";
+
+/// API Gateway proxy request, Telegram update comes as its body,
+/// or EventBridge scheduled event
 #[derive(Deserialize, Serialize, Clone)]
 struct LambdaRequest {
-    id: Option<String>,
+    body: Option<String>,
+    source: Option<String>,
 }
 
+/// API Gateway proxy response
 #[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 struct LambdaResponse {
-    success: bool,
+    status_code: u16,
+    headers: HashMap<String, String>,
+    body: String,
 }
 
 impl LambdaResponse {
-    fn success() -> LambdaResponse {
-        LambdaResponse { success: true }
+    fn ok(body: Option<String>) -> LambdaResponse {
+        let mut headers = HashMap::new();
+        if body.is_some() {
+            headers.insert("Content-Type".to_string(), "application/json".to_string());
+        }
+        LambdaResponse {
+            status_code: 200,
+            headers,
+            body: body.unwrap_or_default(),
+        }
     }
 }
 
@@ -28,40 +47,57 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn lambda_handler(_req: LambdaRequest, _c: Context) -> Result<LambdaResponse, HandlerError> {
-    let mut runtime = Runtime::new().unwrap();
-    let result = runtime.block_on(start());
-    result.map_err(|err| HandlerError::from(err.as_ref()))?;
-    Ok(LambdaResponse::success())
+fn lambda_handler(req: LambdaRequest, _c: Context) -> Result<LambdaResponse, HandlerError> {
+    if req.source.as_deref() == Some(SCHEDULED_EVENT_SOURCE) {
+        return send_scheduled_digests();
+    }
+    let update = req.body.unwrap_or_default();
+    match handle_webhook(&update) {
+        Ok(reply) => Ok(LambdaResponse::ok(reply)),
+        // Telegram redelivers updates until it gets 200, so errors are only logged
+        Err(err) => {
+            error!("Error during handling update: {}", err);
+            Ok(LambdaResponse::ok(None))
+        }
+    }
+}
+
+fn send_scheduled_digests() -> Result<LambdaResponse, HandlerError> {
+    let result = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .map_err(|err| err.to_string())
+        .and_then(|mut rt| rt.block_on(send_digests()).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!("Error during sending digests: {}", err);
+    }
+    Ok(LambdaResponse::ok(None))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::{Local, Timelike};
 
     #[test]
     fn test_deserialize_request() {
-        let request = r#"{"id":"123-456-789", "anotherField": 123}"#;
-        let result: Result<LambdaRequest, _> = serde_json::from_str(request);
-        if let Err(ref err) = result {
-            eprintln!("{:?}", err);
-            result.unwrap();
-        }
+        let request = r#"{"resource": "/", "httpMethod": "POST", "body": "{\"update_id\":1}"}"#;
+        let result: LambdaRequest = serde_json::from_str(request).unwrap();
+        assert_eq!(result.body.as_deref(), Some(r#"{"update_id":1}"#));
+    }
+
+    #[test]
+    fn test_deserialize_scheduled_event() {
+        let request = r#"{"source": "aws.events", "detail-type": "Scheduled Event", "detail": {}}"#;
+        let result: LambdaRequest = serde_json::from_str(request).unwrap();
+        assert_eq!(result.source.as_deref(), Some(SCHEDULED_EVENT_SOURCE));
+        assert_eq!(result.body, None);
     }
 
     #[test]
     fn test_serialize_response() {
-        let value = LambdaResponse::success();
-        let result = serde_json::to_string(&value);
-        match result {
-            Err(ref err) => {
-                eprintln!("{:?}", err);
-                result.unwrap();
-            }
-            Ok(s) => {
-                assert_eq!(r#"{"success":true}"#, s);
-            }
-        }
+        let value = LambdaResponse::ok(None);
+        let result = serde_json::to_string(&value).unwrap();
+        assert_eq!(r#"{"statusCode":200,"headers":{},"body":""}"#, result);
     }
 }
@@ -0,0 +1,27 @@
+use std::fmt;
+
+/// Errors of the bot, their text is shown to users as is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Missing or invalid settings, e.g. env vars or categories
+    Config(String),
+    /// Records can't be read or written
+    Storage(String),
+    /// Invalid data in files or responses
+    Parse(String),
+    /// Remote service is unavailable or rejected a request
+    Network(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "Configuration error: {}", msg),
+            Error::Storage(msg) => write!(f, "Storage error: {}", msg),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::Network(msg) => write!(f, "Network error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,149 @@
+use std::env;
+
+use crate::error::Error;
+use crate::handler::categorizer::LexemeList;
+use crate::handler::tokenizer::Token;
+use crate::handler::user_settings::PerUser;
+
+const ACCOUNT_ENV: &str = "BUDGET_ACCOUNT";
+const USER_ACCOUNTS_ENV: &str = "BUDGET_USER_ACCOUNTS";
+
+pub trait AccountProvider {
+    fn accounts(&self) -> Result<Vec<Account>, Error>;
+}
+
+/// Card, cash or another source of money, recognized in messages by its lexemes
+#[derive(Debug, Clone, Deserialize)]
+pub struct Account {
+    pub name: String,
+    #[serde(with = "serde_with::rust::display_fromstr")]
+    lexemes: LexemeList,
+}
+
+impl Account {
+    #[allow(dead_code)]
+    pub fn new(name: String, lexemes: LexemeList) -> Self {
+        Account { name, lexemes }
+    }
+
+    pub(crate) fn match_word(&self, word: &str) -> bool {
+        self.lexemes.match_word(word)
+    }
+
+    /// The first account mentioned in the message
+    pub(crate) fn detect<'a>(accounts: &'a [Account], tokens: &[Token]) -> Option<&'a Account> {
+        tokens.iter().find_map(|token| match token {
+            Token::Word(word) => accounts.iter().find(|account| account.match_word(word)),
+            _ => None,
+        })
+    }
+
+    /// Accounts a transfer goes from and to. Money goes from the first mentioned account
+    /// to the second one, or from `default` to the only mentioned one.
+    pub(crate) fn detect_transfer(
+        accounts: &[Account],
+        tokens: &[Token],
+        default: Option<&str>,
+    ) -> (Option<String>, Option<String>) {
+        let mut mentioned: Vec<&str> = Vec::new();
+        for token in tokens {
+            if let Token::Word(word) = token {
+                if let Some(account) = accounts.iter().find(|account| account.match_word(word)) {
+                    if !mentioned.contains(&account.name.as_str()) {
+                        mentioned.push(&account.name);
+                    }
+                }
+            }
+        }
+        match mentioned.as_slice() {
+            [from, to, ..] => (Some(from.to_string()), Some(to.to_string())),
+            [to] if default.is_some() && default != Some(to) => {
+                (default.map(str::to_owned), Some(to.to_string()))
+            }
+            [from] => (Some(from.to_string()), None),
+            [] => (default.map(str::to_owned), None),
+        }
+    }
+}
+
+/// Account of records which mention none, configured per user
+pub type UserAccounts = PerUser<Option<String>>;
+
+impl UserAccounts {
+    /// Read accounts from env vars:
+    /// * `BUDGET_ACCOUNT` - default account name, records have no account if not set
+    /// * `BUDGET_USER_ACCOUNTS` - accounts per user, e.g. `alice:visa;bob:cash`
+    pub fn from_env() -> Self {
+        UserAccounts::from_config(
+            env::var(ACCOUNT_ENV).ok().as_deref(),
+            env::var(USER_ACCOUNTS_ENV).ok().as_deref(),
+        )
+    }
+
+    fn from_config(default: Option<&str>, users: Option<&str>) -> Self {
+        let account = |name: &str| Some(name.trim().to_owned()).filter(|name| !name.is_empty());
+        PerUser::new(default.and_then(account))
+            .with_users(users.unwrap_or_default(), |name| account(name).map(Some))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::tokenizer::tokenize;
+
+    use super::*;
+
+    #[test]
+    fn detect_first_mentioned_account() {
+        let accounts = vec![
+            Account::new("Visa".to_string(), "visa,card,карт".into()),
+            Account::new("Cash".to_string(), "cash,налич".into()),
+        ];
+        let detect = |text: &str| {
+            Account::detect(&accounts, &tokenize(text)).map(|account| account.name.clone())
+        };
+        assert_eq!(detect("coffee 3 by Card"), Some("Visa".to_string()));
+        assert_eq!(detect("такси 300 наличными"), Some("Cash".to_string()));
+        assert_eq!(detect("cash or card 5"), Some("Cash".to_string()));
+        assert_eq!(detect("coffee 3"), None);
+    }
+
+    #[test]
+    fn detect_transfer_accounts() {
+        let accounts = vec![
+            Account::new("Visa".to_string(), "visa,card,карт".into()),
+            Account::new("Cash".to_string(), "cash,налич".into()),
+        ];
+        let detect = |text: &str, default: Option<&str>| {
+            Account::detect_transfer(&accounts, &tokenize(text), default)
+        };
+        let name = |name: &str| Some(name.to_string());
+        assert_eq!(
+            detect("transfer 100 from card to cash", None),
+            (name("Visa"), name("Cash"))
+        );
+        assert_eq!(
+            detect("перевод 100 с наличных на карту", Some("Visa")),
+            (name("Cash"), name("Visa"))
+        );
+        assert_eq!(
+            detect("transfer 100 to cash", Some("Visa")),
+            (name("Visa"), name("Cash"))
+        );
+        assert_eq!(detect("transfer 100 to cash", None), (name("Cash"), None));
+        assert_eq!(
+            detect("transfer 100 card to visa", None),
+            (name("Visa"), None)
+        );
+        assert_eq!(detect("transfer 100", Some("Visa")), (name("Visa"), None));
+    }
+
+    #[test]
+    fn user_accounts_from_config() {
+        let accounts = UserAccounts::from_config(Some("cash"), Some("alice:visa;bob: "));
+        assert_eq!(accounts.for_user("alice").as_deref(), Some("visa"));
+        assert_eq!(accounts.for_user("bob").as_deref(), Some("cash"));
+        assert_eq!(accounts.for_user("carol").as_deref(), Some("cash"));
+        assert_eq!(UserAccounts::from_config(None, None).for_user("bob"), &None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt;
+
+use crate::handler::events::{Amount, BudgetRecord, RecordKind};
+
+/// Spending of a category within a month against its monthly limit
+#[derive(Debug, PartialEq, Eq)]
+pub struct BudgetStatus {
+    category: String,
+    spent: Amount,
+    limit: Amount,
+}
+
+impl BudgetStatus {
+    /// Sum up expenses of the `record` category, the limit is taken to be in the currency
+    /// of the record, so amounts in other currencies are left out
+    pub fn new(record: &BudgetRecord, limit: Amount, month_records: &[BudgetRecord]) -> Self {
+        let (_, currency) = record.total_amount();
+        let spent = month_records
+            .iter()
+            .filter(|r| r.kind == RecordKind::Expense && r.category == record.category)
+            .map(BudgetRecord::total_amount)
+            .filter_map(|(amount, c)| if c == currency { Some(amount) } else { None })
+            .fold(Amount::zero(), Amount::saturating_add);
+        BudgetStatus {
+            category: record.category.to_owned(),
+            spent,
+            limit,
+        }
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.spent > self.limit
+    }
+}
+
+impl fmt::Display for BudgetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} this month",
+            self.category, self.spent, self.limit
+        )?;
+        if let Some(percent) = self.spent.percent_of(self.limit) {
+            write!(f, " ({}%)", percent)?;
+        }
+        if self.is_exceeded() {
+            write!(
+                f,
+                "\nWarning: {} budget is exceeded by {}",
+                self.category,
+                self.spent.saturating_sub(self.limit)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::handler::events::{Currency, SourceRef, DEFAULT_LEDGER};
+
+    use super::*;
+
+    fn record(category: &str, amount: &str, currency: Currency) -> BudgetRecord {
+        let date = NaiveDate::from_ymd(2021, 3, 12);
+        BudgetRecord {
+            id: 1,
+            source: SourceRef::default(),
+            date,
+            category: category.to_string(),
+            amount: amount.parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency,
+            desc: String::new(),
+            user: "alice".to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: date,
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    #[test]
+    fn spent_within_limit() {
+        let added = record("Groceries", "12", Currency::RUB);
+        let mut income = record("Groceries", "1000", Currency::RUB);
+        income.kind = RecordKind::Income;
+        let records = vec![
+            record("Groceries", "300", Currency::RUB),
+            record("Groceries", "5", Currency::EUR),
+            record("Fruits", "50", Currency::RUB),
+            income,
+            added.clone(),
+        ];
+        let status = BudgetStatus::new(&added, "400".parse().unwrap(), &records);
+        assert!(!status.is_exceeded());
+        assert_eq!(status.to_string(), "Groceries: 312 of 400 this month (78%)");
+    }
+
+    #[test]
+    fn warn_when_limit_is_exceeded() {
+        let added = record("Groceries", "150", Currency::RUB);
+        let records = vec![record("Groceries", "300", Currency::RUB), added.clone()];
+        let status = BudgetStatus::new(&added, "400".parse().unwrap(), &records);
+        assert!(status.is_exceeded());
+        assert_eq!(
+            status.to_string(),
+            "Groceries: 450 of 400 this month (112%)\n\
+             Warning: Groceries budget is exceeded by 50"
+        );
+    }
+}
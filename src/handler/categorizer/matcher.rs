@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use log::warn;
+use regex::{Regex, RegexBuilder};
+
+use crate::handler::categorizer::stemmer::{is_stemmed, stem};
+
+/// Plain lexemes up to this length match any form of the whole word, otherwise `cand`
+/// would match "candle" as well as "candy". Without stemming of their language
+/// they stay prefixes, since whole words would miss the other forms.
+const MAX_SHORT_LEXEME_LEN: usize = 4;
+
+/// How a lexeme is compared with words, the kind is told by its spelling
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Beginning of a word or another form of it like `хлеба`, the default
+    Prefix { text: String, stem: String },
+    /// Any form of the whole word, written like `=candy` or short like `cand`
+    Word(String),
+    /// Regular expression in any case, written like `/^tax[iy]$/`
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+struct Lexeme {
+    /// Lexeme as it is written in categories
+    text: String,
+    matcher: Matcher,
+}
+
+impl PartialEq for Lexeme {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Lexeme {}
+
+impl Lexeme {
+    /// Empty lexemes are skipped, otherwise they would match any word.
+    /// Invalid regular expressions are skipped with a warning.
+    fn parse(text: &str) -> Option<Lexeme> {
+        let text = text.trim();
+        if text.trim_start_matches('=').trim().is_empty() {
+            return None;
+        }
+        // Patterns keep their case, since `\D` is not `\d`
+        if let Some(pattern) = regex_pattern(text) {
+            return match RegexBuilder::new(pattern).case_insensitive(true).build() {
+                Ok(regex) => Some(Lexeme {
+                    text: text.to_owned(),
+                    matcher: Matcher::Regex(regex),
+                }),
+                Err(err) => {
+                    warn!("Skip invalid lexeme {}: {}", text, err);
+                    None
+                }
+            };
+        }
+        let text = text.to_lowercase();
+        let matcher = match text.strip_prefix('=') {
+            Some(word) => Matcher::Word(stem(word.trim())),
+            None if text.chars().count() <= MAX_SHORT_LEXEME_LEN && is_stemmed(&text) => {
+                Matcher::Word(stem(&text))
+            }
+            None => Matcher::Prefix {
+                stem: stem(&text),
+                text: text.clone(),
+            },
+        };
+        Some(Lexeme { text, matcher })
+    }
+
+    /// Lowercase `word` matches as it is written
+    fn match_word(&self, word: &str, word_stem: &str) -> bool {
+        match &self.matcher {
+            Matcher::Prefix { text, stem } => word.starts_with(text.as_str()) || word_stem == stem,
+            Matcher::Word(stem) => word_stem == stem,
+            Matcher::Regex(regex) => regex.is_match(word),
+        }
+    }
+
+    /// Lowercase `word` matches with a few typos, longer lexemes allow more of them
+    fn match_word_with_typos(&self, word: &str, word_stem: &str) -> bool {
+        match &self.matcher {
+            Matcher::Prefix { text, .. } => {
+                let typos = max_typos(text);
+                let len = text.chars().count();
+                // The beginning of the word may be longer or shorter because of the typos
+                (len.saturating_sub(typos)..=len + typos).any(|len| {
+                    let beginning: String = word.chars().take(len).collect();
+                    beginning.chars().count() == len
+                        && strsim::levenshtein(&beginning, text) <= typos
+                })
+            }
+            Matcher::Word(stem) => strsim::levenshtein(word_stem, stem) <= max_typos(stem),
+            Matcher::Regex(_) => false,
+        }
+    }
+}
+
+/// Number of typos allowed in a word like the lexeme, short ones have to be exact
+fn max_typos(lexeme: &str) -> usize {
+    match lexeme.chars().count() {
+        0..=4 => 0,
+        5..=8 => 1,
+        _ => 2,
+    }
+}
+
+/// Pattern of a lexeme written between slashes
+fn regex_pattern(text: &str) -> Option<&str> {
+    text.strip_prefix('/')
+        .and_then(|text| text.strip_suffix('/'))
+        .filter(|pattern| !pattern.is_empty())
+}
+
+/// Lexemes of a category or an account separated by commas, like `cand,=sweet,/^tort$/`.
+/// Commas are allowed inside regular expressions.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LexemeList(Vec<Lexeme>);
+
+impl LexemeList {
+    /// Word matches if it starts with any of prefix lexemes,
+    /// it is another form of any of them or it matches any other lexeme
+    pub(crate) fn match_word(&self, word: &str) -> bool {
+        let word = word.trim().to_lowercase();
+        let word_stem = stem(&word);
+        self.0.iter().any(|l| l.match_word(&word, &word_stem))
+    }
+
+    /// Word matches any of lexemes with a few typos, e.g. "bananna" matches `banana`
+    pub(crate) fn match_word_with_typos(&self, word: &str) -> bool {
+        let word = word.trim().to_lowercase();
+        let word_stem = stem(&word);
+        self.0
+            .iter()
+            .any(|l| l.match_word_with_typos(&word, &word_stem))
+    }
+}
+
+/// Split lexemes on commas except ones inside regular expressions,
+/// which end with a slash followed by a comma or the end of text
+fn split_lexemes(text: &str) -> Vec<&str> {
+    let mut lexemes = Vec::new();
+    let mut rest = text;
+    loop {
+        let trimmed = rest.trim_start();
+        let end = match trimmed.strip_prefix('/') {
+            Some(pattern) => pattern
+                .match_indices('/')
+                .map(|(i, _)| i + 2)
+                .find(|&end| {
+                    let after = trimmed[end..].trim_start();
+                    after.is_empty() || after.starts_with(',')
+                })
+                .unwrap_or_else(|| trimmed.find(',').unwrap_or(trimmed.len())),
+            None => trimmed.find(',').unwrap_or(trimmed.len()),
+        };
+        lexemes.push(&trimmed[..end]);
+        match trimmed[end..].find(',') {
+            Some(comma) => rest = &trimmed[end + comma + 1..],
+            None => return lexemes,
+        }
+    }
+}
+
+impl From<&str> for LexemeList {
+    fn from(text: &str) -> Self {
+        let lexemes = split_lexemes(text).into_iter().filter_map(Lexeme::parse);
+        LexemeList(lexemes.collect())
+    }
+}
+
+impl FromStr for LexemeList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(From::<&str>::from(s))
+    }
+}
+
+impl fmt::Display for LexemeList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let str_vec: Vec<_> = self.0.iter().map(|x| x.text.as_str()).collect();
+        write!(f, "{}", str_vec.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(lexemes: &str, word: &str) -> bool {
+        LexemeList::from(lexemes).match_word(word)
+    }
+
+    #[test]
+    fn split_lexemes_with_regex() {
+        assert_eq!(
+            split_lexemes("cand, =sweet ,/^a{1,2}b$/, /x/y/,tort"),
+            vec!["cand", "=sweet ", "/^a{1,2}b$/", "/x/y/", "tort"]
+        );
+        assert_eq!(split_lexemes("/unclosed,tort"), vec!["/unclosed", "tort"]);
+        assert_eq!(split_lexemes(""), vec![""]);
+    }
+
+    #[test]
+    fn prefix_lexemes() {
+        assert!(matches("banan", "Bananas"));
+        assert!(matches("chocol", "chocolate"));
+        assert!(!matches("candy", "candle"));
+    }
+
+    #[test]
+    #[cfg(feature = "stem-en")]
+    fn short_lexemes_match_other_forms() {
+        assert!(matches("cand", "Candy"));
+        assert!(matches("cand", "candies"));
+        assert!(!matches("cand", "candle"));
+        assert!(matches("tea", "tea"));
+        assert!(!matches("tea", "teapot"));
+    }
+
+    #[test]
+    #[cfg(feature = "stem-ru")]
+    fn prefix_lexemes_match_other_forms() {
+        assert!(matches("хлеба", "хлебом"));
+        assert!(matches("такси", "такса"));
+        assert!(!matches("хлеба", "хлебница"));
+    }
+
+    #[test]
+    #[cfg(feature = "stem-en")]
+    fn whole_word_lexemes() {
+        assert!(matches("=candy", "candies"));
+        assert!(matches("=candy", "Candy"));
+        assert!(!matches("=candy", "candle"));
+    }
+
+    #[test]
+    fn regex_lexemes() {
+        assert!(matches("/^tax[iy]$/", "Taxi"));
+        assert!(!matches("/^tax[iy]$/", "taxes"));
+        assert!(matches("/^a{1,2}b$/", "aab"));
+        assert!(matches(r"/^\D+$/", "TEA"));
+        // Invalid regex is skipped
+        assert_eq!(LexemeList::from("/(/,tea, =").to_string(), "tea");
+    }
+
+    #[test]
+    fn match_with_typos() {
+        let lexemes = LexemeList::from("banana,=chocolate,tea");
+        assert!(!lexemes.match_word("bananna"));
+        assert!(lexemes.match_word_with_typos("bananna"));
+        assert!(lexemes.match_word_with_typos("bnana"));
+        assert!(lexemes.match_word_with_typos("chocolatte"));
+        assert!(!lexemes.match_word_with_typos("banner"));
+        // Short lexemes have to be exact
+        assert!(!lexemes.match_word_with_typos("tee"));
+    }
+
+    #[test]
+    fn display_lexemes_as_written() {
+        let text = "cand,=sweet,/^a{1,2}b$/";
+        assert_eq!(LexemeList::from(text).to_string(), text);
+    }
+}
@@ -1,27 +1,56 @@
 use std::cmp::Ordering;
-use std::collections::{BTreeSet, BinaryHeap};
-use std::fmt;
+use std::collections::{BTreeSet, HashMap};
 use std::iter::FromIterator;
-use std::str::FromStr;
 
-use serde::export::Formatter;
+use log::warn;
 
-use crate::handler::tokenizer::{tokenize, MessageTokens, Token};
+use crate::error::Error;
+use crate::handler::events::{Amount, RecordKind};
+use crate::handler::tokenizer::{tokenize, Token};
 
+pub use self::matcher::LexemeList;
+
+mod matcher;
+mod stemmer;
 #[cfg(test)]
 mod tests;
 
+/// Shorter words like prepositions are never learned
+const MIN_LEARNED_WORD_LEN: usize = 3;
+/// Category names with up to this many typos are suggested instead of a misspelled one
+const MAX_NAME_TYPOS: usize = 2;
+/// Separates names in the path of a category, like `Food/Groceries/Dairy`
+pub const PATH_SEPARATOR: char = '/';
+
 pub trait CategoryProvider {
-    fn categories(&self) -> Vec<Category>;
+    fn categories(&self) -> Result<Vec<Category>, Error>;
+}
+
+/// Words learned from categories users named for their records
+pub trait LearnedWordsProvider {
+    /// Learned words in order they were learned, a later one overrides an earlier one
+    fn learned_words(&self) -> Result<Vec<LearnedWord>, Error>;
+    fn learn_word(&mut self, word: &LearnedWord) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LearnedWord {
+    pub word: String,
+    pub category: String,
 }
 
 pub struct Categorizer {
     categories: Option<BTreeSet<Category>>,
+    /// Category names by lowercase words, they are used when no lexeme matches
+    learned: HashMap<String, String>,
 }
 
 impl Categorizer {
     pub(crate) fn new() -> Self {
-        Categorizer { categories: None }
+        Categorizer {
+            categories: None,
+            learned: HashMap::new(),
+        }
     }
 
     #[allow(dead_code)]
@@ -29,30 +58,100 @@ impl Categorizer {
         self.classify(&tokenize(text))
     }
 
-    pub(crate) fn classify(&self, tokens: &MessageTokens) -> Option<&Category> {
+    // Categories are ordered by priority and name, caches of regex lexemes don't change it
+    #[allow(clippy::mutable_key_type)]
+    pub(crate) fn classify(&self, tokens: &[Token]) -> Option<&Category> {
         let categories = self
             .categories
             .as_ref()
             .expect("categories must be loaded before classify text");
 
-        let mut results = BinaryHeap::new();
-        for token in tokens {
-            if let Token::Word(word) = token {
-                for c in categories.iter() {
-                    if c.match_word(word) {
-                        results.push(c);
+        // Words with typos are matched only when there are no exact matches.
+        // Parents of other matched categories are left out, so the most specific one wins.
+        let matched = |is_match: &dyn Fn(&Category, &str) -> bool| {
+            let mut results = Vec::new();
+            for token in tokens {
+                if let Token::Word(word) = token {
+                    for c in categories.iter() {
+                        if is_match(c, word) {
+                            results.push(c);
+                        }
                     }
                 }
             }
-        }
+            results
+                .iter()
+                .filter(|c| !results.iter().any(|other| other.is_child_of(c)))
+                .max()
+                .copied()
+        };
+
+        matched(&Category::match_word)
+            .or_else(|| matched(&Category::match_word_with_typos))
+            .or_else(|| self.learned_category(tokens))
+            .or_else(|| self.default_category())
+    }
+
+    /// Category learned for the first word which is known
+    fn learned_category(&self, tokens: &[Token]) -> Option<&Category> {
+        tokens.iter().find_map(|token| match token {
+            Token::Word(word) => self
+                .learned
+                .get(&word.to_lowercase())
+                .and_then(|name| self.category(name)),
+            _ => None,
+        })
+    }
 
-        results.pop().or_else(|| self.default_category())
+    /// Remember the words for the category, except ones matching lexemes of any category.
+    /// Only words which weren't learned for the category before are returned.
+    pub(crate) fn learn(&mut self, words: &[&str], category: &str) -> Vec<LearnedWord> {
+        let mut learned = Vec::new();
+        for word in words {
+            let word = word.to_lowercase();
+            let is_matched = self
+                .categories
+                .iter()
+                .flatten()
+                .any(|c| c.match_word(&word));
+            if word.chars().count() < MIN_LEARNED_WORD_LEN
+                || is_matched
+                || self.learned.get(&word).map(String::as_str) == Some(category)
+            {
+                continue;
+            }
+            self.learned.insert(word.clone(), category.to_owned());
+            learned.push(LearnedWord {
+                word,
+                category: category.to_owned(),
+            });
+        }
+        learned
     }
 
-    pub(crate) fn load_categories<P: CategoryProvider>(&mut self, provider: &P) {
-        for c in provider.categories() {
+    /// Categories are named by their full paths like `Food/Groceries`
+    pub(crate) fn load_categories<P: CategoryProvider + ?Sized>(
+        &mut self,
+        provider: &P,
+    ) -> Result<(), Error> {
+        let categories = provider.categories()?;
+        let paths: Vec<_> = categories.iter().map(|c| c.path(&categories)).collect();
+        for (mut c, path) in categories.into_iter().zip(paths) {
+            c.name = path;
             self.add_category(c);
         }
+        Ok(())
+    }
+
+    pub(crate) fn load_learned_words<P: LearnedWordsProvider + ?Sized>(
+        &mut self,
+        provider: &P,
+    ) -> Result<(), Error> {
+        for learned in provider.learned_words()? {
+            self.learned
+                .insert(learned.word.to_lowercase(), learned.category);
+        }
+        Ok(())
     }
 
     fn add_category(&mut self, category: Category) -> bool {
@@ -65,17 +164,92 @@ impl Categorizer {
         }
     }
 
+    /// Category with the path or the own name in any case, paths go first
+    pub(crate) fn category(&self, name: &str) -> Option<&Category> {
+        let name = name.to_lowercase();
+        self.find_by_name(|category_name| category_name.to_lowercase() == name)
+    }
+
+    /// Category named in a message like `#eating_out` or `#food/dairy`, spaces, dashes
+    /// and underscores are ignored along with the case
+    pub(crate) fn named_category(&self, name: &str) -> Option<&Category> {
+        let name = normalize_name(name);
+        self.find_by_name(|category_name| normalize_name(category_name) == name)
+    }
+
+    fn find_by_name<F: Fn(&str) -> bool>(&self, is_name: F) -> Option<&Category> {
+        let categories = self.categories.iter().flatten();
+        categories
+            .clone()
+            .find(|category| is_name(&category.name))
+            .or_else(|| {
+                categories
+                    .clone()
+                    .find(|category| is_name(category.own_name()))
+            })
+    }
+
+    /// Categories with names close to the misspelled one or starting with it,
+    /// the closest one goes first
+    pub(crate) fn similar_categories(&self, name: &str) -> Vec<&Category> {
+        let name = normalize_name(name);
+        let mut similar: Vec<_> = self
+            .categories
+            .iter()
+            .flatten()
+            .filter_map(|category| {
+                let names = [&category.name, category.own_name()];
+                names
+                    .iter()
+                    .map(|other| normalize_name(other))
+                    .filter_map(|other| {
+                        let distance = strsim::levenshtein(&name, &other);
+                        if distance <= MAX_NAME_TYPOS || other.starts_with(&name) {
+                            Some(distance)
+                        } else {
+                            None
+                        }
+                    })
+                    .min()
+                    .map(|distance| (distance, category))
+            })
+            .collect();
+        similar.sort_by_key(|(distance, _)| *distance);
+        similar.into_iter().map(|(_, category)| category).collect()
+    }
+
     fn default_category(&self) -> Option<&Category> {
         self.categories.as_ref().and_then(|c| c.range(..).next())
     }
 }
 
+/// The last name of the category path
+pub(crate) fn own_name(path: &str) -> &str {
+    path.rsplit(PATH_SEPARATOR).next().unwrap_or(path)
+}
+
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Category {
     pub name: String,
     priority: i32,
     #[serde(with = "serde_with::rust::display_fromstr")]
     lexemes: LexemeList,
+    /// Kind of records in the category, like income for a salary
+    #[serde(default, with = "serde_with::rust::display_fromstr")]
+    pub kind: RecordKind,
+    /// Monthly budget of the category, if any
+    #[serde(default)]
+    pub limit: Option<Amount>,
+    /// Name or path of the parent category, the name may be a path like `Food/Groceries` too
+    #[serde(default)]
+    pub parent: Option<String>,
 }
 
 impl PartialEq for Category {
@@ -88,7 +262,7 @@ impl Eq for Category {}
 
 impl PartialOrd for Category {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(&other))
+        Some(self.cmp(other))
     }
 }
 
@@ -108,49 +282,64 @@ impl Category {
             name,
             priority,
             lexemes,
+            kind: RecordKind::Expense,
+            limit: None,
+            parent: None,
         }
     }
-    fn match_word(&self, word: &str) -> bool {
-        let word = word.trim().to_lowercase();
-        self.lexemes.0.iter().any(|l| word.starts_with(&l.0))
-    }
-}
 
-#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
-struct Lexeme(String);
+    pub fn with_kind(self, kind: RecordKind) -> Self {
+        Category { kind, ..self }
+    }
 
-impl From<&str> for Lexeme {
-    fn from(text: &str) -> Self {
-        Lexeme(text.trim().to_lowercase())
+    pub fn with_limit(self, limit: Option<Amount>) -> Self {
+        Category { limit, ..self }
     }
-}
 
-impl fmt::Display for Lexeme {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+    pub fn with_parent(self, parent: Option<String>) -> Self {
+        let parent = parent.filter(|parent| !parent.trim().is_empty());
+        Category { parent, ..self }
     }
-}
 
-#[derive(Debug, PartialEq, Eq, Clone, Deserialize)]
-pub struct LexemeList(Vec<Lexeme>);
+    /// Name without the path of parents
+    pub(crate) fn own_name(&self) -> &str {
+        own_name(&self.name)
+    }
 
-impl From<&str> for LexemeList {
-    fn from(text: &str) -> Self {
-        LexemeList(text.split(',').map(Lexeme::from).collect())
+    fn is_child_of(&self, parent: &Category) -> bool {
+        self.name.len() > parent.name.len()
+            && self.name.starts_with(&parent.name)
+            && self.name[parent.name.len()..].starts_with(PATH_SEPARATOR)
     }
-}
 
-impl FromStr for LexemeList {
-    type Err = String;
+    /// Full path of the category with paths of its parents looked up among `categories`,
+    /// unknown parents are kept as they are written and loops are cut
+    fn path(&self, categories: &[Category]) -> String {
+        let mut path = self.name.trim().to_owned();
+        let mut visited = vec![self.name.trim().to_lowercase()];
+        let mut parent = self.parent.as_deref();
+        while let Some(name) = parent.map(str::trim).filter(|name| !name.is_empty()) {
+            let key = name.to_lowercase();
+            if visited.contains(&key) {
+                warn!("Parents of category {} make a loop", self.name);
+                break;
+            }
+            let category = categories
+                .iter()
+                .find(|c| c.name.trim().to_lowercase() == key);
+            let name = category.map_or(name, |c| c.name.trim());
+            path = format!("{}{}{}", name, PATH_SEPARATOR, path);
+            parent = category.and_then(|c| c.parent.as_deref());
+            visited.push(key);
+        }
+        path
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(From::<&str>::from(s))
+    fn match_word(&self, word: &str) -> bool {
+        self.lexemes.match_word(word)
     }
-}
 
-impl fmt::Display for LexemeList {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let str_vec: Vec<_> = self.0.iter().map(|x| x.0.as_str()).collect();
-        write!(f, "{}", str_vec.join(","))
+    fn match_word_with_typos(&self, word: &str) -> bool {
+        self.lexemes.match_word_with_typos(word)
     }
 }
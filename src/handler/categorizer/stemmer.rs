@@ -0,0 +1,89 @@
+/// Endings of English words which are cut off, longer ones go first
+#[cfg(feature = "stem-en")]
+const ENGLISH_ENDINGS: &[&str] = &["ings", "ing", "ies", "ied", "es", "ed", "s", "e", "y"];
+
+/// Endings of Russian nouns and adjectives which are cut off, longer ones go first
+#[cfg(feature = "stem-ru")]
+const RUSSIAN_ENDINGS: &[&str] = &[
+    "иями", "ями", "ами", "ого", "его", "ому", "ему", "ыми", "ими", "ией", "ой", "ей", "ий", "ый",
+    "ая", "яя", "ое", "ее", "ие", "ые", "ах", "ях", "ам", "ям", "ом", "ем", "ов", "ев", "ью", "ия",
+    "ья", "а", "я", "о", "е", "ы", "и", "у", "ю", "ь", "й",
+];
+
+/// Stems are never shorter than this, so short words are kept as is
+const MIN_STEM_LEN: usize = 3;
+
+/// Lowercase word without its ending, so different forms of the word have the same stem,
+/// like "хлеб" and "хлеба". It is a rough cut rather than a dictionary stem.
+///
+/// Words of languages which are not compiled in are only lowercased.
+pub(crate) fn stem(word: &str) -> String {
+    let word = word.to_lowercase();
+    let stem_len = endings(&word)
+        .iter()
+        .filter(|ending| word.ends_with(*ending))
+        .map(|ending| word.len() - ending.len())
+        .find(|&len| word[..len].chars().count() >= MIN_STEM_LEN && !is_double_s(&word, len));
+    match stem_len {
+        Some(len) => word[..len].to_owned(),
+        None => word,
+    }
+}
+
+/// Whether the language of the lowercase word is compiled in, so its forms have one stem
+pub(crate) fn is_stemmed(word: &str) -> bool {
+    !endings(word).is_empty()
+}
+
+/// Endings of the word language told by its first letter
+fn endings(word: &str) -> &'static [&'static str] {
+    match word.chars().next() {
+        #[cfg(feature = "stem-ru")]
+        Some(c) if is_cyrillic(c) => RUSSIAN_ENDINGS,
+        #[cfg(feature = "stem-en")]
+        Some(c) if c.is_ascii_alphabetic() => ENGLISH_ENDINGS,
+        _ => &[],
+    }
+}
+
+#[cfg(feature = "stem-ru")]
+fn is_cyrillic(c: char) -> bool {
+    ('\u{0400}'..='\u{04FF}').contains(&c)
+}
+
+/// Words like "glass" keep their last "s"
+fn is_double_s(word: &str, stem_len: usize) -> bool {
+    &word[stem_len..] == "s" && word[..stem_len].ends_with('s')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "stem-en")]
+    fn english_stems() {
+        assert_eq!(stem("Candies"), "cand");
+        assert_eq!(stem("candy"), "cand");
+        assert_eq!(stem("bananas"), "banana");
+        assert_eq!(stem("apples"), "appl");
+        assert_eq!(stem("glass"), "glass");
+        assert_eq!(stem("tea"), "tea");
+    }
+
+    #[test]
+    #[cfg(feature = "stem-ru")]
+    fn russian_stems() {
+        assert_eq!(stem("хлеба"), "хлеб");
+        assert_eq!(stem("Хлебом"), "хлеб");
+        assert_eq!(stem("такси"), "такс");
+        assert_eq!(stem("молочными"), "молочн");
+        assert_eq!(stem("сыр"), "сыр");
+    }
+
+    #[test]
+    fn other_words_are_lowercased() {
+        assert_eq!(stem("ÄPFEL"), "äpfel");
+        assert_eq!(stem("42"), "42");
+    }
+}
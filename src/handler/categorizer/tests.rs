@@ -13,6 +13,9 @@ fn fake_category_sweets() -> Category {
         name: String::from("Sweets"),
         priority: 10,
         lexemes: "cand,sweet,chocolate".into(),
+        kind: RecordKind::Expense,
+        limit: None,
+        parent: None,
     }
 }
 
@@ -21,6 +24,9 @@ fn fake_category_fruits() -> Category {
         name: String::from("Fruits"),
         priority: 20,
         lexemes: "apple,banana,orange".into(),
+        kind: RecordKind::Expense,
+        limit: None,
+        parent: None,
     }
 }
 
@@ -29,6 +35,9 @@ fn fake_category_others() -> Category {
         name: String::from("Others"),
         priority: 99999,
         lexemes: "other,misc".into(),
+        kind: RecordKind::Expense,
+        limit: None,
+        parent: None,
     }
 }
 
@@ -77,3 +86,170 @@ fn category_match_word_ignore_case() {
         "candy should be treated as sweets"
     );
 }
+
+#[test]
+fn empty_lexemes_match_nothing() {
+    let category = Category::new("Others".to_string(), 1, "misc, ,".into());
+    assert!(category.match_word("misc"));
+    assert!(!category.match_word("tea"));
+}
+
+#[test]
+fn classify_with_learned_words() {
+    let mut c = fake_categorizer();
+    let learned = c.learn(&["Fresh", "bananas", "at", "Ashan"], "Fruits");
+    assert_eq!(
+        learned,
+        vec![
+            LearnedWord {
+                word: "fresh".to_string(),
+                category: "Fruits".to_string()
+            },
+            LearnedWord {
+                word: "ashan".to_string(),
+                category: "Fruits".to_string()
+            }
+        ]
+    );
+    assert!(c.learn(&["ashan"], "Fruits").is_empty());
+
+    assert_eq!(c.classify_msg("10 ASHAN").unwrap().name, "Fruits");
+    // Lexemes go first
+    assert_eq!(c.classify_msg("10 candy at ashan").unwrap().name, "Sweets");
+    assert_eq!(c.classify_msg("10 for tea").unwrap().name, "Others");
+}
+
+#[test]
+fn later_learned_word_overrides_earlier() {
+    struct Learned;
+
+    impl LearnedWordsProvider for Learned {
+        fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+            let word = |category: &str| LearnedWord {
+                word: "Ashan".to_string(),
+                category: category.to_string(),
+            };
+            Ok(vec![word("Fruits"), word("Sweets"), word("Unknown")])
+        }
+
+        fn learn_word(&mut self, _word: &LearnedWord) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    let mut c = fake_categorizer();
+    c.load_learned_words(&Learned).unwrap();
+    // Learned words of missing categories are ignored
+    assert_eq!(c.classify_msg("10 ashan").unwrap().name, "Others");
+    c.learn(&["ashan"], "Sweets");
+    assert_eq!(c.classify_msg("10 ashan").unwrap().name, "Sweets");
+}
+
+#[test]
+fn named_category_ignores_case_and_separators() {
+    let mut c = fake_categorizer();
+    c.add_category(Category::new("Eating out".to_string(), 5, "".into()));
+    assert_eq!(c.named_category("FRUITS").unwrap().name, "Fruits");
+    assert_eq!(c.named_category("eating_out").unwrap().name, "Eating out");
+    assert_eq!(c.named_category("eating-Out").unwrap().name, "Eating out");
+    assert!(c.named_category("fruit").is_none());
+}
+
+#[test]
+fn suggest_similar_categories() {
+    let c = fake_categorizer();
+    let names = |name: &str| -> Vec<String> {
+        c.similar_categories(name)
+            .iter()
+            .map(|category| category.name.clone())
+            .collect()
+    };
+    assert_eq!(names("fruts"), vec!["Fruits"]);
+    assert_eq!(names("swets"), vec!["Sweets"]);
+    assert_eq!(names("oth"), vec!["Others"]);
+    assert!(names("transport").is_empty());
+}
+
+#[test]
+fn classify_words_with_typos_after_exact_ones() {
+    let c = fake_categorizer();
+    assert_eq!(c.classify_msg("10 for bananna").unwrap().name, "Fruits");
+    // Sweets have higher priority, but the apple is written right
+    assert_eq!(
+        c.classify_msg("10 for apple chocolatte").unwrap().name,
+        "Fruits"
+    );
+}
+
+struct FakeProvider(Vec<Category>);
+
+impl CategoryProvider for FakeProvider {
+    fn categories(&self) -> Result<Vec<Category>, Error> {
+        Ok(self.0.clone())
+    }
+}
+
+fn fake_hierarchy() -> Categorizer {
+    let category = |name: &str, priority, lexemes: &str, parent: Option<&str>| {
+        Category::new(name.to_string(), priority, lexemes.into())
+            .with_parent(parent.map(str::to_string))
+    };
+    let mut c = Categorizer::new();
+    c.load_categories(&FakeProvider(vec![
+        category("Dairy", 40, "milk,cheese", Some("groceries")),
+        category("Food", 50, "food", None),
+        category("Groceries", 30, "grocer", Some("Food")),
+        category("Transport/Taxi", 10, "taxi", None),
+        category("Rent", 10, "rent", Some("Home")),
+        category("Ping", 10, "ping", Some("Pong")),
+        category("Pong", 10, "pong", Some("Ping")),
+    ]))
+    .unwrap();
+    c
+}
+
+#[test]
+fn categories_are_named_by_paths() {
+    let c = fake_hierarchy();
+    let mut names: Vec<_> = c
+        .categories
+        .iter()
+        .flatten()
+        .map(|c| c.name.as_str())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        vec![
+            "Food",
+            "Food/Groceries",
+            "Food/Groceries/Dairy",
+            "Home/Rent",
+            "Ping/Pong",
+            "Pong/Ping",
+            "Transport/Taxi",
+        ]
+    );
+    assert_eq!(c.category("dairy").unwrap().name, "Food/Groceries/Dairy");
+    assert_eq!(c.category("food/groceries").unwrap().name, "Food/Groceries");
+    assert_eq!(c.named_category("Taxi").unwrap().name, "Transport/Taxi");
+    assert_eq!(
+        c.similar_categories("diary")[0].name,
+        "Food/Groceries/Dairy"
+    );
+}
+
+#[test]
+fn classify_most_specific_category() {
+    let c = fake_hierarchy();
+    // Groceries have higher priority, but Dairy is a more specific one
+    assert_eq!(
+        c.classify_msg("10 for groceries, milk").unwrap().name,
+        "Food/Groceries/Dairy"
+    );
+    assert_eq!(
+        c.classify_msg("10 for food and groceries").unwrap().name,
+        "Food/Groceries"
+    );
+    assert_eq!(c.classify_msg("10 for food").unwrap().name, "Food");
+}
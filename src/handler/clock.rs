@@ -0,0 +1,50 @@
+use chrono::{DateTime, Local, NaiveDate};
+
+/// Source of the current time, tests replace it with a fake one
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+
+    fn today(&self) -> NaiveDate {
+        self.now().date().naive_local()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+#[cfg(test)]
+pub use self::fake::FakeClock;
+
+#[cfg(test)]
+mod fake {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{DateTime, Local};
+
+    use super::Clock;
+
+    /// Clock standing still until it is moved, clones share the same time
+    #[derive(Clone)]
+    pub struct FakeClock(Arc<Mutex<DateTime<Local>>>);
+
+    impl FakeClock {
+        pub fn new(now: DateTime<Local>) -> Self {
+            FakeClock(Arc::new(Mutex::new(now)))
+        }
+
+        pub fn set(&self, now: DateTime<Local>) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.0.lock().unwrap()
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use std::str::FromStr;
 
-use chrono::{Datelike, Duration, Local, Weekday};
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
 
 use crate::handler::{
-    date_parser::WeekdayExt,
+    date_parser::{parse_day_month, parse_numeric_date, DateMatch, WeekdayExt},
     tokenizer::{MessageTokens, Token},
 };
 
@@ -12,101 +12,209 @@ use super::DateShiftParser;
 pub struct EnglishDateShiftParser;
 
 impl DateShiftParser for EnglishDateShiftParser {
-    fn parse_date_shift(tokens: &MessageTokens) -> Option<Duration> {
-        let ref mut iter = tokens.iter().enumerate();
-        while let Some((i, t)) = iter.next() {
-            let duration = match t {
-                Token::Word(_) if t.is_word("yesterday") => Some(Duration::days(1)),
+    fn parse_date_shift(&self, tokens: &MessageTokens, today: NaiveDate) -> Option<DateMatch> {
+        for (i, t) in tokens.iter().enumerate() {
+            let date = match t {
+                Token::Word(_) if t.is_word("yesterday") => {
+                    Some(DateMatch::ago(Duration::days(1), i..i + 1))
+                }
                 Token::Word(_) if t.any_of_words(&["last", "on"]) && tokens.len() > i + 1 => {
                     match tokens.get(i + 1) {
                         Some(Token::Word(w)) => match Weekday::from_str(w) {
                             Ok(wd) => {
-                                let x = Local::today().weekday().days_since(wd);
-                                Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+                                let x = today.weekday().days_since(wd);
+                                let days = if x == 0 { 7 } else { x.into() };
+                                Some(DateMatch::ago(Duration::days(days), i..i + 2))
                             }
                             Err(..) => None,
                         },
                         _ => None,
                     }
                 }
-                Token::Amount(x) => match x.as_i32() {
+                Token::Date(text) => {
+                    parse_numeric_date(text, today).map(|date| DateMatch::date(date, i..i + 1))
+                }
+                Token::Amount(x, _) => match x.as_i32() {
                     Ok(x) if tokens.len() > i + 2 && tokens[i + 2].is_word("ago") => {
                         match tokens[i + 1] {
-                            _ if tokens[i + 1].is_word("days") => Some(Duration::days(x.into())),
-                            _ if tokens[i + 1].is_word("weeks") => Some(Duration::weeks(x.into())),
+                            _ if tokens[i + 1].is_word("days") => {
+                                Some(DateMatch::ago(Duration::days(x.into()), i..i + 3))
+                            }
+                            _ if tokens[i + 1].is_word("weeks") => {
+                                Some(DateMatch::ago(Duration::weeks(x.into()), i..i + 3))
+                            }
                             _ => None,
                         }
                     }
+                    Ok(day) => match tokens.get(i + 1) {
+                        Some(Token::Word(w)) => month_from_str(w)
+                            .and_then(|month| parse_day_month(tokens, day as u32, month, i, today)),
+                        _ => None,
+                    },
                     _ => None,
                 },
                 Token::Word(w) if tokens.len() > i + 1 && tokens[i + 1].is_word("ago") => match w {
-                    _ if w.eq_ignore_ascii_case("week") => Some(Duration::weeks(1)),
-                    _ if w.eq_ignore_ascii_case("day") => Some(Duration::days(1)),
+                    _ if w.eq_ignore_ascii_case("week") => {
+                        Some(DateMatch::ago(Duration::weeks(1), i..i + 2))
+                    }
+                    _ if w.eq_ignore_ascii_case("day") => {
+                        Some(DateMatch::ago(Duration::days(1), i..i + 2))
+                    }
                     _ => None,
                 },
+                Token::Word(w) => month_from_str(w).and_then(|month| {
+                    match tokens.get(i + 1) {
+                        Some(Token::Amount(x, _)) => x.as_i32().ok().map(|day| day as u32),
+                        Some(Token::Word(w)) => day_from_ordinal(w),
+                        _ => None,
+                    }
+                    .and_then(|day| parse_day_month(tokens, day, month, i, today))
+                }),
                 _ => None,
             };
-            if let Some(_) = duration {
-                return duration;
+            if date.is_some() {
+                return date;
             }
         }
         None
     }
 }
 
+fn day_from_ordinal(word: &str) -> Option<u32> {
+    let word = word.to_lowercase();
+    ["st", "nd", "rd", "th"]
+        .iter()
+        .find_map(|suffix| word.strip_suffix(suffix))
+        .and_then(|day| day.parse().ok())
+}
+
+fn month_from_str(word: &str) -> Option<u32> {
+    let month = match word.to_lowercase().as_ref() {
+        "jan" | "january" => 1,
+        "feb" | "february" => 2,
+        "mar" | "march" => 3,
+        "apr" | "april" => 4,
+        "may" => 5,
+        "jun" | "june" => 6,
+        "jul" | "july" => 7,
+        "aug" | "august" => 8,
+        "sep" | "sept" | "september" => 9,
+        "oct" | "october" => 10,
+        "nov" | "november" => 11,
+        "dec" | "december" => 12,
+        _ => return None,
+    };
+    Some(month)
+}
+
 #[cfg(test)]
 mod tests {
-    use chrono::{Datelike, Local};
 
+    use crate::handler::date_parser::{resolve_date, DateShift};
     use crate::handler::tokenizer::tokenize;
 
     use super::*;
 
-    type Parser = EnglishDateShiftParser;
+    const PARSER: EnglishDateShiftParser = EnglishDateShiftParser;
+
+    /// Saturday
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2021, 3, 20)
+    }
+
+    fn parse(text: &str) -> Option<DateShift> {
+        PARSER
+            .parse_date_shift(&tokenize(text), today())
+            .map(|m| m.shift)
+    }
+
+    fn ago(duration: Duration) -> Option<DateShift> {
+        Some(DateShift::Ago(duration))
+    }
+
+    fn date_of_this_year(month: u32, day: u32) -> Option<DateShift> {
+        resolve_date(day, month, None, today()).map(DateShift::Date)
+    }
 
     #[test]
     fn no_shift_by_default() {
-        let tokens = &tokenize("banana 4.5");
-        assert_eq!(Parser::parse_date_shift(tokens), None)
+        assert_eq!(parse("banana 4.5"), None)
     }
 
     #[test]
     fn yesterday() {
-        let tokens = &tokenize("banana 4.5 yesterday");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(1)))
+        assert_eq!(parse("banana 4.5 yesterday"), ago(Duration::days(1)))
     }
 
     #[test]
     fn some_days_ago() {
-        let tokens = &tokenize("banana 4.5 2 days ago");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(2)));
+        assert_eq!(parse("banana 4.5 2 days ago"), ago(Duration::days(2)));
     }
 
     #[test]
     fn some_days_ago_with_int_amount() {
-        let tokens = &tokenize("banana 4, 5 days ago");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(5)));
+        assert_eq!(parse("banana 4, 5 days ago"), ago(Duration::days(5)));
     }
 
     #[test]
     fn a_week_ago() {
-        let tokens = &tokenize("banana 4.5 a week ago");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::weeks(1)));
+        assert_eq!(parse("banana 4.5 a week ago"), ago(Duration::weeks(1)));
     }
 
     #[test]
     fn some_weeks_ago() {
-        let tokens = &tokenize("banana 4.5 2 weeks ago");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::weeks(2)));
+        assert_eq!(parse("banana 4.5 2 weeks ago"), ago(Duration::weeks(2)));
     }
 
     #[test]
     fn last_monday() {
-        let tokens = &tokenize("banana 4.5 last Monday");
-        let x = Local::today().weekday().num_days_from_monday();
+        assert_eq!(parse("banana 4.5 last Monday"), ago(Duration::days(5)));
+    }
+
+    #[test]
+    fn matched_tokens() {
+        let tokens = tokenize("5 days ago banana 4.5");
+        assert_eq!(
+            PARSER.parse_date_shift(&tokens, today()).unwrap().tokens,
+            0..3
+        );
+    }
+
+    #[test]
+    fn numeric_date() {
+        assert_eq!(parse("banana 4.5 12.03"), date_of_this_year(3, 12));
+        assert_eq!(parse("banana 4.5 12/03"), date_of_this_year(3, 12));
+    }
+
+    #[test]
+    fn iso_date() {
+        assert_eq!(
+            parse("banana 4.5 2021-03-12"),
+            Some(DateShift::Date(NaiveDate::from_ymd(2021, 3, 12)))
+        );
+    }
+
+    #[test]
+    fn day_and_month_name() {
+        assert_eq!(parse("banana 4.5 12 March"), date_of_this_year(3, 12));
+        assert_eq!(parse("banana 4.5 on Mar 12"), date_of_this_year(3, 12));
+        assert_eq!(parse("banana 4.5 March 12th"), date_of_this_year(3, 12));
+    }
+
+    #[test]
+    fn month_name_with_the_only_amount() {
+        assert_eq!(parse("lunch with jan 20"), None);
+        assert_eq!(parse("may 15 refund"), None);
+        assert_eq!(parse("lunch with jan 20, taxi 5"), None);
+        assert_eq!(parse("may 15 refund 10"), date_of_this_year(5, 15));
+    }
+
+    #[test]
+    fn day_month_and_year() {
+        let tokens = tokenize("12 March 2020 banana 4.5");
         assert_eq!(
-            Parser::parse_date_shift(tokens),
-            Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+            PARSER.parse_date_shift(&tokens, today()),
+            Some(DateMatch::date(NaiveDate::from_ymd(2020, 3, 12), 0..3))
         );
     }
 }
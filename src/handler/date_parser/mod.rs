@@ -1,4 +1,10 @@
-use chrono::{Duration, Weekday};
+use std::env;
+use std::ops::Range;
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use log::warn;
+use regex::Regex;
 
 #[cfg(feature = "parser-en")]
 use crate::handler::date_parser::english::EnglishDateShiftParser;
@@ -6,19 +12,181 @@ use crate::handler::date_parser::english::EnglishDateShiftParser;
 use crate::handler::date_parser::russian::RussianDateShiftParser;
 
 use crate::handler::tokenizer::*;
+use crate::handler::user_settings::PerUser;
 
 #[cfg(feature = "parser-en")]
 pub mod english;
 #[cfg(feature = "parser-ru")]
 pub mod russian;
 
-#[cfg(feature = "parser-en")]
-pub type DefaultDateShiftParser = EnglishDateShiftParser;
-#[cfg(feature = "parser-ru")]
-pub type DefaultDateShiftParser = RussianDateShiftParser;
+const LANGUAGES_ENV: &str = "BUDGET_DATE_LANGUAGES";
+const USER_LANGUAGES_ENV: &str = "BUDGET_USER_DATE_LANGUAGES";
+
+lazy_static! {
+    static ref RE_ISO_DATE: Regex = Regex::new(r"^(\d{4})-(\d{1,2})-(\d{1,2})$").unwrap();
+    static ref RE_NUMERIC_DATE: Regex =
+        Regex::new(r"^(\d{1,2})[./](\d{1,2})(?:[./](\d{4}|\d{2}))?$").unwrap();
+}
+
+/// Date of a record relative to the message date
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DateShift {
+    /// Some time before the message date, e.g. "yesterday" or "2 weeks ago"
+    Ago(Duration),
+    /// Explicit date, e.g. "12.03" or "March 12"
+    Date(NaiveDate),
+}
+
+impl DateShift {
+    pub fn apply(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            DateShift::Ago(duration) => date - duration,
+            DateShift::Date(date) => date,
+        }
+    }
+}
+
+/// Parsed date along with positions of tokens it was parsed from
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DateMatch {
+    pub shift: DateShift,
+    pub tokens: Range<usize>,
+}
+
+impl DateMatch {
+    pub fn ago(duration: Duration, tokens: Range<usize>) -> Self {
+        DateMatch {
+            shift: DateShift::Ago(duration),
+            tokens,
+        }
+    }
+
+    pub fn date(date: NaiveDate, tokens: Range<usize>) -> Self {
+        DateMatch {
+            shift: DateShift::Date(date),
+            tokens,
+        }
+    }
+}
 
 pub trait DateShiftParser {
-    fn parse_date_shift(tokens: &MessageTokens) -> Option<Duration>;
+    /// Find a date in the message sent on `today`
+    fn parse_date_shift(&self, tokens: &MessageTokens, today: NaiveDate) -> Option<DateMatch>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateLanguage {
+    English,
+    Russian,
+}
+
+/// Languages enabled by cargo features in order they are tried by default
+const COMPILED_IN_LANGUAGES: &[DateLanguage] = &[
+    #[cfg(feature = "parser-en")]
+    DateLanguage::English,
+    #[cfg(feature = "parser-ru")]
+    DateLanguage::Russian,
+];
+
+impl FromStr for DateLanguage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "en" | "english" => Ok(DateLanguage::English),
+            "ru" | "russian" => Ok(DateLanguage::Russian),
+            _ => Err(format!("Unknown date parser language '{}'", s)),
+        }
+    }
+}
+
+impl DateLanguage {
+    fn parser(self) -> Option<Box<dyn DateShiftParser + Send + Sync>> {
+        match self {
+            #[cfg(feature = "parser-en")]
+            DateLanguage::English => Some(Box::new(EnglishDateShiftParser)),
+            #[cfg(feature = "parser-ru")]
+            DateLanguage::Russian => Some(Box::new(RussianDateShiftParser)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    fn parse_list(text: &str) -> Vec<DateLanguage> {
+        text.split(',')
+            .filter(|s| !s.trim().is_empty())
+            .filter_map(|s| match s.parse() {
+                Ok(lang) => Some(lang),
+                Err(err) => {
+                    warn!("{}", err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Runs parsers of several languages over the same tokens and takes the first match
+pub struct CompositeDateShiftParser {
+    parsers: Vec<Box<dyn DateShiftParser + Send + Sync>>,
+}
+
+impl CompositeDateShiftParser {
+    pub fn new(languages: &[DateLanguage]) -> Self {
+        let parsers = languages
+            .iter()
+            .filter_map(|&lang| {
+                let parser = lang.parser();
+                if parser.is_none() {
+                    warn!("Date parser for {:?} is not compiled in", lang);
+                }
+                parser
+            })
+            .collect();
+        CompositeDateShiftParser { parsers }
+    }
+
+    pub fn compiled_in() -> Self {
+        CompositeDateShiftParser::new(COMPILED_IN_LANGUAGES)
+    }
+}
+
+impl Default for CompositeDateShiftParser {
+    fn default() -> Self {
+        CompositeDateShiftParser::compiled_in()
+    }
+}
+
+impl DateShiftParser for CompositeDateShiftParser {
+    fn parse_date_shift(&self, tokens: &MessageTokens, today: NaiveDate) -> Option<DateMatch> {
+        self.parsers
+            .iter()
+            .find_map(|parser| parser.parse_date_shift(tokens, today))
+    }
+}
+
+/// Date parsers configured per user with a common fallback
+pub type UserDateShiftParsers = PerUser<CompositeDateShiftParser>;
+
+impl UserDateShiftParsers {
+    /// Read languages from env vars:
+    /// * `BUDGET_DATE_LANGUAGES` - comma separated default languages, e.g. `en,ru`
+    /// * `BUDGET_USER_DATE_LANGUAGES` - languages per user, e.g. `alice:ru;bob:en,ru`
+    pub fn from_env() -> Self {
+        UserDateShiftParsers::from_config(
+            env::var(LANGUAGES_ENV).ok().as_deref(),
+            env::var(USER_LANGUAGES_ENV).ok().as_deref(),
+        )
+    }
+
+    fn from_config(default: Option<&str>, users: Option<&str>) -> Self {
+        let parser =
+            |languages: &str| CompositeDateShiftParser::new(&DateLanguage::parse_list(languages));
+        PerUser::new(default.map_or_else(CompositeDateShiftParser::compiled_in, parser))
+            .with_users(users.unwrap_or_default(), |languages| {
+                Some(parser(languages))
+            })
+    }
 }
 
 pub trait WeekdayExt {
@@ -43,8 +211,163 @@ impl WeekdayExt for Weekday {
     }
 }
 
+/// Split numeric date like `12.03`, `12/03/21` or `2021-03-12` into day, month and year
+pub(crate) fn split_numeric_date(text: &str) -> Option<(u32, u32, Option<i32>)> {
+    let (day, month, year) = if let Some(caps) = RE_ISO_DATE.captures(text) {
+        (
+            caps[3].parse().ok()?,
+            caps[2].parse().ok()?,
+            caps[1].parse().ok(),
+        )
+    } else {
+        let caps = RE_NUMERIC_DATE.captures(text)?;
+        let year = caps.get(3).and_then(|y| match y.as_str().parse::<i32>() {
+            Ok(y) if y < 100 => Some(2000 + y),
+            y => y.ok(),
+        });
+        (caps[1].parse().ok()?, caps[2].parse().ok()?, year)
+    };
+    if (1..=31).contains(&day) && (1..=12).contains(&month) {
+        Some((day, month, year))
+    } else {
+        None
+    }
+}
+
+/// Build a date from its parts, a missing year is taken so the date is not after `today`
+pub(crate) fn resolve_date(
+    day: u32,
+    month: u32,
+    year: Option<i32>,
+    today: NaiveDate,
+) -> Option<NaiveDate> {
+    match year {
+        Some(year) => NaiveDate::from_ymd_opt(year, month, day),
+        None => NaiveDate::from_ymd_opt(today.year(), month, day)
+            .filter(|date| *date <= today)
+            .or_else(|| NaiveDate::from_ymd_opt(today.year() - 1, month, day)),
+    }
+}
+
+/// Parse numeric date token like `12.03` or `2021-03-12`
+pub(crate) fn parse_numeric_date(text: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let (day, month, year) = split_numeric_date(text)?;
+    resolve_date(day, month, year, today)
+}
+
+/// Take day and month parsed from tokens at `start` and the next one with an optional year after
+pub(crate) fn parse_day_month(
+    tokens: &MessageTokens,
+    day: u32,
+    month: u32,
+    start: usize,
+    today: NaiveDate,
+) -> Option<DateMatch> {
+    let year = match tokens.get(start + 2) {
+        Some(Token::Amount(x, None)) => x.as_i32().ok().filter(|y| *y >= 1000),
+        _ => None,
+    };
+    let end = if year.is_some() { start + 3 } else { start + 2 };
+    if takes_only_amount(tokens, start..end) {
+        return None;
+    }
+    resolve_date(day, month, year, today).map(|date| DateMatch::date(date, start..end))
+}
+
+/// Numbers next to words like "may" or "jan" are amounts rather than dates,
+/// if there is no other amount in the expense, e.g. in "lunch with jan 20"
+fn takes_only_amount(tokens: &MessageTokens, date_tokens: Range<usize>) -> bool {
+    let is_amount = |token: &Token| matches!(token, Token::Amount(..));
+    if !tokens[date_tokens.clone()].iter().any(is_amount) {
+        return false;
+    }
+    let start = tokens[..date_tokens.start]
+        .iter()
+        .rposition(Token::is_expense_separator)
+        .map_or(0, |i| i + 1);
+    let end = tokens[date_tokens.end..]
+        .iter()
+        .position(Token::is_expense_separator)
+        .map_or(tokens.len(), |i| date_tokens.end + i);
+    !tokens[start..date_tokens.start]
+        .iter()
+        .chain(&tokens[date_tokens.end..end])
+        .any(is_amount)
+}
+
 pub fn assert_text(tokens: &[Token], text: &str) -> bool {
     let text = text.to_lowercase();
     let expected = tokenize(&text);
     tokens.len() == expected.len() && expected.iter().zip(tokens).all(|(t1, t2)| t1 == t2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "parser-en", feature = "parser-ru"))]
+    fn shift(parser: &impl DateShiftParser, text: &str) -> Option<DateShift> {
+        let today = NaiveDate::from_ymd(2021, 3, 20);
+        parser
+            .parse_date_shift(&tokenize(text), today)
+            .map(|m| m.shift)
+    }
+
+    #[test]
+    fn numeric_dates() {
+        let today = NaiveDate::from_ymd(2021, 3, 20);
+        let parse = |text| parse_numeric_date(text, today);
+        assert_eq!(parse("12.03"), Some(NaiveDate::from_ymd(2021, 3, 12)));
+        assert_eq!(parse("12/03/20"), Some(NaiveDate::from_ymd(2020, 3, 12)));
+        assert_eq!(parse("12.03.2019"), Some(NaiveDate::from_ymd(2019, 3, 12)));
+        assert_eq!(parse("2021-03-12"), Some(NaiveDate::from_ymd(2021, 3, 12)));
+        assert_eq!(parse("31.02"), None);
+        assert_eq!(parse("12.13"), None);
+    }
+
+    #[test]
+    fn date_without_year_is_not_in_future() {
+        let today = NaiveDate::from_ymd(2021, 3, 20);
+        assert_eq!(
+            resolve_date(20, 3, None, today),
+            Some(NaiveDate::from_ymd(2021, 3, 20))
+        );
+        assert_eq!(
+            resolve_date(21, 3, None, today),
+            Some(NaiveDate::from_ymd(2020, 3, 21))
+        );
+    }
+
+    #[test]
+    fn date_language_from_str() {
+        assert_eq!(DateLanguage::from_str("en"), Ok(DateLanguage::English));
+        assert_eq!(DateLanguage::from_str(" RU"), Ok(DateLanguage::Russian));
+        assert!(DateLanguage::from_str("de").is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "parser-en", feature = "parser-ru"))]
+    fn composite_parser_takes_first_match() {
+        let parser = CompositeDateShiftParser::compiled_in();
+        assert_eq!(
+            shift(&parser, "banana 4.5 yesterday"),
+            Some(DateShift::Ago(Duration::days(1)))
+        );
+        assert_eq!(
+            shift(&parser, "бананы 45 позавчера"),
+            Some(DateShift::Ago(Duration::days(2)))
+        );
+        assert_eq!(shift(&parser, "banana 4.5"), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "parser-en", feature = "parser-ru"))]
+    fn parsers_per_user() {
+        let parsers = UserDateShiftParsers::from_config(Some("en"), Some("alice:ru; bob:en,ru"));
+        let text = "бананы 45 вчера";
+        let yesterday = Some(DateShift::Ago(Duration::days(1)));
+        assert_eq!(shift(parsers.for_user("carol"), text), None);
+        assert_eq!(shift(parsers.for_user("alice"), text), yesterday);
+        assert_eq!(shift(parsers.for_user("bob"), text), yesterday);
+    }
+}
@@ -1,25 +1,33 @@
 use std::str::FromStr;
 
-use chrono::{Datelike, Duration, Local};
+use chrono::{Datelike, Duration, NaiveDate};
 
 use crate::handler::{
-    date_parser::{russian::weekdayrus::WeekdayRus, WeekdayExt},
+    date_parser::{
+        parse_day_month, parse_numeric_date,
+        russian::{monthrus::MonthRus, weekdayrus::WeekdayRus},
+        DateMatch, WeekdayExt,
+    },
     tokenizer::{MessageTokens, Token},
 };
 
 use super::DateShiftParser;
 
+mod monthrus;
 mod weekdayrus;
 
 pub struct RussianDateShiftParser;
 
 impl DateShiftParser for RussianDateShiftParser {
-    fn parse_date_shift(tokens: &MessageTokens) -> Option<Duration> {
-        let ref mut iter = tokens.iter().enumerate();
-        while let Some((i, t)) = iter.next() {
-            let duration = match t {
-                Token::Word(_) if t.is_word("вчера") => Some(Duration::days(1)),
-                Token::Word(_) if t.is_word("позавчера") => Some(Duration::days(2)),
+    fn parse_date_shift(&self, tokens: &MessageTokens, today: NaiveDate) -> Option<DateMatch> {
+        for (i, t) in tokens.iter().enumerate() {
+            let date = match t {
+                Token::Word(_) if t.is_word("вчера") => {
+                    Some(DateMatch::ago(Duration::days(1), i..i + 1))
+                }
+                Token::Word(_) if t.is_word("позавчера") => {
+                    Some(DateMatch::ago(Duration::days(2), i..i + 1))
+                }
                 Token::Word(_)
                     if tokens.len() > i + 1
                         && tokens[i].any_of_words(&["прошлый", "прошлую", "прошлое", "в"]) =>
@@ -27,39 +35,53 @@ impl DateShiftParser for RussianDateShiftParser {
                     match tokens.get(i + 1) {
                         Some(Token::Word(w)) => match WeekdayRus::from_str(w) {
                             Ok(wd) => {
-                                let x = Local::today().weekday().days_since(wd.into());
-                                Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+                                let x = today.weekday().days_since(wd.into());
+                                let days = if x == 0 { 7 } else { x.into() };
+                                Some(DateMatch::ago(Duration::days(days), i..i + 2))
                             }
                             Err(..) => None,
                         },
                         _ => None,
                     }
                 }
-                Token::Amount(x) => match x.as_i32() {
+                Token::Date(text) => {
+                    parse_numeric_date(text, today).map(|date| DateMatch::date(date, i..i + 1))
+                }
+                Token::Amount(x, _) => match x.as_i32() {
                     Ok(x) if tokens.len() > i + 2 && tokens[i + 2].is_word("назад") => {
                         match &tokens[i + 1] {
                             w1 if w1.any_of_words(&["неделю", "недели", "недель"]) => {
-                                Some(Duration::weeks(x.into()))
+                                Some(DateMatch::ago(Duration::weeks(x.into()), i..i + 3))
                             }
                             w1 if w1.any_of_words(&["день", "дней", "дня"]) => {
-                                Some(Duration::days(x.into()))
+                                Some(DateMatch::ago(Duration::days(x.into()), i..i + 3))
                             }
                             _ => None,
                         }
                     }
+                    Ok(day) => match tokens.get(i + 1) {
+                        Some(Token::Word(w)) => MonthRus::from_str(w).ok().and_then(|month| {
+                            parse_day_month(tokens, day as u32, month.into(), i, today)
+                        }),
+                        _ => None,
+                    },
                     _ => None,
                 },
                 Token::Word(w) if tokens.len() > i + 1 && tokens[i + 1].is_word("назад") => {
                     match w {
-                        _ if w.eq_ignore_ascii_case("неделю") => Some(Duration::weeks(1)),
-                        _ if w.eq_ignore_ascii_case("день") => Some(Duration::days(1)),
+                        _ if w.eq_ignore_ascii_case("неделю") => {
+                            Some(DateMatch::ago(Duration::weeks(1), i..i + 2))
+                        }
+                        _ if w.eq_ignore_ascii_case("день") => {
+                            Some(DateMatch::ago(Duration::days(1), i..i + 2))
+                        }
                         _ => None,
                     }
                 }
                 _ => None,
             };
-            if let Some(_) = duration {
-                return duration;
+            if date.is_some() {
+                return date;
             }
         }
         None
@@ -68,89 +90,115 @@ impl DateShiftParser for RussianDateShiftParser {
 
 #[cfg(test)]
 mod tests {
-    use chrono::{Datelike, Local, Weekday};
 
+    use crate::handler::date_parser::{resolve_date, DateShift};
     use crate::handler::tokenizer::tokenize;
 
     use super::*;
 
-    type Parser = RussianDateShiftParser;
+    const PARSER: RussianDateShiftParser = RussianDateShiftParser;
+
+    /// Saturday
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd(2021, 3, 20)
+    }
+
+    fn parse(text: &str) -> Option<DateShift> {
+        PARSER
+            .parse_date_shift(&tokenize(text), today())
+            .map(|m| m.shift)
+    }
+
+    fn ago(duration: Duration) -> Option<DateShift> {
+        Some(DateShift::Ago(duration))
+    }
+
+    fn date_of_this_year(month: u32, day: u32) -> Option<DateShift> {
+        resolve_date(day, month, None, today()).map(DateShift::Date)
+    }
 
     #[test]
     fn no_shift_by_default() {
-        let tokens = &tokenize("бананы 50");
-        assert_eq!(Parser::parse_date_shift(tokens), None)
+        assert_eq!(parse("бананы 50"), None)
     }
 
     #[test]
     fn yesterday() {
-        let tokens = &tokenize("бананы 45,50 вчера");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(1)))
+        assert_eq!(parse("бананы 45,50 вчера"), ago(Duration::days(1)))
     }
 
     #[test]
     fn the_day_before_yesterday() {
-        let tokens = &tokenize("бананы 45,50 позавчера");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(2)))
+        assert_eq!(parse("бананы 45,50 позавчера"), ago(Duration::days(2)))
     }
 
     #[test]
     fn a_day_ago() {
-        let tokens = &tokenize("бананы 45,50 день назад");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(1)));
+        assert_eq!(parse("бананы 45,50 день назад"), ago(Duration::days(1)));
     }
 
     #[test]
     fn some_days_ago() {
-        let tokens = &tokenize("бананы 45,50 2 дня назад");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(2)));
+        assert_eq!(parse("бананы 45,50 2 дня назад"), ago(Duration::days(2)));
     }
 
     #[test]
     fn some_weeks_ago() {
-        let tokens = &tokenize("бананы 45,50 2 недели назад");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::weeks(2)));
+        assert_eq!(
+            parse("бананы 45,50 2 недели назад"),
+            ago(Duration::weeks(2))
+        );
     }
 
     #[test]
     fn a_week_ago() {
-        let tokens = &tokenize("бананы 45,50 неделю назад");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::weeks(1)));
+        assert_eq!(parse("бананы 45,50 неделю назад"), ago(Duration::weeks(1)));
     }
 
     #[test]
     fn some_days_ago_with_int_amount() {
-        let tokens = &tokenize("бананы 45, 5 дней назад");
-        assert_eq!(Parser::parse_date_shift(tokens), Some(Duration::days(5)));
+        assert_eq!(parse("бананы 45, 5 дней назад"), ago(Duration::days(5)));
     }
 
     #[test]
     fn last_monday() {
-        let tokens = &tokenize("бананы 45,50 прошлый понедельник");
-        let x = Local::today().weekday().num_days_from_monday();
         assert_eq!(
-            Parser::parse_date_shift(tokens),
-            Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+            parse("бананы 45,50 прошлый понедельник"),
+            ago(Duration::days(5))
         );
     }
 
     #[test]
     fn on_last_friday() {
-        let tokens = &tokenize("30 бананы в прошлую пятницу");
-        let x = Local::today().weekday().days_since(Weekday::Fri);
+        assert_eq!(parse("30 бананы в прошлую пятницу"), ago(Duration::days(1)));
+    }
+
+    #[test]
+    fn on_thursday() {
+        assert_eq!(parse("100 бананы в четверг"), ago(Duration::days(2)));
+    }
+
+    #[test]
+    fn numeric_date() {
+        assert_eq!(parse("бананы 45,50 12.03"), date_of_this_year(3, 12));
         assert_eq!(
-            Parser::parse_date_shift(tokens),
-            Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+            parse("бананы 45,50 12.03.2020"),
+            Some(DateShift::Date(NaiveDate::from_ymd(2020, 3, 12)))
         );
     }
 
     #[test]
-    fn on_thursday() {
-        let tokens = &tokenize("100 бананы в четверг");
-        let x = Local::today().weekday().days_since(Weekday::Thu);
+    fn day_and_genitive_month() {
+        assert_eq!(parse("бананы 45,50 12 марта"), date_of_this_year(3, 12));
+        assert_eq!(parse("бананы 45,50 1 мая"), date_of_this_year(5, 1));
+    }
+
+    #[test]
+    fn day_month_and_year() {
+        let tokens = tokenize("12 марта 2020 бананы 45");
         assert_eq!(
-            Parser::parse_date_shift(tokens),
-            Some(Duration::days(if x == 0 { 7 } else { x.into() }))
+            PARSER.parse_date_shift(&tokens, today()),
+            Some(DateMatch::date(NaiveDate::from_ymd(2020, 3, 12), 0..3))
         );
     }
 }
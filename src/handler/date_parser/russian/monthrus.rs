@@ -0,0 +1,31 @@
+use std::str::FromStr;
+
+pub struct MonthRus(u32);
+
+impl From<MonthRus> for u32 {
+    fn from(month: MonthRus) -> Self {
+        month.0
+    }
+}
+
+impl FromStr for MonthRus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "янв" | "январь" | "января" => Ok(MonthRus(1)),
+            "фев" | "февраль" | "февраля" => Ok(MonthRus(2)),
+            "мар" | "март" | "марта" => Ok(MonthRus(3)),
+            "апр" | "апрель" | "апреля" => Ok(MonthRus(4)),
+            "май" | "мая" => Ok(MonthRus(5)),
+            "июн" | "июнь" | "июня" => Ok(MonthRus(6)),
+            "июл" | "июль" | "июля" => Ok(MonthRus(7)),
+            "авг" | "август" | "августа" => Ok(MonthRus(8)),
+            "сен" | "сент" | "сентябрь" | "сентября" => Ok(MonthRus(9)),
+            "окт" | "октябрь" | "октября" => Ok(MonthRus(10)),
+            "ноя" | "нояб" | "ноябрь" | "ноября" => Ok(MonthRus(11)),
+            "дек" | "декабрь" | "декабря" => Ok(MonthRus(12)),
+            _ => Err(()),
+        }
+    }
+}
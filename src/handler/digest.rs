@@ -0,0 +1,374 @@
+use std::cmp::Reverse;
+use std::env;
+use std::fmt;
+use std::ops::Range;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use log::warn;
+
+#[cfg(feature = "telegram")]
+use crate::error::Error;
+use crate::handler::date_parser::WeekdayExt;
+use crate::handler::events::{BudgetRecord, RecordKind};
+use crate::handler::report::{Report, ReportPeriod};
+use crate::handler::ChatId;
+
+const DIGESTS_ENV: &str = "BUDGET_DIGESTS";
+#[cfg(feature = "telegram")]
+const CRON_INTERVAL_ENV: &str = "BUDGET_DIGESTS_INTERVAL";
+/// Number of the biggest expenses listed in a digest
+const TOP_EXPENSES: usize = 3;
+
+/// How often a digest is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestPeriod {
+    /// On the weekday about the previous week
+    Weekly(Weekday),
+    /// On the day of month about the previous month,
+    /// days missing in short months are moved to their last day
+    Monthly(u32),
+}
+
+/// Digest of the chat ledger sent at the given local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigestSchedule {
+    pub chat: ChatId,
+    period: DigestPeriod,
+    time: NaiveTime,
+}
+
+impl DigestSchedule {
+    /// Read schedules from `BUDGET_DIGESTS` env var,
+    /// e.g. `-100500:weekly mon 09:00;-100500:monthly 1 09:00`
+    pub fn from_env() -> Vec<DigestSchedule> {
+        DigestSchedule::from_config(&env::var(DIGESTS_ENV).unwrap_or_default())
+    }
+
+    /// Invalid entries are skipped with a warning
+    pub(crate) fn from_config(config: &str) -> Vec<DigestSchedule> {
+        config
+            .split(';')
+            .filter(|entry| !entry.trim().is_empty())
+            .filter_map(|entry| {
+                let schedule = entry
+                    .split_once(':')
+                    .and_then(|(chat, schedule)| Some((chat.trim().parse().ok()?, schedule)))
+                    .and_then(|(chat, schedule)| DigestSchedule::parse(chat, schedule));
+                if schedule.is_none() {
+                    warn!("Invalid digest schedule: '{}'", entry);
+                }
+                schedule
+            })
+            .collect()
+    }
+
+    /// Parse schedule like `weekly mon 09:00` or `monthly 1 09:00`
+    fn parse(chat: ChatId, text: &str) -> Option<DigestSchedule> {
+        let words: Vec<_> = text.split_whitespace().collect();
+        let (period, day, time) = match words.as_slice() {
+            [period, day, time] => (period.to_lowercase(), day, time),
+            _ => return None,
+        };
+        let period = match period.as_ref() {
+            "weekly" => DigestPeriod::Weekly(day.parse().ok()?),
+            "monthly" => DigestPeriod::Monthly(day.parse().ok().filter(|d| (1..=31).contains(d))?),
+            _ => return None,
+        };
+        Some(DigestSchedule {
+            chat,
+            period,
+            time: NaiveTime::parse_from_str(time, "%H:%M").ok()?,
+        })
+    }
+
+    /// The latest time the digest is scheduled at, which is not after `now`
+    fn last_time(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let today = now.date();
+        match self.period {
+            DigestPeriod::Weekly(weekday) => {
+                let days = today.weekday().days_since(weekday);
+                let time = (today - Duration::days(days.into())).and_time(self.time);
+                if time > now {
+                    time - Duration::weeks(1)
+                } else {
+                    time
+                }
+            }
+            DigestPeriod::Monthly(day) => {
+                let time = day_of_month(today.year(), today.month(), day).and_time(self.time);
+                if time <= now {
+                    time
+                } else if today.month() == 1 {
+                    day_of_month(today.year() - 1, 12, day).and_time(self.time)
+                } else {
+                    day_of_month(today.year(), today.month() - 1, day).and_time(self.time)
+                }
+            }
+        }
+    }
+
+    /// Dates the digest sent on `date` is about
+    pub fn dates(&self, date: NaiveDate) -> Range<NaiveDate> {
+        match self.period {
+            DigestPeriod::Weekly(_) => ReportPeriod::CurrentWeek.range(date - Duration::weeks(1)),
+            DigestPeriod::Monthly(_) => {
+                let month_start = NaiveDate::from_ymd(date.year(), date.month(), 1);
+                ReportPeriod::CurrentMonth.range(month_start - Duration::days(1))
+            }
+        }
+    }
+}
+
+/// The day or the last day of the month if it is shorter,
+/// days up to the 28th exist in every month
+fn day_of_month(year: i32, month: u32, day: u32) -> NaiveDate {
+    (28..=day)
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .unwrap_or_else(|| NaiveDate::from_ymd(year, month, day))
+}
+
+/// How often an external cron looks for due digests,
+/// `BUDGET_DIGESTS_INTERVAL` minutes or an hour if not set
+#[cfg(feature = "telegram")]
+pub fn cron_interval_from_env() -> Result<Duration, Error> {
+    match env::var(CRON_INTERVAL_ENV) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|minutes| *minutes > 0)
+            .map(Duration::minutes)
+            .ok_or_else(|| {
+                Error::Config(format!("{} must be a positive number", CRON_INTERVAL_ENV))
+            }),
+        Err(..) => Ok(Duration::hours(1)),
+    }
+}
+
+/// Tells which digests are due, it has to be checked regularly
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    schedules: Vec<DigestSchedule>,
+    checked: Option<NaiveDateTime>,
+}
+
+impl Scheduler {
+    pub fn new(schedules: Vec<DigestSchedule>) -> Self {
+        Scheduler {
+            schedules,
+            checked: None,
+        }
+    }
+
+    /// Digests scheduled after the previous check up to `now`, the first check
+    /// only starts counting. A digest missed several times between checks is due once.
+    pub fn due(&mut self, now: NaiveDateTime) -> Vec<DigestSchedule> {
+        match self.checked.replace(now) {
+            Some(checked) => self.due_since(checked, now),
+            None => vec![],
+        }
+    }
+
+    /// Digests scheduled after `since` up to `now` regardless of previous checks,
+    /// for runs keeping no state between them, e.g. by a cron firing every `now - since`
+    pub fn due_since(&self, since: NaiveDateTime, now: NaiveDateTime) -> Vec<DigestSchedule> {
+        self.schedules
+            .iter()
+            .filter(|schedule| schedule.last_time(now) > since)
+            .copied()
+            .collect()
+    }
+
+    #[cfg(feature = "telegram")]
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+}
+
+/// Report of the digest period along with its biggest expenses
+pub struct Digest {
+    period: DigestPeriod,
+    report: Report,
+    top_expenses: Vec<BudgetRecord>,
+}
+
+impl Digest {
+    pub fn new(
+        schedule: &DigestSchedule,
+        dates: Range<NaiveDate>,
+        records: &[BudgetRecord],
+    ) -> Self {
+        let mut expenses: Vec<_> = records
+            .iter()
+            .filter(|r| r.kind == RecordKind::Expense && dates.contains(&r.date))
+            .cloned()
+            .collect();
+        expenses.sort_by_key(|record| Reverse(record.total_amount().0));
+        expenses.truncate(TOP_EXPENSES);
+        Digest {
+            period: schedule.period,
+            report: Report::new(dates, records),
+            top_expenses: expenses,
+        }
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.period {
+            DigestPeriod::Weekly(_) => writeln!(f, "Weekly digest")?,
+            DigestPeriod::Monthly(_) => writeln!(f, "Monthly digest")?,
+        }
+        write!(f, "{}", self.report)?;
+        if !self.top_expenses.is_empty() {
+            write!(f, "\nTop expenses:")?;
+        }
+        for record in &self.top_expenses {
+            let (amount, currency) = record.total_amount();
+            write!(
+                f,
+                "\n  {} {}: {:.2} {}",
+                record.date, record.category, amount, currency
+            )?;
+            if !record.desc.is_empty() {
+                write!(f, " ({})", record.desc)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::events::{Currency, SourceRef, DEFAULT_LEDGER};
+
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2021, 3, day).and_hms(hour, 0, 0)
+    }
+
+    fn record(day: u32, category: &str, amount: &str, desc: &str) -> BudgetRecord {
+        let date = NaiveDate::from_ymd(2021, 3, day);
+        BudgetRecord {
+            id: 1,
+            source: SourceRef::default(),
+            date,
+            category: category.to_string(),
+            amount: amount.parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency: Currency::EUR,
+            desc: desc.to_string(),
+            user: "alice".to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: date,
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    #[test]
+    fn schedules_from_config() {
+        let schedules = DigestSchedule::from_config(
+            "-100500:weekly Mon 09:00; 42:monthly 31 20:30;;7:daily 09:00;8:weekly mon 9am",
+        );
+        assert_eq!(
+            schedules,
+            vec![
+                DigestSchedule {
+                    chat: -100500,
+                    period: DigestPeriod::Weekly(Weekday::Mon),
+                    time: NaiveTime::from_hms(9, 0, 0),
+                },
+                DigestSchedule {
+                    chat: 42,
+                    period: DigestPeriod::Monthly(31),
+                    time: NaiveTime::from_hms(20, 30, 0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn last_scheduled_time() {
+        let weekly = DigestSchedule::parse(1, "weekly mon 09:00").unwrap();
+        // 2021-03-15 is Monday
+        assert_eq!(weekly.last_time(at(15, 9)), at(15, 9));
+        assert_eq!(weekly.last_time(at(15, 8)), at(8, 9));
+        assert_eq!(weekly.last_time(at(20, 12)), at(15, 9));
+
+        let monthly = DigestSchedule::parse(1, "monthly 31 09:00").unwrap();
+        assert_eq!(monthly.last_time(at(31, 10)), at(31, 9));
+        assert_eq!(
+            monthly.last_time(at(20, 12)),
+            NaiveDate::from_ymd(2021, 2, 28).and_hms(9, 0, 0)
+        );
+    }
+
+    #[test]
+    fn due_digests_are_sent_once() {
+        let weekly = DigestSchedule::parse(1, "weekly mon 09:00").unwrap();
+        let monthly = DigestSchedule::parse(2, "monthly 1 09:00").unwrap();
+        let mut scheduler = Scheduler::new(vec![weekly, monthly]);
+        assert_eq!(scheduler.due(at(14, 12)), vec![]);
+        assert_eq!(scheduler.due(at(15, 8)), vec![]);
+        assert_eq!(scheduler.due(at(15, 9)), vec![weekly]);
+        assert_eq!(scheduler.due(at(15, 10)), vec![]);
+        // Both are missed while the bot is down, the weekly one twice
+        let due = scheduler.due(NaiveDate::from_ymd(2021, 4, 1).and_hms(10, 0, 0));
+        assert_eq!(due, vec![weekly, monthly]);
+    }
+
+    #[test]
+    fn due_digests_since() {
+        let weekly = DigestSchedule::parse(1, "weekly mon 09:00").unwrap();
+        let scheduler = Scheduler::new(vec![weekly]);
+        assert_eq!(scheduler.due_since(at(15, 8), at(15, 9)), vec![weekly]);
+        assert_eq!(scheduler.due_since(at(15, 9), at(15, 10)), vec![]);
+        assert_eq!(scheduler.due_since(at(14, 12), at(15, 12)), vec![weekly]);
+    }
+
+    #[test]
+    fn digest_dates() {
+        let weekly = DigestSchedule::parse(1, "weekly sun 20:00").unwrap();
+        assert_eq!(
+            weekly.dates(NaiveDate::from_ymd(2021, 3, 21)),
+            NaiveDate::from_ymd(2021, 3, 8)..NaiveDate::from_ymd(2021, 3, 15)
+        );
+        let monthly = DigestSchedule::parse(1, "monthly 1 09:00").unwrap();
+        assert_eq!(
+            monthly.dates(NaiveDate::from_ymd(2021, 1, 1)),
+            NaiveDate::from_ymd(2020, 12, 1)..NaiveDate::from_ymd(2021, 1, 1)
+        );
+    }
+
+    #[test]
+    fn digest_with_top_expenses() {
+        let schedule = DigestSchedule::parse(1, "weekly mon 09:00").unwrap();
+        let dates = schedule.dates(NaiveDate::from_ymd(2021, 3, 15));
+        let records = vec![
+            record(8, "Fruits", "4.5", "banana"),
+            record(9, "Rent", "500", ""),
+            record(10, "Fruits", "3", "apple"),
+            record(12, "Sweets", "10", "candy"),
+            record(15, "Rent", "600", ""),
+        ];
+        let digest = Digest::new(&schedule, dates, &records).to_string();
+        assert_eq!(
+            digest,
+            "Weekly digest\n\
+             Report for 2021-03-08 - 2021-03-14\n\
+             By category:\n  Rent: 500.00 EUR\n  Sweets: 10.00 EUR\n  Fruits: 7.50 EUR\n\
+             By user:\n  alice: 517.50 EUR\n\
+             Total: 517.50 EUR\n\
+             Top expenses:\n  \
+             2021-03-09 Rent: 500.00 EUR\n  \
+             2021-03-12 Sweets: 10.00 EUR (candy)\n  \
+             2021-03-08 Fruits: 4.50 EUR (banana)"
+        );
+    }
+}
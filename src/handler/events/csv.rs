@@ -1,57 +1,782 @@
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDate;
 
 use csv;
+use log::{debug, warn};
+
+use crate::error::Error;
+use crate::handler::accounts::{Account, AccountProvider};
+use crate::handler::categorizer::{Category, CategoryProvider, LearnedWord, LearnedWordsProvider};
+use crate::handler::events::{
+    find_by_source, BudgetRecord, EventHandler, HandlerEvent, RecordId, RecordsProvider, SourceRef,
+    DEFAULT_LEDGER,
+};
+
+/// Learned words are common for all ledgers like categories
+const LEARNED_WORDS_FILE: &str = "learned_words.csv";
 
-use crate::handler::categorizer::{Category, CategoryProvider};
-use crate::handler::events::{EventHandler, HandlerEvent};
+/// Columns of the records file, files with other columns are migrated on start
+const COLUMNS: &[&str] = &[
+    "id",
+    "source",
+    "date",
+    "category",
+    "amount",
+    "kind",
+    "currency",
+    "desc",
+    "user",
+    "account",
+    "to_account",
+    "tags",
+    "create_date",
+    "base_amount",
+    "base_currency",
+    "ledger",
+];
 
 pub struct CsvEventHandler {
+    path: PathBuf,
     writer: csv::Writer<File>,
+    /// The greatest id in the file, read once on the first added record
+    last_id: Option<RecordId>,
 }
 
 impl CategoryProvider for CsvEventHandler {
-    fn categories(&self) -> Vec<Category> {
-        let file = OpenOptions::new()
-            .read(true)
-            .open("categories.csv")
-            .expect("Can't read categories.csv");
-        let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_reader(file);
-        let mut iter = reader.deserialize();
-        let mut categories = vec![];
-        while let Some(Ok(category)) = iter.next() {
-            categories.push(category);
+    fn categories(&self) -> Result<Vec<Category>, Error> {
+        read_categories(Path::new("categories.csv"))
+    }
+}
+
+impl AccountProvider for CsvEventHandler {
+    fn accounts(&self) -> Result<Vec<Account>, Error> {
+        let path = Path::new("accounts.csv");
+        if path.exists() {
+            read_accounts(path)
+        } else {
+            Ok(vec![])
         }
-        categories
+    }
+}
+
+impl LearnedWordsProvider for CsvEventHandler {
+    fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+        let path = Path::new(LEARNED_WORDS_FILE);
+        if path.exists() {
+            read_learned_words(path)
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    fn learn_word(&mut self, word: &LearnedWord) -> Result<(), Error> {
+        append_learned_word(Path::new(LEARNED_WORDS_FILE), word)
     }
 }
 
 impl CsvEventHandler {
-    pub fn new() -> Self {
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .append(true)
-            .open("records.csv")
-            .expect("Can't create or read records.csv");
-        let is_empty = file.metadata().map(|meta| meta.len() == 0).unwrap_or(true);
-        CsvEventHandler {
-            writer: csv::WriterBuilder::new()
-                .has_headers(is_empty)
-                .from_writer(file),
+    /// Records of the default ledger are kept in records.csv and others in records-<ledger>.csv,
+    /// categories and optional accounts.csv are common for all of them
+    pub fn new(ledger: &str) -> Result<Self, Error> {
+        CsvEventHandler::with_records_file(records_path(ledger))
+    }
+
+    fn with_records_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        migrate_records(&path)
+            .map_err(|err| Error::Storage(format!("Can't migrate {}: {}", path.display(), err)))?;
+        let writer = open_append_writer(&path).map_err(|err| {
+            Error::Storage(format!("Can't create or read {}: {}", path.display(), err))
+        })?;
+        Ok(CsvEventHandler {
+            path,
+            writer,
+            last_id: None,
+        })
+    }
+
+    /// Replace the row with `id` by `replacement` or remove it if there is no replacement
+    ///
+    /// All rows are copied into a temporary file next to the records file, which then
+    /// atomically replaces the original one, so the ledger is never left half-written.
+    fn rewrite_record(
+        &mut self,
+        id: RecordId,
+        replacement: Option<BudgetRecord>,
+    ) -> Result<(), Error> {
+        self.writer
+            .flush()
+            .map_err(|err| Error::Storage(format!("Error during flush records: {}", err)))?;
+
+        let tmp_path = self.path.with_extension("csv.tmp");
+        let mut found = false;
+        let mut replacement = replacement;
+        let copied = copy_records(&self.path, &tmp_path, |row| {
+            if !found && row.id == id {
+                found = true;
+                replacement.take()
+            } else {
+                Some(row)
+            }
+        })
+        .map(|_| found)
+        .map_err(|err| Error::Storage(format!("Error during rewrite record: {}", err)));
+        match copied {
+            Ok(true) => {}
+            Ok(false) => {
+                let _ = fs::remove_file(&tmp_path);
+                warn!("Record #{} is not found", id);
+                return Err(Error::Storage(format!("Record #{} is not found", id)));
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
         }
+
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|err| Error::Storage(format!("Error during replace records file: {}", err)))?;
+        // The old writer points to the replaced file, so it has to be reopened
+        self.writer = open_append_writer(&self.path)
+            .map_err(|err| Error::Storage(format!("Error during reopen records file: {}", err)))?;
+        debug!("Record #{} rewritten", id);
+        Ok(())
+    }
+
+    /// Ids go on from the greatest one in the file, which is only read by the first call
+    fn next_id(&mut self) -> Result<RecordId, Error> {
+        let last = match self.last_id {
+            Some(last) => last,
+            None => {
+                self.writer.flush().map_err(|err| {
+                    Error::Storage(format!("Error during flush records: {}", err))
+                })?;
+                let last = self.read_records()?.iter().map(|record| record.id).max();
+                last.unwrap_or(0)
+            }
+        };
+        self.last_id = Some(last + 1);
+        Ok(last + 1)
+    }
+
+    fn read_records(&self) -> Result<Vec<BudgetRecord>, Error> {
+        let mut reader = csv::Reader::from_path(&self.path)
+            .map_err(|err| Error::Storage(format!("Error during read records: {}", err)))?;
+        reader
+            .deserialize()
+            .collect::<csv::Result<_>>()
+            .map_err(|err| Error::Storage(format!("Error during read records: {}", err)))
     }
 }
 
 impl EventHandler for CsvEventHandler {
-    fn handle_event(&mut self, event: HandlerEvent) -> Result<(), String> {
+    fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
         match event {
-            HandlerEvent::AddRecord(record) => self
-                .writer
-                .serialize(record)
-                .map_err(|_| "Error during save record".to_string()),
-            HandlerEvent::UpdateRecord(_) => {
-                Err("Update record is not implemented yet".to_string())
+            HandlerEvent::AddRecord(mut record) => {
+                record.id = self.next_id()?;
+                self.writer
+                    .serialize(&record)
+                    .map_err(|err| Error::Storage(format!("Error during save record: {}", err)))?;
+                self.writer.flush().map_err(|err| {
+                    Error::Storage(format!("Error during flush records: {}", err))
+                })?;
+                Ok(HandlerEvent::AddRecord(record))
             }
+            HandlerEvent::UpdateRecord(record) => {
+                self.rewrite_record(record.id, Some(record.clone()))?;
+                Ok(HandlerEvent::UpdateRecord(record))
+            }
+            HandlerEvent::DeleteRecord(id) => {
+                self.rewrite_record(id, None)?;
+                Ok(HandlerEvent::DeleteRecord(id))
+            }
+        }
+    }
+}
+
+impl RecordsProvider for CsvEventHandler {
+    fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+        let mut records = self.read_records()?;
+        records.retain(|record| dates.contains(&record.date));
+        Ok(records)
+    }
+
+    fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+        Ok(find_by_source(self.read_records()?, source))
+    }
+
+    fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error> {
+        let records = self.read_records()?.into_iter();
+        Ok(records
+            .filter(|record| record.user == user)
+            .max_by_key(|record| record.id))
+    }
+}
+
+fn records_path(ledger: &str) -> PathBuf {
+    if ledger == DEFAULT_LEDGER {
+        PathBuf::from("records.csv")
+    } else {
+        PathBuf::from(format!("records-{}.csv", ledger))
+    }
+}
+
+/// Read categories separated with `;`, invalid rows are skipped
+fn read_categories(path: &Path) -> Result<Vec<Category>, Error> {
+    let file = File::open(path)
+        .map_err(|err| Error::Config(format!("Can't read {}: {}", path.display(), err)))?;
+    let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_reader(file);
+    let mut categories = vec![];
+    for row in reader.deserialize() {
+        match row {
+            Ok(category) => categories.push(category),
+            Err(err) => warn!("Skip invalid category in {}: {}", path.display(), err),
+        }
+    }
+    Ok(categories)
+}
+
+/// Read accounts with `name;lexemes` columns, invalid rows are skipped
+fn read_accounts(path: &Path) -> Result<Vec<Account>, Error> {
+    let file = File::open(path)
+        .map_err(|err| Error::Config(format!("Can't read {}: {}", path.display(), err)))?;
+    let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_reader(file);
+    let mut accounts = vec![];
+    for row in reader.deserialize() {
+        match row {
+            Ok(account) => accounts.push(account),
+            Err(err) => warn!("Skip invalid account in {}: {}", path.display(), err),
+        }
+    }
+    Ok(accounts)
+}
+
+/// Read learned words with `word;category` columns, invalid rows are skipped
+fn read_learned_words(path: &Path) -> Result<Vec<LearnedWord>, Error> {
+    let file = File::open(path)
+        .map_err(|err| Error::Storage(format!("Can't read {}: {}", path.display(), err)))?;
+    let mut reader = csv::ReaderBuilder::new().delimiter(b';').from_reader(file);
+    let mut words = vec![];
+    for row in reader.deserialize() {
+        match row {
+            Ok(word) => words.push(word),
+            Err(err) => warn!("Skip invalid learned word in {}: {}", path.display(), err),
+        }
+    }
+    Ok(words)
+}
+
+fn append_learned_word(path: &Path, word: &LearnedWord) -> Result<(), Error> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| Error::Storage(format!("Can't open {}: {}", path.display(), err)))?;
+    let is_empty = file.metadata().map(|meta| meta.len() == 0).unwrap_or(true);
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .has_headers(is_empty)
+        .from_writer(file);
+    writer
+        .serialize(word)
+        .map_err(|err| Error::Storage(format!("Error during save learned word: {}", err)))?;
+    writer
+        .flush()
+        .map_err(|err| Error::Storage(format!("Error during flush learned words: {}", err)))
+}
+
+fn open_append_writer(path: &Path) -> io::Result<csv::Writer<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let is_empty = file.metadata().map(|meta| meta.len() == 0).unwrap_or(true);
+    Ok(csv::WriterBuilder::new()
+        .has_headers(is_empty)
+        .from_writer(file))
+}
+
+/// Rewrite the records file if it was created with other columns, e.g. before currencies.
+/// Missing values are filled with defaults.
+fn migrate_records(path: &Path) -> csv::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let headers = csv::Reader::from_path(path)?.headers()?.clone();
+    if headers.is_empty() || headers.iter().eq(COLUMNS.iter().copied()) {
+        return Ok(());
+    }
+    warn!("Migrating {} from columns {:?}", path.display(), headers);
+    let tmp_path = path.with_extension("csv.tmp");
+    copy_records(path, &tmp_path, Some)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Copy all records from `src` to `dst` passing each one through `map`,
+/// records mapped to `None` are skipped
+fn copy_records<F>(src: &Path, dst: &Path, mut map: F) -> csv::Result<()>
+where
+    F: FnMut(BudgetRecord) -> Option<BudgetRecord>,
+{
+    let mut reader = csv::Reader::from_path(src)?;
+    let file = File::create(dst)?;
+    let mut writer = csv::Writer::from_writer(&file);
+    for row in reader.deserialize() {
+        if let Some(record) = map(row?) {
+            writer.serialize(record)?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::NaiveDate;
+
+    use crate::handler::events::{Currency, RecordKind};
+
+    use super::*;
+
+    fn records_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("{}-{}.csv", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn record(id: i64, amount: &str) -> BudgetRecord {
+        record_on(id, amount, NaiveDate::from_ymd(2021, 3, 12))
+    }
+
+    fn record_on(id: i64, amount: &str, date: NaiveDate) -> BudgetRecord {
+        BudgetRecord {
+            id,
+            source: SourceRef {
+                source: "test".to_string(),
+                chat_id: None,
+                message_id: id,
+                part: 0,
+            },
+            date,
+            category: "Fruits".to_string(),
+            amount: amount.parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency: Currency::EUR,
+            desc: "banana".to_string(),
+            user: "user".to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: date,
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    fn read_records(path: &Path) -> Vec<BudgetRecord> {
+        csv::Reader::from_path(path)
+            .unwrap()
+            .deserialize()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn add_records_without_rereading_ids() {
+        let path = records_file("add_records_without_rereading_ids");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        let add = |handler: &mut CsvEventHandler| match handler
+            .handle_event(HandlerEvent::AddRecord(record(0, "10")))
+            .unwrap()
+        {
+            HandlerEvent::AddRecord(record) => record.id,
+            event => panic!("Unexpected {:?}", event),
+        };
+        let first = add(&mut handler);
+        // The next id would fail to be read from the file
+        fs::remove_file(&path).unwrap();
+        let second = add(&mut handler);
+        assert_eq!((first, second), (1, 2));
+    }
+
+    #[test]
+    fn update_existing_record() {
+        let path = records_file("update_existing_record");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(1, "10")))
+            .unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(2, "20")))
+            .unwrap();
+
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(record(1, "15")))
+            .unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(3, "30")))
+            .unwrap();
+        drop(handler);
+
+        let amounts: Vec<_> = read_records(&path)
+            .into_iter()
+            .map(|r| (r.id, r.amount.to_string()))
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                (1, "15".to_string()),
+                (2, "20".to_string()),
+                (3, "30".to_string())
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_record_found_by_source() {
+        let path = records_file("update_record_found_by_source");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        // The same message id in two chats
+        let mut first = record(0, "10");
+        first.source = "telegram:1:42".parse().unwrap();
+        let mut second = record(0, "20");
+        second.source = "telegram:2:42".parse().unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(first))
+            .unwrap();
+        let added = handler
+            .handle_event(HandlerEvent::AddRecord(second.clone()))
+            .unwrap();
+        assert!(matches!(added, HandlerEvent::AddRecord(record) if record.id == 2));
+
+        let found = handler.record_by_source(&second.source).unwrap().unwrap();
+        let edited = BudgetRecord {
+            amount: "25".parse().unwrap(),
+            ..found
+        };
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(edited))
+            .unwrap();
+
+        let amounts: Vec<_> = read_records(&path)
+            .into_iter()
+            .map(|r| (r.id, r.amount.to_string()))
+            .collect();
+        assert_eq!(amounts, vec![(1, "10".to_string()), (2, "25".to_string())]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_legacy_record() {
+        let path = records_file("update_legacy_record");
+        // Ids were message ids before sources were kept
+        fs::write(
+            &path,
+            "id,date,category,amount,desc,user,create_date\n\
+             42,2021-03-12,Fruits,10.5,banana,user,2021-03-12\n",
+        )
+        .unwrap();
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        let source: SourceRef = "telegram:-100500:42".parse().unwrap();
+
+        let found = handler.record_by_source(&source).unwrap().unwrap();
+        assert_eq!(found.id, 42);
+        let edited = BudgetRecord {
+            amount: "12".parse().unwrap(),
+            source: source.clone(),
+            ..found
+        };
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(edited))
+            .unwrap();
+        let added = handler
+            .handle_event(HandlerEvent::AddRecord(record(0, "20")))
+            .unwrap();
+        assert!(matches!(added, HandlerEvent::AddRecord(record) if record.id == 43));
+        drop(handler);
+
+        let records: Vec<_> = read_records(&path)
+            .into_iter()
+            .map(|r| (r.id, r.source.to_string(), r.amount.to_string()))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (42, source.to_string(), "12".to_string()),
+                (43, "test::0".to_string(), "20".to_string())
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn update_missing_record() {
+        let path = records_file("update_missing_record");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(1, "10")))
+            .unwrap();
+
+        let result = handler.handle_event(HandlerEvent::UpdateRecord(record(2, "15")));
+
+        assert_eq!(
+            result.err(),
+            Some(Error::Storage("Record #2 is not found".to_string()))
+        );
+        assert!(!path.with_extension("csv.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_record() {
+        let path = records_file("delete_record");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        for id in 1..=3 {
+            handler
+                .handle_event(HandlerEvent::AddRecord(record(id, "10")))
+                .unwrap();
         }
+
+        handler.handle_event(HandlerEvent::DeleteRecord(2)).unwrap();
+        let result = handler.handle_event(HandlerEvent::DeleteRecord(2));
+        drop(handler);
+
+        assert_eq!(
+            result.err(),
+            Some(Error::Storage("Record #2 is not found".to_string()))
+        );
+        let ids: Vec<_> = read_records(&path).into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_records_within_dates() {
+        let path = records_file("read_records_within_dates");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        for (id, day) in [(1, 10), (2, 12), (3, 20)].iter() {
+            let date = NaiveDate::from_ymd(2021, 3, *day);
+            handler
+                .handle_event(HandlerEvent::AddRecord(record_on(*id, "10", date)))
+                .unwrap();
+        }
+
+        let dates = NaiveDate::from_ymd(2021, 3, 11)..NaiveDate::from_ymd(2021, 3, 20);
+        let ids: Vec<_> = handler
+            .records(dates)
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+
+        assert_eq!(ids, vec![2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_file_per_ledger() {
+        assert_eq!(records_path(DEFAULT_LEDGER), PathBuf::from("records.csv"));
+        assert_eq!(
+            records_path("chat-100500"),
+            PathBuf::from("records-chat-100500.csv")
+        );
+    }
+
+    #[test]
+    fn columns_match_record_fields() {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(record(1, "10")).unwrap();
+        let data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(data.lines().next(), Some(COLUMNS.join(",").as_ref()));
+    }
+
+    #[test]
+    fn write_and_read_tags() {
+        let path = records_file("write_and_read_tags");
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        let mut tagged = record(1, "10");
+        tagged.tags = vec!["vacation".to_string(), "kids".to_string()];
+        handler
+            .handle_event(HandlerEvent::AddRecord(tagged))
+            .unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(2, "20")))
+            .unwrap();
+        drop(handler);
+
+        let tags: Vec<_> = read_records(&path).into_iter().map(|r| r.tags).collect();
+        assert_eq!(
+            tags,
+            vec![vec!["vacation".to_string(), "kids".to_string()], vec![]]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate_records_without_currency() {
+        let path = records_file("migrate_records_without_currency");
+        fs::write(
+            &path,
+            "id,date,category,amount,desc,user,create_date\n\
+             1,2021-03-12,Fruits,10.5,banana,user,2021-03-12\n",
+        )
+        .unwrap();
+
+        let mut handler = CsvEventHandler::with_records_file(&path).unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(record(2, "20")))
+            .unwrap();
+        drop(handler);
+
+        let currencies: Vec<_> = read_records(&path)
+            .into_iter()
+            .map(|r| (r.id, r.amount.to_string(), r.currency, r.ledger))
+            .collect();
+        assert_eq!(
+            currencies,
+            vec![
+                (
+                    1,
+                    "10.5".to_string(),
+                    Currency::RUB,
+                    DEFAULT_LEDGER.to_string()
+                ),
+                (
+                    2,
+                    "20".to_string(),
+                    Currency::EUR,
+                    DEFAULT_LEDGER.to_string()
+                )
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_invalid_categories() {
+        let path = records_file("skip_invalid_categories");
+        fs::write(
+            &path,
+            "priority;name;lexemes\n1;Fruits;banana\nhigh;Sweets;candy\n2;Pets;cat\n",
+        )
+        .unwrap();
+
+        let names: Vec<_> = read_categories(&path)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+
+        assert_eq!(names, vec!["Fruits", "Pets"]);
+        assert!(matches!(
+            read_categories(&records_file("missing_categories")),
+            Err(Error::Config(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_categories_with_kind_and_limit() {
+        let path = records_file("read_categories_with_kind_and_limit");
+        fs::write(
+            &path,
+            "priority;name;lexemes;kind;limit\n1;Fruits;banana;;300\n2;Salary;salary;income;\n",
+        )
+        .unwrap();
+
+        let kinds: Vec<_> = read_categories(&path)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.kind, c.limit))
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                (
+                    "Fruits".to_string(),
+                    RecordKind::Expense,
+                    "300".parse().ok()
+                ),
+                ("Salary".to_string(), RecordKind::Income, None)
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_categories_with_parent() {
+        let path = records_file("read_categories_with_parent");
+        fs::write(
+            &path,
+            "priority;name;lexemes;parent
+1;Food;;
+2;Dairy;milk;Food
+",
+        )
+        .unwrap();
+
+        let parents: Vec<_> = read_categories(&path)
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.parent))
+            .collect();
+
+        assert_eq!(
+            parents,
+            vec![
+                ("Food".to_string(), None),
+                ("Dairy".to_string(), Some("Food".to_string()))
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn append_and_read_learned_words() {
+        let path = records_file("append_and_read_learned_words");
+        let word = |word: &str, category: &str| LearnedWord {
+            word: word.to_string(),
+            category: category.to_string(),
+        };
+        append_learned_word(&path, &word("ashan", "Fruits")).unwrap();
+        append_learned_word(&path, &word("starbucks", "Cafe")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "word;category\nashan;Fruits\nstarbucks;Cafe\n"
+        );
+        assert_eq!(
+            read_learned_words(&path).unwrap(),
+            vec![word("ashan", "Fruits"), word("starbucks", "Cafe")]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skip_invalid_accounts() {
+        let path = records_file("skip_invalid_accounts");
+        fs::write(&path, "name;lexemes\nVisa;visa,card\nCash\nWallet;cash\n").unwrap();
+
+        let names: Vec<_> = read_accounts(&path)
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect();
+
+        assert_eq!(names, vec!["Visa", "Wallet"]);
+        fs::remove_file(&path).unwrap();
     }
 }
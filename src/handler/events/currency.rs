@@ -0,0 +1,178 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+
+use log::warn;
+
+use crate::handler::user_settings::PerUser;
+
+const CURRENCY_ENV: &str = "BUDGET_CURRENCY";
+const USER_CURRENCIES_ENV: &str = "BUDGET_USER_CURRENCIES";
+
+/// ISO 4217 currency code like `EUR`
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const RUB: Currency = Currency(*b"RUB");
+    pub const USD: Currency = Currency(*b"USD");
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const GBP: Currency = Currency(*b"GBP");
+    pub const JPY: Currency = Currency(*b"JPY");
+
+    /// Codes recognized in messages, anything else is likely an ordinary word
+    const KNOWN: &'static [&'static str] = &[
+        "RUB", "USD", "EUR", "GBP", "JPY", "CNY", "CHF", "UAH", "KZT", "BYN", "GEL", "AMD", "TRY",
+        "PLN", "CZK", "SEK", "NOK", "DKK", "CAD", "AUD", "THB",
+    ];
+
+    /// Detect currency written as a separate word by a symbol, a localized word
+    /// or a known code in uppercase, e.g. `€`, `рублей` or `USD`.
+    /// Codes in lowercase are likely ordinary words like "try".
+    pub fn detect(word: &str) -> Option<Currency> {
+        Currency::detect_name(word).or_else(|| Currency::known_code(word))
+    }
+
+    /// Detect currency attached to an amount like `5р` or `12usd`,
+    /// codes may be written in any case there
+    pub fn detect_attached(word: &str) -> Option<Currency> {
+        match word.to_lowercase().as_ref() {
+            "р" => Some(Currency::RUB),
+            _ => Currency::detect_name(word).or_else(|| Currency::known_code(&word.to_uppercase())),
+        }
+    }
+
+    fn detect_name(word: &str) -> Option<Currency> {
+        let word = word.to_lowercase();
+        let currency = match word.as_ref() {
+            "₽" | "руб" | "рубль" | "рубля" | "рублей" => Currency::RUB,
+            "$" | "dollar" | "dollars" | "доллар" | "доллара" | "долларов" | "бакс" | "бакса"
+            | "баксов" => Currency::USD,
+            "€" | "euro" | "euros" | "евро" => Currency::EUR,
+            "£" => Currency::GBP,
+            "¥" => Currency::JPY,
+            _ => return None,
+        };
+        Some(currency)
+    }
+
+    fn known_code(code: &str) -> Option<Currency> {
+        if Currency::KNOWN.contains(&code) {
+            code.parse().ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn code(&self) -> &str {
+        // Only ASCII letters get here from the constructors
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+/// Records stored before currencies were introduced are treated as roubles
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::RUB
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.trim().to_uppercase();
+        match code.as_bytes() {
+            &[a, b, c] if code.bytes().all(|b| b.is_ascii_uppercase()) => Ok(Currency([a, b, c])),
+            _ => Err(format!("Invalid currency code '{}'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.to_string()
+    }
+}
+
+/// Currency of amounts written without one, configured per user
+pub type UserCurrencies = PerUser<Currency>;
+
+impl UserCurrencies {
+    /// Read currencies from env vars:
+    /// * `BUDGET_CURRENCY` - default currency code, `RUB` if not set
+    /// * `BUDGET_USER_CURRENCIES` - currencies per user, e.g. `alice:EUR;bob:USD`
+    pub fn from_env() -> Self {
+        UserCurrencies::from_config(
+            env::var(CURRENCY_ENV).ok().as_deref(),
+            env::var(USER_CURRENCIES_ENV).ok().as_deref(),
+        )
+    }
+
+    fn from_config(default: Option<&str>, users: Option<&str>) -> Self {
+        let default = default
+            .and_then(|code| code.parse().map_err(|err| warn!("{}", err)).ok())
+            .unwrap_or_default();
+        PerUser::new(default).with_users(users.unwrap_or_default(), |code| code.parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str() {
+        assert_eq!("eur".parse(), Ok(Currency::EUR));
+        assert_eq!(" USD ".parse(), Ok(Currency::USD));
+        assert!("US".parse::<Currency>().is_err());
+        assert!("РУБ".parse::<Currency>().is_err());
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Currency::detect("€"), Some(Currency::EUR));
+        assert_eq!(Currency::detect("Рублей"), Some(Currency::RUB));
+        assert_eq!(Currency::detect("CHF"), "CHF".parse().ok());
+        assert_eq!(Currency::detect("usb"), None);
+        assert_eq!(Currency::detect("banana"), None);
+        // Ordinary words and a bare letter are not currencies
+        assert_eq!(Currency::detect("try"), None);
+        assert_eq!(Currency::detect("р"), None);
+    }
+
+    #[test]
+    fn detect_attached() {
+        assert_eq!(Currency::detect_attached("р"), Some(Currency::RUB));
+        assert_eq!(Currency::detect_attached("chf"), "CHF".parse().ok());
+        assert_eq!(Currency::detect_attached("$"), Some(Currency::USD));
+        assert_eq!(Currency::detect_attached("x"), None);
+    }
+
+    #[test]
+    fn currencies_per_user() {
+        let currencies = UserCurrencies::from_config(Some("eur"), Some("alice:USD;bob:dollars"));
+        assert_eq!(currencies.for_user("alice"), &Currency::USD);
+        assert_eq!(currencies.for_user("bob"), &Currency::EUR);
+        assert_eq!(
+            UserCurrencies::from_config(None, None).for_user("bob"),
+            &Currency::RUB
+        );
+    }
+}
@@ -1,25 +1,33 @@
 use core::fmt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
 use std::str::FromStr;
 
-use chrono::{Local, NaiveDate};
+use chrono::{Datelike, Duration, NaiveDate};
 use google_sheets4::{
     AddConditionalFormatRuleRequest, AddSheetRequest, BasicFilter, BatchUpdateSpreadsheetRequest,
     BooleanCondition, BooleanRule, CellData, CellFormat, ClearValuesRequest, Color, ConditionValue,
-    ConditionalFormatRule, Error, GridCoordinate, GridProperties, GridRange, NumberFormat,
+    ConditionalFormatRule, ExtendedValue, GridCoordinate, GridProperties, GridRange, NumberFormat,
     PivotGroup, PivotTable, PivotValue, RepeatCellRequest, Request, RowData, SetBasicFilterRequest,
     SheetProperties, Sheets, SortSpec, TextFormat, UpdateCellsRequest, ValueRange,
 };
 use hyper::Client;
-use log::{debug, error, warn};
+use log::{debug, warn};
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use serde::export::{fmt::Display, Formatter};
+use rust_decimal::Decimal;
 use yup_oauth2::{ServiceAccountAccess, ServiceAccountKey};
 
+use crate::error::Error;
 use crate::handler::{
-    categorizer::{Category, CategoryProvider},
-    events::{BudgetRecord, EventHandler, HandlerEvent},
+    accounts::{Account, AccountProvider},
+    categorizer::{Category, CategoryProvider, LearnedWord, LearnedWordsProvider, PATH_SEPARATOR},
+    clock::{Clock, SystemClock},
+    events::{
+        Amount, BudgetRecord, EventHandler, HandlerEvent, Locale, RecordId, RecordKind,
+        RecordsProvider, SourceRef, DEFAULT_LEDGER,
+    },
 };
 
 const SS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
@@ -40,17 +48,53 @@ impl Display for SortOrder {
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 enum Column {
     Date,
     Amount,
     Category,
     Description,
     User,
-    MessageId,
+    Id,
+    Currency,
+    BaseAmount,
+    BaseCurrency,
+    Source,
+    Kind,
+    Account,
+    Tags,
+    /// The first category of the record category path, used to roll totals up
+    TopCategory,
+    ToAccount,
     _Count,
-    _PivotTable,
 }
 
+/// Titles of the record columns, sheets with another header are created by older versions
+const HEADER: &[&str] = &[
+    "Date",
+    "Amount",
+    "Category",
+    "Description",
+    "User",
+    "Id",
+    "Currency",
+    "Base Amount",
+    "Base Currency",
+    "Source",
+    "Kind",
+    "Account",
+    "Tags",
+    "Top category",
+    "To account",
+];
+
+/// Sheet reserving record ids, month sheets have ids like 202103
+const IDS_SHEET_ID: i32 = 1;
+
+/// The pivot table is anchored at column AA, far enough from the record columns
+/// to keep it in place when new ones are added
+const PIVOT_TABLE_COLUMN: i32 = 26;
+
 impl Column {
     fn name(self) -> String {
         match self as i32 {
@@ -64,6 +108,12 @@ impl Column {
             7 => String::from("H"),
             8 => String::from("I"),
             9 => String::from("J"),
+            10 => String::from("K"),
+            11 => String::from("L"),
+            12 => String::from("M"),
+            13 => String::from("N"),
+            14 => String::from("O"),
+            15 => String::from("P"),
             _ => unreachable!(),
         }
     }
@@ -75,13 +125,14 @@ trait NaiveDateExt {
 
 impl NaiveDateExt for NaiveDate {
     fn get_sheet_id(&self) -> i32 {
-        self.format("%Y%m").to_string().parse().unwrap()
+        self.year() * 100 + self.month() as i32
     }
 }
 
 trait BudgetRecordExt {
     fn to_value_range(&self, range: Option<&GssRange>, major_dimension: Option<&str>)
         -> ValueRange;
+    fn from_row(row: &[String], ledger: &str) -> Option<BudgetRecord>;
 }
 
 impl BudgetRecordExt for BudgetRecord {
@@ -94,15 +145,71 @@ impl BudgetRecordExt for BudgetRecord {
             range: range.map(|r| r.to_string()),
             values: Some(vec![vec![
                 self.date.to_string(),
-                self.amount.to_string().replace('.', ","),
+                self.amount.localized(Locale::Ru).to_string(),
                 self.category.to_owned(),
                 self.desc.to_owned(),
                 self.user.to_owned(),
                 self.id.to_string(),
+                self.currency.to_string(),
+                self.base_amount
+                    .map(|amount| amount.localized(Locale::Ru).to_string())
+                    .unwrap_or_default(),
+                self.base_currency
+                    .map(|currency| currency.to_string())
+                    .unwrap_or_default(),
+                self.source.to_string(),
+                self.kind.to_string(),
+                self.account.clone().unwrap_or_default(),
+                self.tags.join(" "),
+                top_category(&self.category).to_owned(),
+                self.to_account.clone().unwrap_or_default(),
             ]]),
             major_dimension: major_dimension.map(|s| s.to_owned()),
         }
     }
+
+    /// Parse row fetched with unformatted values and dates as serial numbers
+    fn from_row(row: &[String], ledger: &str) -> Option<BudgetRecord> {
+        let serial = row.get(0)?.parse::<f64>().ok()?;
+        let date = NaiveDate::from_ymd(1899, 12, 30) + Duration::days(serial as i64);
+        // Unformatted value may have float noise like 9.749999999
+        let amount = Amount(Decimal::from_str(row.get(1)?).ok()?.round_dp(2));
+        Some(BudgetRecord {
+            id: row.get(5)?.parse().ok()?,
+            date,
+            category: row.get(2)?.to_owned(),
+            amount,
+            // Sheets filled before currencies were introduced have no such column
+            currency: row
+                .get(6)
+                .and_then(|code| code.parse().ok())
+                .unwrap_or_default(),
+            desc: row.get(3).cloned().unwrap_or_default(),
+            user: row.get(4).cloned().unwrap_or_default(),
+            create_date: date,
+            base_amount: row
+                .get(7)
+                .and_then(|amount| Decimal::from_str(amount).ok())
+                .map(|amount| Amount(amount.round_dp(2))),
+            base_currency: row.get(8).and_then(|code| code.parse().ok()),
+            ledger: ledger.to_owned(),
+            // Rows added before sources were introduced have no source
+            source: row
+                .get(9)
+                .and_then(|source| source.parse().ok())
+                .unwrap_or_default(),
+            kind: row
+                .get(10)
+                .and_then(|kind| kind.parse().ok())
+                .unwrap_or_default(),
+            account: row.get(11).filter(|account| !account.is_empty()).cloned(),
+            tags: row
+                .get(12)
+                .map(|tags| tags.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            to_account: row.get(14).filter(|account| !account.is_empty()).cloned(),
+        })
+    }
 }
 
 impl From<(&str, &str)> for GssRange {
@@ -139,28 +246,67 @@ impl AsRef<str> for GssRange {
 
 pub struct GoogleDocsEventHandler {
     categories_sheet_name: String,
+    accounts_sheet_name: String,
+    learned_words_sheet_name: String,
+    ids_sheet_name: String,
     data_sheet_name_format: String,
     key: ServiceAccountKey,
     ss_id: String,
+    ledger: String,
+    clock: Box<dyn Clock + Send + Sync>,
+    /// Sheets which layout is up to date
+    checked_sheets: HashSet<i32>,
+    /// The greatest id of records added before the ids sheet, read once
+    ids_offset: Option<RecordId>,
 }
 
 impl GoogleDocsEventHandler {
-    pub fn new() -> Self {
-        let ss_id = env::var("GSS_SPREADSHEET_ID").expect("GSS_SPREADSHEET_ID must be provided");
-        let creds = env::var("GSS_CREDENTIALS").expect("GSS_CREDENTIALS must be provided");
+    /// Every ledger has its own spreadsheet, the default one is `GSS_SPREADSHEET_ID`
+    /// and others are listed in `GSS_LEDGER_SPREADSHEETS` like `family:<id>;chat-100500:<id>`
+    pub fn new(ledger: &str) -> Result<Self, Error> {
+        let ss_id = if ledger == DEFAULT_LEDGER {
+            env::var("GSS_SPREADSHEET_ID")
+                .map_err(|_| Error::Config("GSS_SPREADSHEET_ID must be provided".to_string()))?
+        } else {
+            let spreadsheets = env::var("GSS_LEDGER_SPREADSHEETS").unwrap_or_default();
+            ledger_spreadsheet(&spreadsheets, ledger).ok_or_else(|| {
+                Error::Config(format!(
+                    "Ledger '{}' has no spreadsheet in GSS_LEDGER_SPREADSHEETS",
+                    ledger
+                ))
+            })?
+        };
+        let creds = env::var("GSS_CREDENTIALS")
+            .map_err(|_| Error::Config("GSS_CREDENTIALS must be provided".to_string()))?;
         let data_sheet_name_format =
             env::var("GSS_DATA_SHEET_NAME_FORMAT").unwrap_or("%Y-%m".to_owned());
         let categories_sheet_name =
             env::var("GSS_CATEGORIES_SHEET_NAME").unwrap_or("Categories".to_owned());
-        let key = serde_json::from_str::<ServiceAccountKey>(&creds)
-            .expect("GSS_CREDENTIALS must be a valid credentials JSON");
+        let accounts_sheet_name =
+            env::var("GSS_ACCOUNTS_SHEET_NAME").unwrap_or("Accounts".to_owned());
+        let learned_words_sheet_name =
+            env::var("GSS_LEARNED_WORDS_SHEET_NAME").unwrap_or("Learned words".to_owned());
+        let ids_sheet_name = env::var("GSS_IDS_SHEET_NAME").unwrap_or("Record ids".to_owned());
+        let key = serde_json::from_str::<ServiceAccountKey>(&creds).map_err(|err| {
+            Error::Config(format!(
+                "GSS_CREDENTIALS must be a valid credentials JSON: {}",
+                err
+            ))
+        })?;
 
-        GoogleDocsEventHandler {
+        Ok(GoogleDocsEventHandler {
             categories_sheet_name,
+            accounts_sheet_name,
+            learned_words_sheet_name,
+            ids_sheet_name,
             ss_id,
             key,
             data_sheet_name_format,
-        }
+            ledger: ledger.to_owned(),
+            clock: Box::new(SystemClock),
+            checked_sheets: HashSet::new(),
+            ids_offset: None,
+        })
     }
 
     fn auth(&self) -> ServiceAccountAccess<Client> {
@@ -184,51 +330,174 @@ impl GoogleDocsEventHandler {
 }
 
 impl CategoryProvider for GoogleDocsEventHandler {
-    fn categories(&self) -> Vec<Category> {
+    /// Categories sheet has a header row, which is skipped
+    fn categories(&self) -> Result<Vec<Category>, Error> {
         let hub = self.hub();
-        let range: GssRange = (self.categories_sheet_name.as_ref(), "A1:C").into();
+        let range: GssRange = (self.categories_sheet_name.as_ref(), "A2:F").into();
         let call = hub
             .spreadsheets()
             .values_get(&self.ss_id, range.url_encoded().as_ref());
-        if let Some(data) = call.doit().expect("Can not fetch categories").1.values {
-            data.iter()
-                .map(|c| {
-                    let name = c.get(1).expect("Missing name for category").to_owned();
-                    let priority = c
-                        .get(0)
-                        .expect("Missing priority for category")
-                        .parse()
-                        .expect("Priority must be a number");
-                    let lexemes = c.get(2).map(|s| s.to_owned()).unwrap_or(String::new());
-                    Category::new(name, priority, lexemes.as_str().into())
-                })
-                .collect()
-        } else {
-            vec![]
+        let (_, data) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Can not fetch categories: {}", err)))?;
+        let categories = data
+            .values
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|c| {
+                let category = category_from_row(c);
+                if category.is_none() {
+                    warn!("Skip invalid category row: {:?}", c);
+                }
+                category
+            })
+            .collect();
+        Ok(categories)
+    }
+}
+
+impl AccountProvider for GoogleDocsEventHandler {
+    /// Accounts sheet with a header row is optional, records have no accounts without it
+    fn accounts(&self) -> Result<Vec<Account>, Error> {
+        let hub = self.hub();
+        let range: GssRange = (self.accounts_sheet_name.as_ref(), "A2:B").into();
+        let call = hub
+            .spreadsheets()
+            .values_get(&self.ss_id, range.url_encoded().as_ref());
+        match call.doit() {
+            Ok((_, data)) => Ok(data
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|row| account_from_row(row))
+                .collect()),
+            Err(err) => {
+                warn!("Can not fetch accounts: {}", err);
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+impl LearnedWordsProvider for GoogleDocsEventHandler {
+    /// Learned words sheet with a header row is optional, nothing is learned without it
+    fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+        let hub = self.hub();
+        let range: GssRange = (self.learned_words_sheet_name.as_ref(), "A2:B").into();
+        let call = hub
+            .spreadsheets()
+            .values_get(&self.ss_id, range.url_encoded().as_ref());
+        match call.doit() {
+            Ok((_, data)) => Ok(data
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|row| learned_word_from_row(row))
+                .collect()),
+            Err(err) => {
+                warn!("Can not fetch learned words: {}", err);
+                Ok(vec![])
+            }
         }
     }
+
+    fn learn_word(&mut self, word: &LearnedWord) -> Result<(), Error> {
+        let data = ValueRange {
+            range: None,
+            values: Some(vec![vec![word.word.to_owned(), word.category.to_owned()]]),
+            major_dimension: None,
+        };
+        let range: GssRange = (self.learned_words_sheet_name.as_ref(), "A1").into();
+        let hub = self.hub();
+        let call = hub
+            .spreadsheets()
+            .values_append(data, &self.ss_id, range.url_encoded().as_ref())
+            .value_input_option("RAW")
+            .add_scope(SS_SCOPE);
+        call.doit()
+            .map(|_| ())
+            .map_err(|err| Error::Network(format!("Can not save learned word: {}", err)))
+    }
+}
+
+/// Parse row of word and category
+fn learned_word_from_row(row: &[String]) -> Option<LearnedWord> {
+    let word = row
+        .get(0)
+        .map(|word| word.trim())
+        .filter(|word| !word.is_empty())?;
+    let category = row
+        .get(1)
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())?;
+    Some(LearnedWord {
+        word: word.to_owned(),
+        category: category.to_owned(),
+    })
+}
+
+/// Parse row of name and lexemes
+fn account_from_row(row: &[String]) -> Option<Account> {
+    let name = row.get(0).filter(|name| !name.trim().is_empty())?;
+    let lexemes = row.get(1).filter(|lexemes| !lexemes.trim().is_empty())?;
+    Some(Account::new(name.to_owned(), lexemes.as_str().into()))
+}
+
+/// Spreadsheet id of the ledger in config like `family:<id>;chat-100500:<id>`
+fn ledger_spreadsheet(config: &str, ledger: &str) -> Option<String> {
+    config
+        .split(';')
+        .filter_map(|entry| entry.split_once(':'))
+        .find(|(name, _)| name.trim() == ledger)
+        .map(|(_, ss_id)| ss_id.trim().to_owned())
+}
+
+/// Parse row of priority, name, optional lexemes, optional kind and optional monthly limit
+fn category_from_row(row: &[String]) -> Option<Category> {
+    let priority = row.get(0)?.trim().parse().ok()?;
+    let name = row
+        .get(1)
+        .filter(|name| !name.trim().is_empty())?
+        .to_owned();
+    let lexemes = row.get(2).map(|s| s.as_str()).unwrap_or_default();
+    let kind = match row.get(3) {
+        Some(kind) => kind.parse().ok()?,
+        None => RecordKind::Expense,
+    };
+    let limit = match row.get(4).map(|limit| limit.trim()) {
+        Some(limit) if !limit.is_empty() => Some(limit.parse().ok()?),
+        _ => None,
+    };
+    Some(
+        Category::new(name, priority, lexemes.into())
+            .with_kind(kind)
+            .with_limit(limit)
+            .with_parent(row.get(5).cloned()),
+    )
 }
 
 impl EventHandler for GoogleDocsEventHandler {
-    fn handle_event(&mut self, event: HandlerEvent) -> Result<(), String> {
+    fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
         match event {
-            HandlerEvent::AddRecord(record) => {
+            HandlerEvent::AddRecord(mut record) => {
+                record.id = self.next_record_id(&record.source)?;
                 let sheet_id = record.date.get_sheet_id();
-                let sheet_name = self.get_or_create_sheet_by_date(&record.date);
-                self.add_record(&record, &sheet_name);
-                if record.date != Local::today().naive_local() {
-                    self.sort_sheets_data(&[sheet_id]);
+                let sheet_name = self.get_or_create_sheet_by_date(&record.date)?;
+                self.add_record(&record, &sheet_name)?;
+                if record.date != self.clock.today() {
+                    self.sort_sheets_data(&[sheet_id])?;
                 }
-                Ok(())
+                Ok(HandlerEvent::AddRecord(record))
             }
             HandlerEvent::UpdateRecord(record) => {
-                let new_sheet_name = self.get_or_create_sheet_by_date(&record.date);
-                if let Some(range) = self.find_record_range(&record, &new_sheet_name) {
+                let id = record.id.to_string();
+                let new_sheet_name = self.get_or_create_sheet_by_date(&record.date)?;
+                if let Some(range) = self.find_record_range(Column::Id, &id, &new_sheet_name)? {
                     // The same month as previous version has
                     debug!("Record #{} found in range {}", record.id, range);
-                    self.update_record(&record, &range);
-                    self.sort_sheets_data(&[record.date.get_sheet_id()]); // TODO: check previous date in table
-                    return Ok(());
+                    self.update_record(&record, &range)?;
+                    self.sort_sheets_data(&[record.date.get_sheet_id()])?; // TODO: check previous date in table
+                    return Ok(HandlerEvent::UpdateRecord(record));
                 } else {
                     debug!(
                         "Record #{} was not found on sheet {}",
@@ -236,34 +505,97 @@ impl EventHandler for GoogleDocsEventHandler {
                     );
                 }
 
-                let id = record.create_date.get_sheet_id();
-                let sheet_ids = last_sheet_ids(id, 12);
+                let created_sheet_id = record.create_date.get_sheet_id();
+                let sheet_names =
+                    self.get_existing_sheet_names(last_sheet_ids(created_sheet_id, 12))?;
+                debug!(
+                    "Search record #{} in next sheets: {:?}",
+                    record.id, sheet_names
+                );
                 if let Some(range) =
-                    self.get_existing_sheet_names(sheet_ids)
-                        .and_then(|sheet_names| {
-                            debug!(
-                                "Search record #{} in next sheets: {:?}",
-                                record.id, sheet_names
-                            );
-                            self.find_record_range_on_sheets(sheet_names, &record)
-                        })
+                    self.find_record_range_on_sheets(sheet_names, Column::Id, &id)?
                 {
                     // Different month from previous version
                     debug!("Record #{} found in range {}", record.id, range);
-                    self.add_record(&record, &new_sheet_name);
-                    self.clear_record(&record, &range);
+                    self.add_record(&record, &new_sheet_name)?;
+                    self.clear_record(record.id, &range)?;
                     self.sort_sheets_data(&[
                         record.date.get_sheet_id(),
                         record.create_date.get_sheet_id(),
-                    ]); // TODO: check previous date in table
-                    return Ok(());
+                    ])?; // TODO: check previous date in table
+                    Ok(HandlerEvent::UpdateRecord(record))
                 } else {
                     warn!("Record #{} is not found", record.id);
-                    Err(format!("Record #{} is not found", record.id))
+                    Err(Error::Storage(format!(
+                        "Record #{} is not found",
+                        record.id
+                    )))
                 }
             }
+            HandlerEvent::DeleteRecord(id) => {
+                // The record date is unknown, so recent sheets are searched
+                let sheet_ids = last_sheet_ids(self.clock.today().get_sheet_id(), 12);
+                let sheet_names = self.get_existing_sheet_names(sheet_ids)?;
+                match self.find_record_range_on_sheets(sheet_names, Column::Id, &id.to_string())? {
+                    Some(range) => {
+                        debug!("Record #{} found in range {}", id, range);
+                        self.clear_record(id, &range)?;
+                        Ok(HandlerEvent::DeleteRecord(id))
+                    }
+                    None => {
+                        warn!("Record #{} is not found", id);
+                        Err(Error::Storage(format!("Record #{} is not found", id)))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RecordsProvider for GoogleDocsEventHandler {
+    fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+        let sheet_names = self.get_existing_sheet_names(sheet_ids_between(&dates))?;
+        let mut records = self.read_records(sheet_names)?;
+        records.retain(|record| dates.contains(&record.date));
+        Ok(records)
+    }
+
+    fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+        // The record date is unknown, so recent sheets are searched
+        let sheet_ids = last_sheet_ids(self.clock.today().get_sheet_id(), 12);
+        let sheet_names = self.get_existing_sheet_names(sheet_ids)?;
+        if let Some(range) = self.find_record_range_on_sheets(
+            sheet_names.clone(),
+            Column::Source,
+            &source.to_string(),
+        )? {
+            return self.record_at(&range);
+        }
+        if source.part > 0 {
+            return Ok(None);
+        }
+        let legacy = self.find_record_range_on_sheets(
+            sheet_names,
+            Column::Id,
+            &source.message_id.to_string(),
+        )?;
+        match legacy {
+            Some(range) => Ok(self
+                .record_at(&range)?
+                .filter(|record| record.is_legacy_of(source))),
+            None => Ok(None),
         }
     }
+
+    fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error> {
+        // The record date is unknown, so recent sheets are searched
+        let sheet_ids = last_sheet_ids(self.clock.today().get_sheet_id(), 12);
+        let records = self.read_records(self.get_existing_sheet_names(sheet_ids)?)?;
+        Ok(records
+            .into_iter()
+            .filter(|record| record.user == user)
+            .max_by_key(|record| record.id))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -290,124 +622,157 @@ impl Display for GssRange {
 }
 
 impl GoogleDocsEventHandler {
-    fn get_or_create_sheet_by_date(&mut self, date: &NaiveDate) -> String {
+    fn get_or_create_sheet_by_date(&mut self, date: &NaiveDate) -> Result<String, Error> {
         let sheet_id = date.get_sheet_id();
-        let mut sheet_name = self.get_sheet_name(sheet_id);
-        if sheet_name.is_none() {
-            let name = date.format(&self.data_sheet_name_format).to_string();
-            self.add_sheet(sheet_id, &name);
-            sheet_name.replace(name);
+        match self.get_sheet_name(sheet_id)? {
+            Some(sheet_name) => {
+                if !self.checked_sheets.contains(&sheet_id) {
+                    self.upgrade_sheet(sheet_id, &sheet_name)?;
+                    self.checked_sheets.insert(sheet_id);
+                }
+                Ok(sheet_name)
+            }
+            None => {
+                let sheet_name = date.format(&self.data_sheet_name_format).to_string();
+                self.add_sheet(sheet_id, &sheet_name)?;
+                self.checked_sheets.insert(sheet_id);
+                Ok(sheet_name)
+            }
         }
-        sheet_name.unwrap()
     }
 
-    fn get_sheet_name(&mut self, sheet_id: i32) -> Option<String> {
-        self.list_sheets_names()
-            .and_then(|info| info.get(&sheet_id).cloned())
+    /// Sheets created by older versions have fewer columns and the pivot table right after
+    /// them, where new columns overwrite it. Their pivot table is moved to its current place,
+    /// the header and the layout of columns are updated.
+    fn upgrade_sheet(&mut self, sheet_id: i32, sheet_name: &str) -> Result<(), Error> {
+        let range: GssRange = (sheet_name, 1).into();
+        let hub = self.hub();
+        let call = hub
+            .spreadsheets()
+            .get(&self.ss_id)
+            .add_ranges(range.as_ref())
+            .param(
+                "fields",
+                "sheets(data(rowData(values(formattedValue,pivotTable))))",
+            )
+            .add_scope(SS_SCOPE);
+        let (_, spreadsheet) = call.doit().map_err(|err| {
+            Error::Network(format!("Can not fetch header of {}: {}", sheet_name, err))
+        })?;
+        let cells = spreadsheet
+            .sheets
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|sheet| sheet.data.unwrap_or_default())
+            .flat_map(|data| data.row_data.unwrap_or_default())
+            .next()
+            .and_then(|row| row.values)
+            .unwrap_or_default();
+        let header: Vec<_> = cells
+            .iter()
+            .map(|cell| cell.formatted_value.clone().unwrap_or_default())
+            .collect();
+        let pivot_columns: Vec<_> = cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| cell.pivot_table.is_some())
+            .map(|(column, _)| column as i32)
+            .collect();
+        if !is_sheet_outdated(&header, &pivot_columns) {
+            return Ok(());
+        }
+
+        warn!(
+            "Update layout of sheet {} created by older version",
+            sheet_name
+        );
+        let mut requests: Vec<_> = pivot_columns
+            .into_iter()
+            .filter(|&column| column != PIVOT_TABLE_COLUMN)
+            .map(|column| remove_pivot_table_request(sheet_id, column))
+            .collect();
+        requests.extend(layout_requests(sheet_id));
+        let call = hub.spreadsheets().batch_update(
+            BatchUpdateSpreadsheetRequest {
+                requests: Some(requests),
+                ..Default::default()
+            },
+            &self.ss_id,
+        );
+        call.doit().map_err(|err| {
+            Error::Network(format!(
+                "Error during update of sheet {}: {}",
+                sheet_name, err
+            ))
+        })?;
+        self.update_header(sheet_name)
+    }
+
+    fn get_sheet_name(&mut self, sheet_id: i32) -> Result<Option<String>, Error> {
+        Ok(self.list_sheets_names()?.remove(&sheet_id))
     }
 
-    fn get_existing_sheet_names(&mut self, sheet_ids: Vec<i32>) -> Option<Vec<String>> {
+    fn get_existing_sheet_names(&self, sheet_ids: Vec<i32>) -> Result<Vec<String>, Error> {
         let sheet_names = self.list_sheets_names()?;
-        Some(
-            sheet_names
-                .iter()
-                .filter(|(id, _)| sheet_ids.contains(id))
-                .map(|(_, name)| name.to_owned())
-                .collect(),
-        )
+        Ok(sheet_names
+            .into_iter()
+            .filter(|(id, _)| sheet_ids.contains(id))
+            .map(|(_, name)| name)
+            .collect())
     }
 
-    fn list_sheets_names(&mut self) -> Option<HashMap<i32, String>> {
+    /// Sheets without ids are skipped
+    fn list_sheets_names(&self) -> Result<HashMap<i32, String>, Error> {
         let hub = self.hub();
         let call = hub
             .spreadsheets()
             .get(&self.ss_id)
             .param("fields", "sheets.properties");
-        match call.doit() {
-            Ok(response) => {
-                let sheets = response.1.sheets?;
-                let ids = sheets
-                    .iter()
-                    .map(|s| {
-                        let props = s.properties.as_ref().unwrap();
-                        let sheet_id = props.sheet_id.unwrap();
-                        let sheet_title =
-                            props.title.clone().unwrap_or_else(|| sheet_id.to_string());
-                        (sheet_id, sheet_title)
-                    })
-                    .collect();
-                let result = HashMap::from(ids);
-                Some(result)
-            }
-            Err(..) => None,
-        }
+        let (_, spreadsheet) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Can not fetch list of sheets: {}", err)))?;
+        let names = spreadsheet
+            .sheets
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|sheet| {
+                let props = sheet.properties?;
+                let sheet_id = props.sheet_id?;
+                let sheet_title = props.title.unwrap_or_else(|| sheet_id.to_string());
+                Some((sheet_id, sheet_title))
+            })
+            .collect();
+        Ok(names)
     }
 
-    fn add_sheet(&mut self, sheet_id: i32, sheet_name: &str) {
+    fn add_sheet(&mut self, sheet_id: i32, sheet_name: &str) -> Result<(), Error> {
+        let mut requests = vec![
+            add_sheet_request(sheet_id, sheet_name),
+            hide_the_same_date_conditional_format_request(sheet_id),
+        ];
+        requests.extend(layout_requests(sheet_id));
         let hub = self.hub();
         let call = hub.spreadsheets().batch_update(
             BatchUpdateSpreadsheetRequest {
-                requests: Some(vec![
-                    add_sheet_request(sheet_id, sheet_name),
-                    hide_the_same_date_conditional_format_request(sheet_id),
-                    number_format_request(
-                        sheet_id,
-                        Column::Date as i32,
-                        NumberFormat {
-                            pattern: Some("dd, ddd".to_string()),
-                            type_: Some("DATE".to_string()),
-                        },
-                    ),
-                    number_format_request(
-                        sheet_id,
-                        Column::Amount as i32,
-                        NumberFormat {
-                            pattern: Some("#,##0.00".to_string()),
-                            type_: Some("NUMBER".to_string()),
-                        },
-                    ),
-                    number_format_request(
-                        sheet_id,
-                        Column::MessageId as i32,
-                        NumberFormat {
-                            pattern: None,
-                            type_: Some("TEXT".to_string()),
-                        },
-                    ),
-                    basic_filter_request(sheet_id, 0, Column::_Count as i32),
-                    add_pivot_table_request(sheet_id),
-                ]),
+                requests: Some(requests),
                 ..Default::default()
             },
             &self.ss_id,
         );
-        match call.doit() {
-            Err(err) => {
-                match err {
-                    Error::Failure(response) => {
-                        error!("GSS BadRequest: {:?}", response);
-                        // todo: check if sheet already exists
-                    }
-                    err => error!("Error during creation month sheet: {}", err),
-                }
+        call.doit().map_err(|err| match err {
+            // todo: check if sheet already exists
+            google_sheets4::Error::Failure(response) => {
+                Error::Network(format!("GSS BadRequest: {:?}", response))
             }
-            Ok(..) => {
-                debug!("New sheet created: {}", sheet_name);
-                self.update_header(sheet_name);
-            }
-        }
+            err => Error::Network(format!("Error during creation month sheet: {}", err)),
+        })?;
+        debug!("New sheet created: {}", sheet_name);
+        self.update_header(sheet_name)
     }
 
-    fn update_header(&mut self, sheet_name: &str) {
+    fn update_header(&mut self, sheet_name: &str) -> Result<(), Error> {
         let data = ValueRange {
-            values: Some(vec![vec![
-                "Date".to_string(),
-                "Amount".to_string(),
-                "Category".to_string(),
-                "Description".to_string(),
-                "User".to_string(),
-                "Message Id".to_string(),
-            ]]),
+            values: Some(vec![HEADER.iter().map(|title| title.to_string()).collect()]),
             ..Default::default()
         };
         let range: GssRange = (sheet_name, "A1").into();
@@ -416,12 +781,12 @@ impl GoogleDocsEventHandler {
             .spreadsheets()
             .values_update(data, &self.ss_id, &range.url_encoded())
             .value_input_option("RAW");
-        if let Err(err) = call.doit() {
-            error!("Error during update header: {:?}", err);
-        }
+        call.doit()
+            .map(|_| ())
+            .map_err(|err| Error::Network(format!("Error during update header: {}", err)))
     }
 
-    fn add_record(&mut self, record: &BudgetRecord, sheet_name: &str) {
+    fn add_record(&mut self, record: &BudgetRecord, sheet_name: &str) -> Result<(), Error> {
         let data = record.to_value_range(None, None);
         let range: GssRange = (sheet_name, "A1").into();
         let hub = self.hub();
@@ -430,12 +795,15 @@ impl GoogleDocsEventHandler {
             .values_append(data, &self.ss_id, range.url_encoded().as_ref())
             .value_input_option("USER_ENTERED")
             .add_scope(SS_SCOPE);
-        if let Err(err) = call.doit() {
-            error!("Error during adding record with id={}: {}", record.id, err);
-        }
+        call.doit().map(|_| ()).map_err(|err| {
+            Error::Network(format!(
+                "Error during adding record with id={}: {}",
+                record.id, err
+            ))
+        })
     }
 
-    fn update_record(&mut self, record: &BudgetRecord, range: &GssRange) {
+    fn update_record(&mut self, record: &BudgetRecord, range: &GssRange) -> Result<(), Error> {
         let data = record.to_value_range(Some(range), None);
         let hub = self.hub();
         let call = hub
@@ -443,15 +811,15 @@ impl GoogleDocsEventHandler {
             .values_update(data, &self.ss_id, range.url_encoded().as_ref())
             .value_input_option("USER_ENTERED")
             .add_scope(SS_SCOPE);
-        if let Err(err) = call.doit() {
-            error!(
+        call.doit().map(|_| ()).map_err(|err| {
+            Error::Network(format!(
                 "Error during updating record with id={}: {}",
                 record.id, err
-            );
-        }
+            ))
+        })
     }
 
-    fn clear_record(&mut self, record: &BudgetRecord, range: &GssRange) {
+    fn clear_record(&mut self, id: RecordId, range: &GssRange) -> Result<(), Error> {
         let hub = self.hub();
         let call = hub
             .spreadsheets()
@@ -461,42 +829,253 @@ impl GoogleDocsEventHandler {
                 range.url_encoded().as_ref(),
             )
             .add_scope(SS_SCOPE);
-        if let Err(err) = call.doit() {
-            error!(
+        call.doit().map(|_| ()).map_err(|err| {
+            Error::Network(format!(
                 "Error during clearing record with id={}: {}",
-                record.id, err
-            );
+                id, err
+            ))
+        })
+    }
+
+    /// Records on the sheets, rows of deleted records are skipped
+    fn read_records(&self, sheet_names: Vec<String>) -> Result<Vec<BudgetRecord>, Error> {
+        if sheet_names.is_empty() {
+            return Ok(vec![]);
+        }
+        let hub = self.hub();
+        let mut call = hub
+            .spreadsheets()
+            .values_batch_get(&self.ss_id)
+            .major_dimension("ROWS")
+            .value_render_option("UNFORMATTED_VALUE")
+            .date_time_render_option("SERIAL_NUMBER")
+            .add_scope(SS_SCOPE);
+        for sheet_name in sheet_names.iter() {
+            let range: GssRange = (sheet_name.as_str(), "A2:O").into();
+            call = call.add_ranges(range.as_ref());
         }
+        let (_, data) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Error during fetching records: {}", err)))?;
+        let records = data
+            .value_ranges
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|range| range.values.iter().flatten())
+            .filter_map(|row| {
+                let record = BudgetRecord::from_row(row, &self.ledger);
+                // Deleted records leave empty rows
+                if record.is_none() && !row.is_empty() {
+                    warn!("Skip invalid record row: {:?}", row);
+                }
+                record
+            })
+            .collect();
+        Ok(records)
     }
 
-    fn find_record_range(&mut self, record: &BudgetRecord, sheet_name: &str) -> Option<GssRange> {
-        let range = GssRange::from_sheet_and_col(sheet_name, Column::MessageId);
+    /// Record in the row of `range`
+    fn record_at(&self, range: &GssRange) -> Result<Option<BudgetRecord>, Error> {
         let hub = self.hub();
         let call = hub
             .spreadsheets()
             .values_get(&self.ss_id, range.as_ref())
+            .major_dimension("ROWS")
+            .value_render_option("UNFORMATTED_VALUE")
+            .date_time_render_option("SERIAL_NUMBER")
+            .add_scope(SS_SCOPE);
+        let (_, value_range) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Error during fetching record: {}", err)))?;
+        let record = value_range
+            .values
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| BudgetRecord::from_row(row, &self.ledger));
+        Ok(record)
+    }
+
+    /// Ids are reserved by appending a row to the ids sheet, concurrent appends never get
+    /// the same row, so neither do ids. They go on from the greatest id of the records
+    /// added before the sheet, which is kept in its first row.
+    fn next_record_id(&mut self, source: &SourceRef) -> Result<RecordId, Error> {
+        let offset = match self.ids_offset {
+            Some(offset) => offset,
+            None => {
+                let offset = self.read_ids_offset()?;
+                self.ids_offset = Some(offset);
+                offset
+            }
+        };
+        let data = ValueRange {
+            values: Some(vec![vec![source.to_string()]]),
+            ..Default::default()
+        };
+        let range: GssRange = (self.ids_sheet_name.as_ref(), "A2").into();
+        let hub = self.hub();
+        let call = hub
+            .spreadsheets()
+            .values_append(data, &self.ss_id, range.url_encoded().as_ref())
+            .value_input_option("RAW")
+            .insert_data_option("INSERT_ROWS")
+            .add_scope(SS_SCOPE);
+        let (_, response) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Error during reserving record id: {}", err)))?;
+        let row = response
+            .updates
+            .and_then(|updates| updates.updated_range)
+            .and_then(|range| range_start_row(&range))
+            .ok_or_else(|| Error::Network("No row of reserved record id".to_string()))?;
+        Ok(offset + RecordId::from(row) - 1)
+    }
+
+    /// The ids sheet is created on the first record, another invocation may create it
+    /// at the same time, then the offset written by that one is taken
+    fn read_ids_offset(&mut self) -> Result<RecordId, Error> {
+        let has_ids_sheet = self
+            .list_sheets_names()?
+            .values()
+            .any(|name| name == &self.ids_sheet_name);
+        if !has_ids_sheet {
+            let offset = self.last_record_id()?;
+            if let Err(err) = self.add_ids_sheet(offset) {
+                warn!("{}", err);
+            }
+        }
+        let range: GssRange = (self.ids_sheet_name.as_ref(), "A1").into();
+        let hub = self.hub();
+        let call = hub
+            .spreadsheets()
+            .values_get(&self.ss_id, range.url_encoded().as_ref())
+            .value_render_option("FORMATTED_VALUE")
+            .add_scope(SS_SCOPE);
+        let (_, data) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Error during fetching {}: {}", range, err)))?;
+        data.values
+            .unwrap_or_default()
+            .first()
+            .and_then(|row| row.first())
+            .and_then(|offset| offset.trim().parse().ok())
+            .ok_or_else(|| Error::Storage(format!("No last record id in {}", range)))
+    }
+
+    /// The sheet is added along with its first row, so it is never seen without the offset
+    fn add_ids_sheet(&mut self, offset: RecordId) -> Result<(), Error> {
+        let requests = vec![
+            add_sheet_request(IDS_SHEET_ID, &self.ids_sheet_name),
+            Request {
+                update_cells: Some(UpdateCellsRequest {
+                    start: Some(GridCoordinate {
+                        sheet_id: Some(IDS_SHEET_ID),
+                        column_index: Some(0),
+                        row_index: Some(0),
+                    }),
+                    fields: Some("userEnteredValue".to_string()),
+                    rows: Some(vec![RowData {
+                        values: Some(vec![CellData {
+                            user_entered_value: Some(ExtendedValue {
+                                string_value: Some(offset.to_string()),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }]),
+                    }]),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+        let hub = self.hub();
+        let call = hub.spreadsheets().batch_update(
+            BatchUpdateSpreadsheetRequest {
+                requests: Some(requests),
+                ..Default::default()
+            },
+            &self.ss_id,
+        );
+        call.doit().map(|_| ()).map_err(|err| {
+            Error::Network(format!(
+                "Error during creation of {} sheet: {}",
+                self.ids_sheet_name, err
+            ))
+        })
+    }
+
+    /// The greatest id on the month sheets
+    fn last_record_id(&self) -> Result<RecordId, Error> {
+        let sheet_names: Vec<_> = self
+            .list_sheets_names()?
+            .into_iter()
+            .filter(|(id, _)| is_month_sheet(*id))
+            .map(|(_, name)| name)
+            .collect();
+        if sheet_names.is_empty() {
+            return Ok(0);
+        }
+        let hub = self.hub();
+        let mut call = hub
+            .spreadsheets()
+            .values_batch_get(&self.ss_id)
             .major_dimension("COLUMNS")
             .value_render_option("FORMATTED_VALUE")
             .add_scope(SS_SCOPE);
-        let result = call.doit();
-        match result {
-            Ok((_, value_range)) => {
-                let id = record.id.to_string();
-                let row_index = value_range.values.and_then(|rows| {
-                    rows.first()
-                        .and_then(|cols| cols.iter().position(|v| *v == id))
-                });
-                row_index.map(|idx| (sheet_name, idx as i32 + 1).into())
-            }
-            Err(_) => None,
+        for sheet_name in sheet_names.iter() {
+            let range: GssRange = (sheet_name.as_str(), Column::Id).into();
+            call = call.add_ranges(range.as_ref());
         }
+        let (_, data) = call
+            .doit()
+            .map_err(|err| Error::Network(format!("Error during fetching record ids: {}", err)))?;
+        let last = data
+            .value_ranges
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|range| range.values.iter().flatten().flatten())
+            .filter_map(|id| id.parse::<RecordId>().ok())
+            .max();
+        Ok(last.unwrap_or(0))
     }
 
+    /// Row of the sheet with `value` in `column`
+    fn find_record_range(
+        &self,
+        column: Column,
+        value: &str,
+        sheet_name: &str,
+    ) -> Result<Option<GssRange>, Error> {
+        let range = GssRange::from_sheet_and_col(sheet_name, column);
+        let hub = self.hub();
+        let call = hub
+            .spreadsheets()
+            .values_get(&self.ss_id, range.as_ref())
+            .major_dimension("COLUMNS")
+            .value_render_option("FORMATTED_VALUE")
+            .add_scope(SS_SCOPE);
+        let (_, value_range) = call.doit().map_err(|err| {
+            Error::Network(format!(
+                "Error during searching records on {}: {}",
+                range, err
+            ))
+        })?;
+        let row_index = value_range.values.and_then(|rows| {
+            rows.first()
+                .and_then(|cols| cols.iter().position(|v| v == value))
+        });
+        Ok(row_index.map(|idx| (sheet_name, idx as i32 + 1).into()))
+    }
+
+    /// Row with `value` in `column` on the first of sheets having one
     fn find_record_range_on_sheets(
-        &mut self,
+        &self,
         sheet_names: Vec<String>,
-        record: &BudgetRecord,
-    ) -> Option<GssRange> {
+        column: Column,
+        value: &str,
+    ) -> Result<Option<GssRange>, Error> {
+        if sheet_names.is_empty() {
+            return Ok(None);
+        }
         let hub = self.hub();
         let mut call = hub
             .spreadsheets()
@@ -505,38 +1084,35 @@ impl GoogleDocsEventHandler {
             .value_render_option("FORMATTED_VALUE")
             .add_scope(SS_SCOPE);
         for sheet_name in sheet_names {
-            let range: GssRange = (sheet_name.as_str(), Column::MessageId).into();
+            let range: GssRange = (sheet_name.as_str(), column).into();
             call = call.add_ranges(range.as_ref());
         }
-        match call.doit() {
-            Ok((_, data)) => {
-                let id = record.id.to_string();
-                data.value_ranges.and_then(|ranges| {
-                    let result = ranges.iter().find_map(|range| {
-                        range
-                            .range
-                            .as_ref()
-                            .zip(range.values.as_ref().and_then(|rows| {
-                                rows.first()
-                                    .and_then(|cols| cols.iter().position(|v| *v == id))
-                            }))
-                    });
-                    result.and_then(|(range, index)| {
-                        range
-                            .split("!")
-                            .next()
-                            .map(|sheet_name| (sheet_name, index as i32 + 1).into())
-                    })
-                })
-            }
-            Err(_) => {
-                error!("Record #{} is not found", record.id);
-                None
-            }
-        }
+        let (_, data) = call.doit().map_err(|err| {
+            Error::Network(format!(
+                "Error during searching record with {}: {}",
+                value, err
+            ))
+        })?;
+        Ok(data.value_ranges.and_then(|ranges| {
+            let result = ranges.iter().find_map(|range| {
+                range
+                    .range
+                    .as_ref()
+                    .zip(range.values.as_ref().and_then(|rows| {
+                        rows.first()
+                            .and_then(|cols| cols.iter().position(|v| v == value))
+                    }))
+            });
+            result.and_then(|(range, index)| {
+                range
+                    .split("!")
+                    .next()
+                    .map(|sheet_name| (sheet_name, index as i32 + 1).into())
+            })
+        }))
     }
 
-    fn sort_sheets_data(&mut self, sheet_ids: &[i32]) {
+    fn sort_sheets_data(&mut self, sheet_ids: &[i32]) -> Result<(), Error> {
         let filter_requests: Vec<Request> = sheet_ids
             .iter()
             .map(|&sheet_id| basic_filter_request(sheet_id, 0, Column::_Count as i32))
@@ -549,15 +1125,72 @@ impl GoogleDocsEventHandler {
             },
             &self.ss_id,
         );
-        if let Err(err) = call.doit() {
-            error!(
+        call.doit().map(|_| ()).map_err(|err| {
+            Error::Network(format!(
                 "Error during setting filter for sheets with ids {:?}: {}",
                 sheet_ids, err
-            );
-        }
+            ))
+        })
     }
 }
 
+/// Sheet is created by an older version if its header differs
+/// or its pivot table is not at `PIVOT_TABLE_COLUMN`
+fn is_sheet_outdated(header: &[String], pivot_columns: &[i32]) -> bool {
+    let is_header_current =
+        header.len() >= HEADER.len() && header.iter().zip(HEADER).all(|(a, b)| a.as_str() == *b);
+    !is_header_current || pivot_columns != [PIVOT_TABLE_COLUMN]
+}
+
+/// Formats of the record columns, their filter and the pivot table over them,
+/// requests are repeated safely when the sheet layout is updated
+fn layout_requests(sheet_id: i32) -> Vec<Request> {
+    vec![
+        number_format_request(
+            sheet_id,
+            Column::Date as i32,
+            NumberFormat {
+                pattern: Some("dd, ddd".to_string()),
+                type_: Some("DATE".to_string()),
+            },
+        ),
+        number_format_request(
+            sheet_id,
+            Column::Amount as i32,
+            NumberFormat {
+                pattern: Some("#,##0.00".to_string()),
+                type_: Some("NUMBER".to_string()),
+            },
+        ),
+        number_format_request(
+            sheet_id,
+            Column::BaseAmount as i32,
+            NumberFormat {
+                pattern: Some("#,##0.00".to_string()),
+                type_: Some("NUMBER".to_string()),
+            },
+        ),
+        number_format_request(
+            sheet_id,
+            Column::Id as i32,
+            NumberFormat {
+                pattern: None,
+                type_: Some("TEXT".to_string()),
+            },
+        ),
+        number_format_request(
+            sheet_id,
+            Column::Source as i32,
+            NumberFormat {
+                pattern: None,
+                type_: Some("TEXT".to_string()),
+            },
+        ),
+        basic_filter_request(sheet_id, 0, Column::_Count as i32),
+        add_pivot_table_request(sheet_id),
+    ]
+}
+
 #[inline]
 fn add_sheet_request(sheet_id: i32, sheet_name: &str) -> Request {
     Request {
@@ -677,7 +1310,7 @@ fn add_pivot_table_request(sheet_id: i32) -> Request {
         update_cells: Some(UpdateCellsRequest {
             start: Some(GridCoordinate {
                 sheet_id: Some(sheet_id),
-                column_index: Some(Column::_PivotTable as i32),
+                column_index: Some(PIVOT_TABLE_COLUMN),
                 row_index: Some(0),
             }),
             fields: Some("pivotTable".to_string()),
@@ -696,12 +1329,35 @@ fn add_pivot_table_request(sheet_id: i32) -> Request {
                             source_column_offset: Some(Column::Amount as i32),
                             ..Default::default()
                         }]),
-                        rows: Some(vec![PivotGroup {
-                            source_column_offset: Some(Column::Category as i32),
-                            show_totals: Some(true),
-                            sort_order: Some(SortOrder::Ascending.to_string()),
-                            ..Default::default()
-                        }]),
+                        // Income and expenses as well as amounts in different currencies
+                        // are summed up separately. Subcategories are summed up under
+                        // top categories, full paths keep them next to their parents.
+                        rows: Some(vec![
+                            PivotGroup {
+                                source_column_offset: Some(Column::Kind as i32),
+                                show_totals: Some(true),
+                                sort_order: Some(SortOrder::Ascending.to_string()),
+                                ..Default::default()
+                            },
+                            PivotGroup {
+                                source_column_offset: Some(Column::Currency as i32),
+                                show_totals: Some(true),
+                                sort_order: Some(SortOrder::Ascending.to_string()),
+                                ..Default::default()
+                            },
+                            PivotGroup {
+                                source_column_offset: Some(Column::TopCategory as i32),
+                                show_totals: Some(true),
+                                sort_order: Some(SortOrder::Ascending.to_string()),
+                                ..Default::default()
+                            },
+                            PivotGroup {
+                                source_column_offset: Some(Column::Category as i32),
+                                show_totals: Some(true),
+                                sort_order: Some(SortOrder::Ascending.to_string()),
+                                ..Default::default()
+                            },
+                        ]),
                         columns: Some(vec![PivotGroup {
                             source_column_offset: Some(Column::User as i32),
                             show_totals: Some(true),
@@ -719,6 +1375,61 @@ fn add_pivot_table_request(sheet_id: i32) -> Request {
     }
 }
 
+/// Category at the top of the path like `Food` of `Food/Groceries/Dairy`
+fn top_category(path: &str) -> &str {
+    path.split(PATH_SEPARATOR).next().unwrap_or(path)
+}
+
+/// Removes the pivot table anchored at the first row of the column along with its values
+#[inline]
+fn remove_pivot_table_request(sheet_id: i32, column: i32) -> Request {
+    Request {
+        update_cells: Some(UpdateCellsRequest {
+            start: Some(GridCoordinate {
+                sheet_id: Some(sheet_id),
+                column_index: Some(column),
+                row_index: Some(0),
+            }),
+            fields: Some("pivotTable".to_string()),
+            rows: Some(vec![RowData {
+                values: Some(vec![CellData::default()]),
+            }]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Ids of monthly sheets which may contain records with dates within `dates`
+/// Sheets of months have ids like 202103
+fn is_month_sheet(sheet_id: i32) -> bool {
+    sheet_id >= 100 && (1..=12).contains(&(sheet_id % 100))
+}
+
+fn sheet_ids_between(dates: &Range<NaiveDate>) -> Vec<i32> {
+    let mut ids = vec![];
+    let mut date = NaiveDate::from_ymd(dates.start.year(), dates.start.month(), 1);
+    while date < dates.end {
+        ids.push(date.get_sheet_id());
+        date = if date.month() == 12 {
+            NaiveDate::from_ymd(date.year() + 1, 1, 1)
+        } else {
+            NaiveDate::from_ymd(date.year(), date.month() + 1, 1)
+        };
+    }
+    ids
+}
+
+/// The first row of a range like `'Record ids'!A7:A7`
+fn range_start_row(range: &str) -> Option<i32> {
+    let (_, cells) = range.rsplit_once('!')?;
+    let start = cells.split(':').next()?;
+    start
+        .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+        .parse()
+        .ok()
+}
+
 fn last_sheet_ids(id: i32, count: usize) -> Vec<i32> {
     (0..count - 1).fold(vec![id], |mut v, _| {
         let id = v.last().unwrap();
@@ -730,7 +1441,15 @@ fn last_sheet_ids(id: i32, count: usize) -> Vec<i32> {
 
 #[cfg(test)]
 mod tests {
-    use crate::handler::events::google_docs::last_sheet_ids;
+    use chrono::NaiveDate;
+
+    use crate::handler::categorizer::LearnedWord;
+    use crate::handler::events::google_docs::{
+        account_from_row, category_from_row, is_month_sheet, is_sheet_outdated, last_sheet_ids,
+        learned_word_from_row, ledger_spreadsheet, range_start_row, sheet_ids_between,
+        top_category, HEADER, PIVOT_TABLE_COLUMN,
+    };
+    use crate::handler::events::RecordKind;
 
     #[test]
     fn last_4_sheet_ids() {
@@ -739,4 +1458,135 @@ mod tests {
             vec![202102, 202101, 202012, 202011]
         )
     }
+
+    #[test]
+    fn start_row_of_range() {
+        assert_eq!(range_start_row("'Record ids'!A7:A7"), Some(7));
+        assert_eq!(range_start_row("Ids!A12"), Some(12));
+        assert_eq!(range_start_row("Ids!A:A"), None);
+    }
+
+    #[test]
+    fn month_sheets() {
+        assert!(is_month_sheet(202103));
+        assert!(is_month_sheet(202112));
+        assert!(!is_month_sheet(0));
+        assert!(!is_month_sheet(202113));
+        assert!(!is_month_sheet(202100));
+    }
+
+    #[test]
+    fn sheet_ids_between_dates() {
+        let dates = NaiveDate::from_ymd(2020, 12, 14)..NaiveDate::from_ymd(2021, 2, 1);
+        assert_eq!(sheet_ids_between(&dates), vec![202012, 202101])
+    }
+
+    #[test]
+    fn skip_invalid_category_rows() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert!(category_from_row(&row(&["1", "Fruits", "banana"])).is_some());
+        assert!(category_from_row(&row(&["2", "Other"])).is_some());
+        assert!(category_from_row(&row(&["Priority", "Name"])).is_none());
+        assert!(category_from_row(&row(&["3"])).is_none());
+        assert!(category_from_row(&row(&["4", "Salary", "salary", "refund"])).is_none());
+    }
+
+    #[test]
+    fn category_kind_from_row() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let kind = |cells: &[&str]| category_from_row(&row(cells)).map(|c| c.kind);
+        assert_eq!(kind(&["1", "Fruits", "banana"]), Some(RecordKind::Expense));
+        assert_eq!(
+            kind(&["1", "Fruits", "banana", ""]),
+            Some(RecordKind::Expense)
+        );
+        assert_eq!(
+            kind(&["2", "Salary", "", "income"]),
+            Some(RecordKind::Income)
+        );
+    }
+
+    #[test]
+    fn category_limit_from_row() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let limit = |cells: &[&str]| category_from_row(&row(cells)).map(|c| c.limit);
+        assert_eq!(limit(&["1", "Fruits", "banana"]), Some(None));
+        assert_eq!(limit(&["1", "Fruits", "banana", "", " "]), Some(None));
+        assert_eq!(
+            limit(&["1", "Fruits", "banana", "", "400"]),
+            Some("400".parse().ok())
+        );
+        assert_eq!(limit(&["1", "Fruits", "banana", "", "a lot"]), None);
+    }
+
+    #[test]
+    fn outdated_sheets() {
+        let header = |titles: &[&str]| titles.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let current = header(HEADER);
+        assert!(!is_sheet_outdated(&current, &[PIVOT_TABLE_COLUMN]));
+        // Pivot table values follow the header in the first row
+        let mut with_pivot_table = current.clone();
+        with_pivot_table.extend(header(&["", "SUM of Amount"]));
+        assert!(!is_sheet_outdated(&with_pivot_table, &[PIVOT_TABLE_COLUMN]));
+        // Sheets created before currencies have the pivot table at column H
+        let first = header(&["Date", "Amount", "Category", "Description", "User", "Id"]);
+        assert!(is_sheet_outdated(&first, &[7]));
+        assert!(is_sheet_outdated(&current, &[15]));
+        assert!(is_sheet_outdated(&current[..13], &[PIVOT_TABLE_COLUMN]));
+        assert!(is_sheet_outdated(&current, &[]));
+    }
+
+    #[test]
+    fn top_category_of_path() {
+        assert_eq!(top_category("Food/Groceries/Dairy"), "Food");
+        assert_eq!(top_category("Rent"), "Rent");
+    }
+
+    #[test]
+    fn category_parent_from_row() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let parent = |cells: &[&str]| category_from_row(&row(cells)).map(|c| c.parent);
+        assert_eq!(parent(&["1", "Fruits", "banana"]), Some(None));
+        assert_eq!(parent(&["1", "Fruits", "banana", "", "", " "]), Some(None));
+        assert_eq!(
+            parent(&["1", "Fruits", "banana", "", "", "Food"]),
+            Some(Some("Food".to_string()))
+        );
+    }
+
+    #[test]
+    fn skip_invalid_learned_word_rows() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            learned_word_from_row(&row(&["ashan ", "Fruits"])),
+            Some(LearnedWord {
+                word: "ashan".to_string(),
+                category: "Fruits".to_string()
+            })
+        );
+        assert_eq!(learned_word_from_row(&row(&["ashan"])), None);
+        assert_eq!(learned_word_from_row(&row(&["", "Fruits"])), None);
+    }
+
+    #[test]
+    fn skip_invalid_account_rows() {
+        let row = |cells: &[&str]| cells.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert!(account_from_row(&row(&["Visa", "visa,card"])).is_some());
+        assert!(account_from_row(&row(&["Cash"])).is_none());
+        assert!(account_from_row(&row(&["", "cash"])).is_none());
+    }
+
+    #[test]
+    fn spreadsheet_of_ledger() {
+        let config = "family: abc; chat-100500:def";
+        assert_eq!(
+            ledger_spreadsheet(config, "family"),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            ledger_spreadsheet(config, "chat-100500"),
+            Some("def".to_string())
+        );
+        assert_eq!(ledger_spreadsheet(config, "chat42"), None);
+    }
 }
@@ -0,0 +1,262 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use log::{debug, info};
+
+use crate::error::Error;
+use crate::handler::events::{Storage, StorageKind};
+use crate::handler::ChatId;
+
+const LINKS_FILE_ENV: &str = "BUDGET_LEDGERS_FILE";
+const DEFAULT_LINKS_FILE: &str = "ledgers.json";
+const ADMINS_ENV: &str = "BUDGET_ADMINS";
+
+/// Ledger of messages without a chat, e.g. from CLI, it keeps the original storage files
+pub const DEFAULT_LEDGER: &str = "default";
+
+type StorageFactory = Box<dyn Fn(&str) -> Result<Box<dyn Storage>, Error> + Send + Sync>;
+
+/// Routes chats to their ledgers, each ledger has its own storage.
+///
+/// Every chat has a ledger of its own unless it is linked to another one,
+/// links are kept in `BUDGET_LEDGERS_FILE` (ledgers.json) like `{"-100500": "family"}`.
+/// Only users listed in `BUDGET_ADMINS` like `alice,bob` can link chats to other ledgers,
+/// since a linked chat reads and deletes records of the ledger.
+pub struct Ledgers {
+    factory: StorageFactory,
+    storages: HashMap<String, Box<dyn Storage>>,
+    links: BTreeMap<ChatId, String>,
+    links_file: Option<PathBuf>,
+    admins: Vec<String>,
+}
+
+impl Ledgers {
+    /// Ledgers with storages made by `factory` and links kept in memory only
+    pub fn new<F>(factory: F) -> Self
+    where
+        F: Fn(&str) -> Result<Box<dyn Storage>, Error> + Send + Sync + 'static,
+    {
+        Ledgers {
+            factory: Box::new(factory),
+            storages: HashMap::new(),
+            links: BTreeMap::new(),
+            links_file: None,
+            admins: vec![],
+        }
+    }
+
+    /// Users allowed to link chats, separated with commas
+    pub fn with_admins(self, admins: &str) -> Self {
+        Ledgers {
+            admins: admins
+                .split(',')
+                .map(|admin| admin.trim().trim_start_matches('@'))
+                .filter(|admin| !admin.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            ..self
+        }
+    }
+
+    pub fn from_env(kind: StorageKind) -> Result<Self, Error> {
+        let path =
+            PathBuf::from(env::var(LINKS_FILE_ENV).unwrap_or_else(|_| DEFAULT_LINKS_FILE.into()));
+        let mut ledgers = Ledgers::new(move |ledger| kind.create(ledger))
+            .with_admins(&env::var(ADMINS_ENV).unwrap_or_default());
+        if path.exists() {
+            let text = fs::read_to_string(&path)
+                .map_err(|err| Error::Config(format!("Can't read {}: {}", path.display(), err)))?;
+            ledgers.links = serde_json::from_str(&text)
+                .map_err(|err| Error::Parse(format!("Invalid {}: {}", path.display(), err)))?;
+            info!("{} chats are linked to ledgers", ledgers.links.len());
+        }
+        ledgers.links_file = Some(path);
+        Ok(ledgers)
+    }
+
+    /// Ledger the chat is linked to or its own one
+    pub fn ledger_of(&self, chat: Option<ChatId>) -> String {
+        match chat {
+            Some(chat) => self
+                .links
+                .get(&chat)
+                .cloned()
+                .unwrap_or_else(|| format!("chat{}", chat)),
+            None => DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    /// Storage of the ledger, it is opened on the first use
+    pub fn storage(&mut self, ledger: &str) -> Result<&mut dyn Storage, Error> {
+        if !self.storages.contains_key(ledger) {
+            validate_name(ledger)?;
+            debug!("Open storage of ledger '{}'", ledger);
+            let storage = (self.factory)(ledger)?;
+            self.storages.insert(ledger.to_owned(), storage);
+        }
+        let storage = self.storages.get_mut(ledger).expect("storage is opened");
+        Ok(storage.as_mut())
+    }
+
+    /// Make the chat use `ledger` from now on, records already in the chat ledger stay there.
+    /// Any user can return the chat to its own ledger, other ones are linked by admins only.
+    pub fn link(&mut self, chat: ChatId, user: &str, ledger: &str) -> Result<(), Error> {
+        validate_name(ledger)?;
+        if ledger == format!("chat{}", chat) {
+            self.links.remove(&chat);
+        } else if self.admins.iter().any(|admin| admin == user) {
+            self.links.insert(chat, ledger.to_owned());
+        } else {
+            return Err(Error::Config(format!(
+                "Only users listed in {} can link chats to other ledgers",
+                ADMINS_ENV
+            )));
+        }
+        if let Some(path) = &self.links_file {
+            let text = serde_json::to_string_pretty(&self.links)
+                .map_err(|err| Error::Parse(format!("Can't serialize links: {}", err)))?;
+            fs::write(path, text).map_err(|err| {
+                Error::Storage(format!("Can't save links to {}: {}", path.display(), err))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Ledger names become parts of file names, so only safe characters are allowed
+fn validate_name(ledger: &str) -> Result<(), Error> {
+    let valid = !ledger.is_empty()
+        && ledger
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Parse(format!(
+            "Invalid ledger name '{}', use letters, digits, '-' and '_'",
+            ledger
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use chrono::NaiveDate;
+
+    use crate::handler::accounts::{Account, AccountProvider};
+    use crate::handler::categorizer::{
+        Category, CategoryProvider, LearnedWord, LearnedWordsProvider,
+    };
+    use crate::handler::events::{
+        BudgetRecord, EventHandler, HandlerEvent, RecordsProvider, SourceRef,
+    };
+
+    use super::*;
+
+    struct NoStorage;
+
+    impl EventHandler for NoStorage {
+        fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
+            Ok(event)
+        }
+    }
+
+    impl RecordsProvider for NoStorage {
+        fn records(&self, _dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+            Ok(vec![])
+        }
+
+        fn record_by_source(&self, _source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+            Ok(None)
+        }
+
+        fn last_record_of(&self, _user: &str) -> Result<Option<BudgetRecord>, Error> {
+            Ok(None)
+        }
+    }
+
+    impl CategoryProvider for NoStorage {
+        fn categories(&self) -> Result<Vec<Category>, Error> {
+            Ok(vec![])
+        }
+    }
+
+    impl AccountProvider for NoStorage {
+        fn accounts(&self) -> Result<Vec<Account>, Error> {
+            Ok(vec![])
+        }
+    }
+
+    impl LearnedWordsProvider for NoStorage {
+        fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+            Ok(vec![])
+        }
+
+        fn learn_word(&mut self, _word: &LearnedWord) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chat_ledgers_and_links() {
+        let mut ledgers = Ledgers::new(|_| Ok(Box::new(NoStorage))).with_admins("alice, @bob");
+        assert_eq!(ledgers.ledger_of(None), DEFAULT_LEDGER);
+        assert_eq!(ledgers.ledger_of(Some(-100500)), "chat-100500");
+
+        ledgers.link(-100500, "alice", "family").unwrap();
+        ledgers.link(42, "bob", "chat-100500").unwrap();
+        assert_eq!(ledgers.ledger_of(Some(-100500)), "family");
+        assert_eq!(ledgers.ledger_of(Some(42)), "chat-100500");
+
+        ledgers.link(-100500, "carol", "chat-100500").unwrap();
+        assert_eq!(ledgers.ledger_of(Some(-100500)), "chat-100500");
+    }
+
+    #[test]
+    fn only_admins_link_to_other_ledgers() {
+        let mut ledgers = Ledgers::new(|_| Ok(Box::new(NoStorage))).with_admins("alice");
+        for ledger in &[DEFAULT_LEDGER, "chat42", "family"] {
+            assert!(matches!(
+                ledgers.link(-100500, "mallory", ledger),
+                Err(Error::Config(_))
+            ));
+        }
+        assert!(matches!(
+            ledgers.link(-100500, "", "family"),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(ledgers.ledger_of(Some(-100500)), "chat-100500");
+    }
+
+    #[test]
+    fn invalid_ledger_name() {
+        let mut ledgers = Ledgers::new(|_| Ok(Box::new(NoStorage)));
+        assert!(matches!(
+            ledgers.link(1, "alice", "../records"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(ledgers.link(1, "alice", ""), Err(Error::Parse(_))));
+        assert!(ledgers.storage("../records").is_err());
+        assert_eq!(ledgers.ledger_of(Some(1)), "chat1");
+    }
+
+    #[test]
+    fn storage_is_opened_once_per_ledger() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        let mut ledgers = Ledgers::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(NoStorage))
+        });
+        ledgers.storage("family").unwrap();
+        ledgers.storage("family").unwrap();
+        ledgers.storage(DEFAULT_LEDGER).unwrap();
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,43 +1,119 @@
+use std::env;
 use std::fmt;
+use std::ops::{Neg, Range};
 use std::str::FromStr;
 
 use chrono::NaiveDate;
+use log::warn;
 use regex::Regex;
+use rust_decimal::Decimal;
 
+use crate::error::Error;
+use crate::handler::accounts::AccountProvider;
+use crate::handler::categorizer::{CategoryProvider, LearnedWordsProvider};
 #[cfg(feature = "csv-storage")]
 use crate::handler::events::csv::CsvEventHandler;
 #[cfg(feature = "gss-storage")]
 use crate::handler::events::google_docs::GoogleDocsEventHandler;
+#[cfg(feature = "sqlite-storage")]
+use crate::handler::events::sqlite::SqliteEventHandler;
+use crate::handler::ChatId;
 use std::num::ParseIntError;
 
+pub use self::currency::{Currency, UserCurrencies};
+pub use self::ledger::{Ledgers, DEFAULT_LEDGER};
+
 #[cfg(feature = "csv-storage")]
 mod csv;
+mod currency;
 #[cfg(feature = "gss-storage")]
 mod google_docs;
-
-#[cfg(feature = "csv-storage")]
-pub type DefaultEventHandler = CsvEventHandler;
-#[cfg(feature = "gss-storage")]
-pub type DefaultEventHandler = GoogleDocsEventHandler;
+mod ledger;
+#[cfg(feature = "sqlite-storage")]
+mod sqlite;
 
 pub(crate) type RecordId = i64;
 
+const STORAGE_ENV: &str = "BUDGET_STORAGE";
+
 lazy_static! {
     static ref RE_AMOUNT: Regex = Regex::new(r"^-?\d+(?:[.,]\d{1,2})?$").unwrap();
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
-pub struct Amount(pub(crate) String);
+/// Exact decimal amount of money
+///
+/// It is (de)serialized as a plain decimal string like `9.75`,
+/// so records stored before stay readable.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Amount(#[serde(with = "serde_with::rust::display_fromstr")] Decimal);
 
 impl Amount {
+    pub fn zero() -> Self {
+        Amount(Decimal::ZERO)
+    }
+
     pub fn as_i32(&self) -> Result<i32, ParseIntError> {
-        self.0.parse::<i32>()
+        self.0.to_string().parse::<i32>()
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Sum which stops at the biggest amount instead of overflowing,
+    /// amounts of up to 28 digits are valid, but a total of them may not fit
+    pub fn saturating_add(self, other: Amount) -> Amount {
+        self.checked_add(other).unwrap_or_else(|| {
+            warn!("Amount overflow on adding {} to {}", other, self);
+            if other.0.is_sign_negative() {
+                Amount(Decimal::MIN)
+            } else {
+                Amount(Decimal::MAX)
+            }
+        })
+    }
+
+    pub fn saturating_sub(self, other: Amount) -> Amount {
+        self.saturating_add(-other)
+    }
+
+    /// Share of `whole` in whole percents, there is none of zero
+    pub fn percent_of(self, whole: Amount) -> Option<Decimal> {
+        self.0
+            .checked_mul(Decimal::ONE_HUNDRED)?
+            .checked_div(whole.0)
+            .map(|percent| percent.round())
+    }
+
+    /// Multiply by `rate` rounding to cents
+    pub fn scaled(self, rate: Decimal) -> Option<Amount> {
+        self.0.checked_mul(rate).map(|x| Amount(x.round_dp(2)))
+    }
+
+    /// Display the amount with decimal separator used in `locale`
+    pub fn localized(&self, locale: Locale) -> LocalizedAmount<'_> {
+        LocalizedAmount {
+            amount: self,
+            locale,
+        }
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
     }
 }
 
 impl fmt::Display for Amount {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        fmt::Display::fmt(&self.0, f)
     }
 }
 
@@ -46,41 +122,443 @@ impl FromStr for Amount {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if RE_AMOUNT.is_match(s) {
-            Ok(Amount(s.replace(',', ".")))
+            Decimal::from_str(&s.replace(',', "."))
+                .map(Amount)
+                .map_err(|_| ())
         } else {
             Err(())
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Locale {
+    En,
+    Ru,
+}
+
+impl Locale {
+    fn decimal_separator(self) -> char {
+        match self {
+            Locale::En => '.',
+            Locale::Ru => ',',
+        }
+    }
+}
+
+pub struct LocalizedAmount<'a> {
+    amount: &'a Amount,
+    locale: Locale,
+}
+
+impl fmt::Display for LocalizedAmount<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match f.precision() {
+            Some(precision) => format!("{:.*}", precision, self.amount.0),
+            None => self.amount.0.to_string(),
+        };
+        f.write_str(&text.replace('.', &self.locale.decimal_separator().to_string()))
+    }
+}
+
+/// Message a record is made from, edits of the message update the record.
+///
+/// It is kept as text like `telegram:-100500:42`, the chat is empty for readers without chats.
+/// Expenses after the first one in the same message are suffixed like `telegram:-100500:42/1`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceRef {
+    pub source: String,
+    pub chat_id: Option<ChatId>,
+    pub message_id: i64,
+    /// Position of the expense in the message
+    pub part: usize,
+}
+
+impl fmt::Display for SourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.source)?;
+        if let Some(chat_id) = self.chat_id {
+            write!(f, "{}", chat_id)?;
+        }
+        write!(f, ":{}", self.message_id)?;
+        if self.part > 0 {
+            write!(f, "/{}", self.part)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SourceRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Records saved before sources were introduced have none
+        if s.is_empty() {
+            return Ok(SourceRef::default());
+        }
+        let invalid = || format!("Invalid message reference '{}'", s);
+        let mut parts = s.rsplitn(3, ':');
+        let last = parts.next().unwrap_or_default();
+        let (message_id, part) = match last.split_once('/') {
+            Some((id, part)) => (id.parse().ok(), part.parse().ok()),
+            None => (last.parse().ok(), Some(0)),
+        };
+        let chat_id = match parts.next() {
+            Some("") => Some(None),
+            Some(id) => id.parse().ok().map(Some),
+            None => None,
+        };
+        match (parts.next(), chat_id, message_id, part) {
+            (Some(source), Some(chat_id), Some(message_id), Some(part)) => Ok(SourceRef {
+                source: source.to_owned(),
+                chat_id,
+                message_id,
+                part,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Direction of money a record stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordKind {
+    Expense,
+    Income,
+    /// Money moved between own accounts, it is neither spent nor earned
+    Transfer,
+}
+
+/// Records stored before kinds were introduced are expenses
+impl Default for RecordKind {
+    fn default() -> Self {
+        RecordKind::Expense
+    }
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RecordKind::Expense => "expense",
+            RecordKind::Income => "income",
+            RecordKind::Transfer => "transfer",
+        })
+    }
+}
+
+impl FromStr for RecordKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "" | "expense" => Ok(RecordKind::Expense),
+            "income" => Ok(RecordKind::Income),
+            "transfer" => Ok(RecordKind::Transfer),
+            _ => Err(format!("Unknown record kind '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BudgetRecord {
+    /// Storage key assigned when the record is added, it doesn't change on updates
     pub id: RecordId,
+    #[serde(default, with = "serde_with::rust::display_fromstr")]
+    pub source: SourceRef,
     pub date: NaiveDate,
     pub category: String,
     pub amount: Amount,
+    #[serde(default, with = "serde_with::rust::display_fromstr")]
+    pub kind: RecordKind,
+    #[serde(default)]
+    pub currency: Currency,
     pub desc: String,
     pub user: String,
+    /// Card, cash or another account the money is paid from or received to
+    #[serde(default)]
+    pub account: Option<String>,
+    /// Account the money of a transfer goes to from `account`
+    #[serde(default)]
+    pub to_account: Option<String>,
+    /// Lowercase labels grouping records across categories, like `vacation`
+    #[serde(
+        default,
+        with = "serde_with::rust::StringWithSeparator::<serde_with::SpaceSeparator>"
+    )]
+    pub tags: Vec<String>,
     pub create_date: NaiveDate,
+    /// `amount` converted to the base currency, if conversion is configured
+    #[serde(default)]
+    pub base_amount: Option<Amount>,
+    #[serde(default)]
+    pub base_currency: Option<Currency>,
+    #[serde(default = "default_ledger")]
+    pub ledger: String,
+}
+
+impl BudgetRecord {
+    /// Amount to sum up with others, the converted one when available
+    pub fn total_amount(&self) -> (Amount, Currency) {
+        match (self.base_amount, self.base_currency) {
+            (Some(amount), Some(currency)) => (amount, currency),
+            _ => (self.amount, self.currency),
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.to_lowercase();
+        self.tags.contains(&tag)
+    }
+
+    /// Records saved before sources were kept have none, their id is the message id
+    pub(crate) fn is_legacy_of(&self, source: &SourceRef) -> bool {
+        self.source == SourceRef::default() && source.part == 0 && self.id == source.message_id
+    }
+}
+
+/// Record made from the message, a legacy one is taken only if there is no other
+pub(crate) fn find_by_source(
+    mut records: Vec<BudgetRecord>,
+    source: &SourceRef,
+) -> Option<BudgetRecord> {
+    let index = records
+        .iter()
+        .position(|record| record.source == *source)
+        .or_else(|| {
+            records
+                .iter()
+                .position(|record| record.is_legacy_of(source))
+        });
+    index.map(|index| records.swap_remove(index))
+}
+
+fn default_ledger() -> String {
+    DEFAULT_LEDGER.to_string()
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum HandlerEvent {
     AddRecord(BudgetRecord),
     UpdateRecord(BudgetRecord),
+    DeleteRecord(RecordId),
 }
 
 pub trait EventHandler {
-    fn handle_event(&mut self, event: HandlerEvent) -> Result<(), String>;
+    /// Save the event and return it as saved, added records get their ids from the storage
+    fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error>;
+}
+
+pub trait RecordsProvider {
+    /// Records with dates within `dates` range
+    fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error>;
+
+    /// Record made from the message, if any. Legacy records without a source
+    /// are matched by the message id they were saved with.
+    fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error>;
+
+    /// Record the user added last, the one with the greatest id
+    fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error>;
+}
+
+/// Storage backend which keeps records, reads them back and provides categories
+/// and accounts for them along with words learned for categories
+pub trait Storage:
+    EventHandler
+    + RecordsProvider
+    + CategoryProvider
+    + AccountProvider
+    + LearnedWordsProvider
+    + Send
+    + Sync
+{
+}
+
+impl<T> Storage for T where
+    T: EventHandler
+        + RecordsProvider
+        + CategoryProvider
+        + AccountProvider
+        + LearnedWordsProvider
+        + Send
+        + Sync
+{
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Csv,
+    GoogleSheets,
+    Sqlite,
+}
+
+/// Storage backends enabled by cargo features, the first one is used by default
+const COMPILED_IN_STORAGES: &[StorageKind] = &[
+    #[cfg(feature = "csv-storage")]
+    StorageKind::Csv,
+    #[cfg(feature = "gss-storage")]
+    StorageKind::GoogleSheets,
+    #[cfg(feature = "sqlite-storage")]
+    StorageKind::Sqlite,
+];
+
+impl FromStr for StorageKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "csv" => Ok(StorageKind::Csv),
+            "gss" | "google-sheets" => Ok(StorageKind::GoogleSheets),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(Error::Config(format!("Unknown storage backend '{}'", s))),
+        }
+    }
+}
+
+impl StorageKind {
+    /// Take storage kind from `BUDGET_STORAGE` env var or the first compiled in one
+    pub fn from_env() -> Result<Self, Error> {
+        match env::var(STORAGE_ENV) {
+            Ok(value) => value.parse(),
+            Err(..) => COMPILED_IN_STORAGES
+                .first()
+                .copied()
+                .ok_or_else(|| Error::Config("No storage backend is compiled in".to_string())),
+        }
+    }
+
+    /// Open storage of the ledger
+    #[allow(unused_variables)]
+    pub fn create(self, ledger: &str) -> Result<Box<dyn Storage>, Error> {
+        match self {
+            #[cfg(feature = "csv-storage")]
+            StorageKind::Csv => Ok(Box::new(CsvEventHandler::new(ledger)?)),
+            #[cfg(feature = "gss-storage")]
+            StorageKind::GoogleSheets => Ok(Box::new(GoogleDocsEventHandler::new(ledger)?)),
+            #[cfg(feature = "sqlite-storage")]
+            StorageKind::Sqlite => Ok(Box::new(SqliteEventHandler::new(ledger)?)),
+            #[allow(unreachable_patterns)]
+            kind => Err(Error::Config(format!(
+                "Storage backend {:?} is not compiled in",
+                kind
+            ))),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn storage_kind_from_str() {
+        assert_eq!(StorageKind::from_str("csv"), Ok(StorageKind::Csv));
+        assert_eq!(
+            StorageKind::from_str(" GSS "),
+            Ok(StorageKind::GoogleSheets)
+        );
+        assert_eq!(StorageKind::from_str("SQLite"), Ok(StorageKind::Sqlite));
+        assert!(StorageKind::from_str("postgres").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "gss-storage"))]
+    fn storage_kind_not_compiled_in() {
+        assert!(StorageKind::GoogleSheets.create(DEFAULT_LEDGER).is_err());
+    }
+
+    #[test]
+    fn source_ref_to_and_from_str() {
+        let source = SourceRef {
+            source: "telegram".to_string(),
+            chat_id: Some(-100500),
+            message_id: 42,
+            part: 0,
+        };
+        assert_eq!(source.to_string(), "telegram:-100500:42");
+        assert_eq!("telegram:-100500:42".parse(), Ok(source.clone()));
+
+        let source = SourceRef { part: 2, ..source };
+        assert_eq!(source.to_string(), "telegram:-100500:42/2");
+        assert_eq!("telegram:-100500:42/2".parse(), Ok(source));
+
+        let source = SourceRef {
+            source: "cli".to_string(),
+            chat_id: None,
+            message_id: 1615550400,
+            part: 0,
+        };
+        assert_eq!(source.to_string(), "cli::1615550400");
+        assert_eq!("cli::1615550400".parse(), Ok(source));
+
+        assert_eq!("".parse(), Ok(SourceRef::default()));
+        assert!("telegram:42".parse::<SourceRef>().is_err());
+        assert!("telegram:chat:42".parse::<SourceRef>().is_err());
+        assert!("telegram:1:42/".parse::<SourceRef>().is_err());
+    }
+
+    #[test]
+    fn record_kind_to_and_from_str() {
+        for kind in &[
+            RecordKind::Expense,
+            RecordKind::Income,
+            RecordKind::Transfer,
+        ] {
+            assert_eq!(kind.to_string().parse(), Ok(*kind));
+        }
+        assert_eq!("".parse(), Ok(RecordKind::Expense));
+        assert_eq!("Income".parse(), Ok(RecordKind::Income));
+        assert!("refund".parse::<RecordKind>().is_err());
+    }
+
+    fn record_with_source(id: RecordId, source: SourceRef) -> BudgetRecord {
+        BudgetRecord {
+            id,
+            source,
+            date: NaiveDate::from_ymd(2021, 3, 12),
+            category: "Fruits".to_string(),
+            amount: "10".parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency: Currency::EUR,
+            desc: "banana".to_string(),
+            user: "user".to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: NaiveDate::from_ymd(2021, 3, 12),
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    #[test]
+    fn find_legacy_record_by_source() {
+        let source = SourceRef {
+            source: "telegram".to_string(),
+            chat_id: Some(-100500),
+            message_id: 42,
+            part: 0,
+        };
+        let legacy = BudgetRecord {
+            id: 42,
+            source: SourceRef::default(),
+            ..record_with_source(7, source.clone())
+        };
+        assert_eq!(
+            find_by_source(vec![legacy.clone()], &source).map(|r| r.id),
+            Some(42)
+        );
+        let records = vec![legacy.clone(), record_with_source(7, source.clone())];
+        assert_eq!(find_by_source(records, &source).map(|r| r.id), Some(7));
+
+        let second = SourceRef { part: 1, ..source };
+        assert!(find_by_source(vec![legacy], &second).is_none());
+    }
+
     #[test]
     fn amount_integer() {
-        assert_eq!(Amount::from_str("42").unwrap().0, "42")
+        assert_eq!(Amount::from_str("42").unwrap().to_string(), "42")
     }
 
     #[test]
@@ -91,12 +569,12 @@ mod tests {
 
     #[test]
     fn amount_decimal_1_digit() {
-        assert_eq!(Amount::from_str("42.1").unwrap().0, "42.1")
+        assert_eq!(Amount::from_str("42.1").unwrap().to_string(), "42.1")
     }
 
     #[test]
     fn amount_decimal_2_digits() {
-        assert_eq!(Amount::from_str("42.13").unwrap().0, "42.13")
+        assert_eq!(Amount::from_str("42.13").unwrap().to_string(), "42.13")
     }
 
     #[test]
@@ -109,11 +587,65 @@ mod tests {
 
     #[test]
     fn amount_decimal_with_comma_replaced() {
-        assert_eq!(Amount::from_str("42,13").unwrap().0, "42.13")
+        assert_eq!(Amount::from_str("42,13").unwrap().to_string(), "42.13")
     }
 
     #[test]
     fn amount_negative_is_allowed() {
-        assert_eq!(Amount::from_str("-42").unwrap().0, "-42")
+        assert_eq!(Amount::from_str("-42").unwrap().to_string(), "-42")
+    }
+
+    fn amount(s: &str) -> Amount {
+        Amount::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn amount_arithmetic() {
+        assert_eq!(
+            amount("9.75").checked_add(amount("0.25")),
+            Some(amount("10"))
+        );
+        assert_eq!(
+            amount("9.75").checked_sub(amount("10")),
+            Some(amount("-0.25"))
+        );
+        assert_eq!(-amount("9.75"), amount("-9.75"));
+        let sum = [amount("0.1"), amount("0.2"), amount("0.3")]
+            .iter()
+            .fold(Amount::zero(), |sum, x| sum.saturating_add(*x));
+        assert_eq!(sum, amount("0.6"));
+        assert_eq!(
+            amount("312").percent_of(amount("400")),
+            Some(Decimal::from(78))
+        );
+        assert_eq!(amount("1").percent_of(Amount::zero()), None);
+    }
+
+    #[test]
+    fn amount_overflow() {
+        let big = amount(&format!("5{}", "0".repeat(28)));
+        assert_eq!(big.checked_add(big), None);
+        assert_eq!(big.saturating_add(big), Amount(Decimal::MAX));
+        assert_eq!((-big).saturating_sub(big), Amount(Decimal::MIN));
+    }
+
+    #[test]
+    fn amount_localized() {
+        assert_eq!(amount("9.75").localized(Locale::Ru).to_string(), "9,75");
+        assert_eq!(amount("9.75").localized(Locale::En).to_string(), "9.75");
+        assert_eq!(
+            format!("{:.2}", amount("9.5").localized(Locale::Ru)),
+            "9,50"
+        );
+    }
+
+    #[test]
+    fn amount_serde_keeps_plain_format() {
+        let json = serde_json::to_string(&amount("9.75")).unwrap();
+        assert_eq!(json, r#""9.75""#);
+        assert_eq!(
+            serde_json::from_str::<Amount>(r#""42""#).unwrap(),
+            amount("42")
+        );
     }
 }
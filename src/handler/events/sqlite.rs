@@ -0,0 +1,881 @@
+use std::env;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::NaiveDate;
+use log::{debug, info, warn};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::error::Error;
+use crate::handler::accounts::{Account, AccountProvider};
+use crate::handler::categorizer::{Category, CategoryProvider, LearnedWord, LearnedWordsProvider};
+use crate::handler::events::{
+    Amount, BudgetRecord, Currency, EventHandler, HandlerEvent, RecordId, RecordKind,
+    RecordsProvider, SourceRef,
+};
+
+const DATABASE_ENV: &str = "BUDGET_SQLITE_PATH";
+const DEFAULT_DATABASE: &str = "budget.sqlite";
+
+/// Schema changes applied in order, the number of applied ones is kept in `user_version`
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE records (
+        id INTEGER PRIMARY KEY,
+        date TEXT NOT NULL,
+        category TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT NOT NULL,
+        description TEXT NOT NULL,
+        user TEXT NOT NULL,
+        create_date TEXT NOT NULL,
+        base_amount TEXT,
+        base_currency TEXT
+    );
+    CREATE INDEX records_date ON records (date);
+    CREATE INDEX records_user ON records (user);
+    CREATE TABLE categories (
+        name TEXT PRIMARY KEY,
+        priority INTEGER NOT NULL,
+        lexemes TEXT NOT NULL DEFAULT ''
+    );
+"#,
+    r#"
+    CREATE TABLE ledger_records (
+        ledger TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        date TEXT NOT NULL,
+        category TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT NOT NULL,
+        description TEXT NOT NULL,
+        user TEXT NOT NULL,
+        create_date TEXT NOT NULL,
+        base_amount TEXT,
+        base_currency TEXT,
+        PRIMARY KEY (ledger, id)
+    );
+    INSERT INTO ledger_records
+        SELECT 'default', id, date, category, amount, currency, description, user,
+               create_date, base_amount, base_currency
+        FROM records;
+    DROP TABLE records;
+    ALTER TABLE ledger_records RENAME TO records;
+    CREATE INDEX records_date ON records (ledger, date);
+    CREATE INDEX records_user ON records (user);
+"#,
+    r#"
+    ALTER TABLE records ADD COLUMN source TEXT NOT NULL DEFAULT '';
+    CREATE INDEX records_source ON records (ledger, source);
+"#,
+    r#"
+    ALTER TABLE records ADD COLUMN kind TEXT NOT NULL DEFAULT 'expense';
+    ALTER TABLE categories ADD COLUMN kind TEXT NOT NULL DEFAULT 'expense';
+"#,
+    r#"
+    ALTER TABLE records ADD COLUMN account TEXT;
+    CREATE TABLE accounts (
+        name TEXT PRIMARY KEY,
+        lexemes TEXT NOT NULL DEFAULT ''
+    );
+"#,
+    r#"
+    ALTER TABLE categories ADD COLUMN monthly_limit TEXT;
+"#,
+    r#"
+    CREATE TABLE learned_words (
+        word TEXT PRIMARY KEY,
+        category TEXT NOT NULL
+    );
+"#,
+    r#"
+    ALTER TABLE records ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+"#,
+    r#"
+    ALTER TABLE categories ADD COLUMN parent TEXT;
+"#,
+    r#"
+    CREATE TABLE numbered_records (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        ledger TEXT NOT NULL,
+        date TEXT NOT NULL,
+        category TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT NOT NULL,
+        description TEXT NOT NULL,
+        user TEXT NOT NULL,
+        create_date TEXT NOT NULL,
+        base_amount TEXT,
+        base_currency TEXT,
+        source TEXT NOT NULL DEFAULT '',
+        kind TEXT NOT NULL DEFAULT 'expense',
+        account TEXT,
+        tags TEXT NOT NULL DEFAULT ''
+    );
+    INSERT INTO numbered_records
+        SELECT id, ledger, date, category, amount, currency, description, user, create_date,
+               base_amount, base_currency, source, kind, account, tags
+        FROM records;
+    DROP TABLE records;
+    ALTER TABLE numbered_records RENAME TO records;
+    CREATE INDEX records_date ON records (ledger, date);
+    CREATE INDEX records_user ON records (user);
+    CREATE INDEX records_source ON records (ledger, source);
+"#,
+    r#"
+    ALTER TABLE records ADD COLUMN to_account TEXT;
+"#,
+];
+
+const RECORD_COLUMNS: &str = "id, date, category, amount, currency, description, user, \
+                              create_date, base_amount, base_currency, ledger, source, kind, \
+                              account, tags, to_account";
+
+/// Records, categories and accounts in a SQLite database
+///
+/// Categories and accounts are managed right in the `categories` and `accounts` tables,
+/// lexemes are written the same way as in categories.csv. Words learned for categories
+/// are kept in the `learned_words` table, tags of a record are separated by spaces.
+/// The `parent` column of a category makes it a subcategory, records keep the full path.
+/// All ledgers share one database, records of each are partitioned by the `ledger` column.
+/// Record ids are assigned by SQLite and never reused, even after deletion.
+pub struct SqliteEventHandler {
+    // Connection is not Sync, while storages are shared between threads
+    conn: Mutex<Connection>,
+    ledger: String,
+}
+
+impl SqliteEventHandler {
+    /// Open database at `BUDGET_SQLITE_PATH` or budget.sqlite
+    pub fn new(ledger: &str) -> Result<Self, Error> {
+        let path = env::var(DATABASE_ENV).unwrap_or_else(|_| DEFAULT_DATABASE.to_string());
+        SqliteEventHandler::open(path, ledger)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, ledger: &str) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let conn = Connection::open(path).map_err(|err| {
+            Error::Storage(format!("Can't open database {}: {}", path.display(), err))
+        })?;
+        SqliteEventHandler::with_connection(conn, ledger)
+    }
+
+    fn with_connection(mut conn: Connection, ledger: &str) -> Result<Self, Error> {
+        migrate(&mut conn).map_err(|err| Error::Storage(format!("Migration failed: {}", err)))?;
+        Ok(SqliteEventHandler {
+            conn: Mutex::new(conn),
+            ledger: ledger.to_owned(),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic while holding the lock can't leave a transaction half-applied
+        self.conn.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The record is saved with a new id, which is returned
+    fn add_record(&self, record: &BudgetRecord) -> rusqlite::Result<RecordId> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT INTO records (date, category, amount, currency, description, user, \
+             create_date, base_amount, base_currency, ledger, source, kind, account, tags, \
+             to_account) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                record.date,
+                record.category,
+                record.amount,
+                record.currency,
+                record.desc,
+                record.user,
+                record.create_date,
+                record.base_amount,
+                record.base_currency,
+                self.ledger,
+                record.source,
+                record.kind,
+                record.account,
+                record.tags.join(" "),
+                record.to_account,
+            ],
+        )?;
+        // The id column is an alias of rowid
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Legacy records get the source of the record with their id
+    fn update_record(&self, record: &BudgetRecord) -> rusqlite::Result<usize> {
+        self.conn().execute(
+            "UPDATE records SET date = ?2, category = ?3, amount = ?4, currency = ?5, \
+             description = ?6, user = ?7, create_date = ?8, base_amount = ?9, \
+             base_currency = ?10, kind = ?12, account = ?13, tags = ?14, source = ?15, \
+             to_account = ?16 \
+             WHERE ledger = ?11 AND id = ?1",
+            params![
+                record.id,
+                record.date,
+                record.category,
+                record.amount,
+                record.currency,
+                record.desc,
+                record.user,
+                record.create_date,
+                record.base_amount,
+                record.base_currency,
+                self.ledger,
+                record.kind,
+                record.account,
+                record.tags.join(" "),
+                record.source,
+                record.to_account,
+            ],
+        )
+    }
+
+    fn delete_record(&self, id: RecordId) -> rusqlite::Result<usize> {
+        self.conn().execute(
+            "DELETE FROM records WHERE ledger = ?1 AND id = ?2",
+            params![self.ledger, id],
+        )
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Applying database migration #{}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", &((i + 1) as i64))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn record_from_row(row: &Row) -> rusqlite::Result<BudgetRecord> {
+    Ok(BudgetRecord {
+        id: row.get(0)?,
+        date: row.get(1)?,
+        category: row.get(2)?,
+        amount: row.get(3)?,
+        currency: row.get(4)?,
+        desc: row.get(5)?,
+        user: row.get(6)?,
+        create_date: row.get(7)?,
+        base_amount: row.get(8)?,
+        base_currency: row.get(9)?,
+        ledger: row.get(10)?,
+        source: row.get(11)?,
+        kind: row.get(12)?,
+        account: row.get(13)?,
+        tags: row
+            .get::<_, String>(14)?
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+        to_account: row.get(15)?,
+    })
+}
+
+impl EventHandler for SqliteEventHandler {
+    fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
+        let (id, result) = match &event {
+            HandlerEvent::AddRecord(record) => {
+                let id = self.add_record(record).map_err(|err| {
+                    Error::Storage(format!("Error during save new record: {}", err))
+                })?;
+                debug!("Record #{} saved", id);
+                return Ok(HandlerEvent::AddRecord(BudgetRecord {
+                    id,
+                    ..record.clone()
+                }));
+            }
+            HandlerEvent::UpdateRecord(record) => (record.id, self.update_record(record)),
+            HandlerEvent::DeleteRecord(id) => (*id, self.delete_record(*id)),
+        };
+        match result {
+            Ok(0) => {
+                warn!("Record #{} is not found", id);
+                Err(Error::Storage(format!("Record #{} is not found", id)))
+            }
+            Ok(_) => {
+                debug!("Record #{} saved", id);
+                Ok(event)
+            }
+            Err(err) => Err(Error::Storage(format!(
+                "Error during save record #{}: {}",
+                id, err
+            ))),
+        }
+    }
+}
+
+impl RecordsProvider for SqliteEventHandler {
+    fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+        let conn = self.conn();
+        let read_error = |err| Error::Storage(format!("Error during read records: {}", err));
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM records WHERE ledger = ?1 AND date >= ?2 AND date < ?3 \
+                 ORDER BY date, id",
+                RECORD_COLUMNS
+            ))
+            .map_err(read_error)?;
+        let rows = statement
+            .query_map(
+                params![self.ledger, dates.start, dates.end],
+                record_from_row,
+            )
+            .map_err(read_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(read_error)
+    }
+
+    fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+        // Legacy records have an empty source, they go after the record of the source
+        let legacy_id = if source.part == 0 {
+            Some(source.message_id)
+        } else {
+            None
+        };
+        self.conn()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM records WHERE ledger = ?1 \
+                     AND (source = ?2 OR (source = '' AND id = ?3)) \
+                     ORDER BY source = '' LIMIT 1",
+                    RECORD_COLUMNS
+                ),
+                params![self.ledger, source, legacy_id],
+                record_from_row,
+            )
+            .optional()
+            .map_err(|err| Error::Storage(format!("Error during read record: {}", err)))
+    }
+
+    fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error> {
+        self.conn()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM records WHERE ledger = ?1 AND user = ?2 \
+                     ORDER BY id DESC LIMIT 1",
+                    RECORD_COLUMNS
+                ),
+                params![self.ledger, user],
+                record_from_row,
+            )
+            .optional()
+            .map_err(|err| Error::Storage(format!("Error during read record: {}", err)))
+    }
+}
+
+impl CategoryProvider for SqliteEventHandler {
+    fn categories(&self) -> Result<Vec<Category>, Error> {
+        let conn = self.conn();
+        let read_error = |err| Error::Storage(format!("Error during read categories: {}", err));
+        let mut statement = conn
+            .prepare("SELECT name, priority, lexemes, kind, monthly_limit, parent FROM categories")
+            .map_err(read_error)?;
+        let rows = statement
+            .query_map(params![], |row| {
+                let lexemes: String = row.get(2)?;
+                let category = Category::new(row.get(0)?, row.get(1)?, lexemes.as_str().into());
+                Ok(category
+                    .with_kind(row.get(3)?)
+                    .with_limit(row.get(4)?)
+                    .with_parent(row.get(5)?))
+            })
+            .map_err(read_error)?;
+        let mut categories = vec![];
+        for row in rows {
+            match row {
+                Ok(category) => categories.push(category),
+                Err(err) => warn!("Skip invalid category: {}", err),
+            }
+        }
+        Ok(categories)
+    }
+}
+
+impl AccountProvider for SqliteEventHandler {
+    fn accounts(&self) -> Result<Vec<Account>, Error> {
+        let conn = self.conn();
+        let read_error = |err| Error::Storage(format!("Error during read accounts: {}", err));
+        let mut statement = conn
+            .prepare("SELECT name, lexemes FROM accounts")
+            .map_err(read_error)?;
+        let rows = statement
+            .query_map(params![], |row| {
+                let lexemes: String = row.get(1)?;
+                Ok(Account::new(row.get(0)?, lexemes.as_str().into()))
+            })
+            .map_err(read_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(read_error)
+    }
+}
+
+impl LearnedWordsProvider for SqliteEventHandler {
+    fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+        let conn = self.conn();
+        let read_error = |err| Error::Storage(format!("Error during read learned words: {}", err));
+        let mut statement = conn
+            .prepare("SELECT word, category FROM learned_words ORDER BY rowid")
+            .map_err(read_error)?;
+        let rows = statement
+            .query_map(params![], |row| {
+                Ok(LearnedWord {
+                    word: row.get(0)?,
+                    category: row.get(1)?,
+                })
+            })
+            .map_err(read_error)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(read_error)
+    }
+
+    /// A word learned again for another category replaces the previous one
+    fn learn_word(&mut self, word: &LearnedWord) -> Result<(), Error> {
+        self.conn()
+            .execute(
+                "INSERT OR REPLACE INTO learned_words (word, category) VALUES (?1, ?2)",
+                params![word.word, word.category],
+            )
+            .map(|_| ())
+            .map_err(|err| Error::Storage(format!("Error during save learned word: {}", err)))
+    }
+}
+
+// Amounts are kept as text, so they stay exact
+
+impl ToSql for Amount {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Amount {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+impl ToSql for Currency {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.code()))
+    }
+}
+
+impl FromSql for Currency {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+impl ToSql for SourceRef {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for SourceRef {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+impl ToSql for RecordKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for RecordKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::events::DEFAULT_LEDGER;
+
+    use super::*;
+
+    fn handler() -> SqliteEventHandler {
+        let conn = Connection::open_in_memory().unwrap();
+        SqliteEventHandler::with_connection(conn, DEFAULT_LEDGER).unwrap()
+    }
+
+    fn record(id: i64, amount: &str, day: u32) -> BudgetRecord {
+        let date = NaiveDate::from_ymd(2021, 3, day);
+        BudgetRecord {
+            id,
+            source: SourceRef {
+                source: "test".to_string(),
+                chat_id: None,
+                message_id: id,
+                part: 0,
+            },
+            date,
+            category: "Fruits".to_string(),
+            amount: amount.parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency: Currency::EUR,
+            desc: "banana".to_string(),
+            user: "user".to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: date,
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    fn amounts(handler: &SqliteEventHandler) -> Vec<(RecordId, String)> {
+        let dates = NaiveDate::from_ymd(2021, 3, 1)..NaiveDate::from_ymd(2021, 4, 1);
+        handler
+            .records(dates)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.id, r.amount.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn add_update_and_delete_records() {
+        let mut handler = handler();
+        for (id, amount) in [(1, "10"), (2, "20.5"), (3, "30")].iter() {
+            handler
+                .handle_event(HandlerEvent::AddRecord(record(*id, amount, 12)))
+                .unwrap();
+        }
+
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(record(1, "15", 12)))
+            .unwrap();
+        handler.handle_event(HandlerEvent::DeleteRecord(3)).unwrap();
+
+        assert_eq!(
+            amounts(&handler),
+            vec![(1, "15".to_string()), (2, "20.5".to_string())]
+        );
+        assert_eq!(
+            handler.handle_event(HandlerEvent::DeleteRecord(3)).err(),
+            Some(Error::Storage("Record #3 is not found".to_string()))
+        );
+    }
+
+    #[test]
+    fn update_record_found_by_source() {
+        let mut handler = handler();
+        let mut first = record(0, "10", 12);
+        first.source = "telegram:1:42".parse().unwrap();
+        let mut second = record(0, "20", 12);
+        second.source = "telegram:2:42".parse().unwrap();
+        handler
+            .handle_event(HandlerEvent::AddRecord(first))
+            .unwrap();
+        let added = handler
+            .handle_event(HandlerEvent::AddRecord(second.clone()))
+            .unwrap();
+        assert!(matches!(added, HandlerEvent::AddRecord(record) if record.id == 2));
+
+        let found = handler.record_by_source(&second.source).unwrap().unwrap();
+        assert_eq!(found.id, 2);
+        let edited = BudgetRecord {
+            amount: "25".parse().unwrap(),
+            ..found
+        };
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(edited))
+            .unwrap();
+
+        assert_eq!(
+            amounts(&handler),
+            vec![(1, "10".to_string()), (2, "25".to_string())]
+        );
+        let missing = "telegram:3:42".parse().unwrap();
+        assert!(handler.record_by_source(&missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn last_record_of_user() {
+        let mut handler = handler();
+        for (user, amount) in [("alice", "10"), ("alice", "20"), ("bob", "30")].iter() {
+            let mut added = record(0, amount, 12);
+            added.user = user.to_string();
+            handler
+                .handle_event(HandlerEvent::AddRecord(added))
+                .unwrap();
+        }
+
+        let last = handler.last_record_of("alice").unwrap().unwrap();
+        assert_eq!((last.id, last.amount.to_string()), (2, "20".to_string()));
+        handler.handle_event(HandlerEvent::DeleteRecord(2)).unwrap();
+        let last = handler.last_record_of("alice").unwrap().unwrap();
+        assert_eq!(last.id, 1);
+        assert!(handler.last_record_of("carol").unwrap().is_none());
+    }
+
+    #[test]
+    fn read_records_within_dates() {
+        let mut handler = handler();
+        for (id, day) in [(1, 20), (2, 10), (3, 31)].iter() {
+            handler
+                .handle_event(HandlerEvent::AddRecord(record(*id, "10", *day)))
+                .unwrap();
+        }
+        let dates = NaiveDate::from_ymd(2021, 3, 10)..NaiveDate::from_ymd(2021, 3, 31);
+        let ids: Vec<_> = handler
+            .records(dates)
+            .unwrap()
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn keep_converted_amount() {
+        let mut handler = handler();
+        let mut converted = record(1, "10", 12);
+        converted.base_amount = "739.50".parse().ok();
+        converted.base_currency = Some(Currency::RUB);
+        handler
+            .handle_event(HandlerEvent::AddRecord(converted))
+            .unwrap();
+
+        let dates = NaiveDate::from_ymd(2021, 3, 1)..NaiveDate::from_ymd(2021, 4, 1);
+        let record = handler.records(dates).unwrap().remove(0);
+        assert_eq!(record.currency, Currency::EUR);
+        assert_eq!(record.base_amount, "739.50".parse().ok());
+        assert_eq!(record.base_currency, Some(Currency::RUB));
+    }
+
+    #[test]
+    fn read_categories() {
+        let handler = handler();
+        handler
+            .conn()
+            .execute_batch(
+                "INSERT INTO categories (name, priority, lexemes) VALUES ('Fruits', 1, 'banana');
+                 INSERT INTO categories (name, priority) VALUES ('Other', 0);
+                 INSERT INTO categories (name, priority, lexemes, kind)
+                     VALUES ('Salary', 2, 'salary', 'income');
+                 INSERT INTO categories (name, priority, lexemes, kind, monthly_limit)
+                     VALUES ('Sweets', 3, 'candy', 'expense', '50.5');",
+            )
+            .unwrap();
+        let mut categories: Vec<_> = handler
+            .categories()
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.kind, c.limit))
+            .collect();
+        categories.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            categories,
+            vec![
+                ("Fruits".to_string(), RecordKind::Expense, None),
+                ("Other".to_string(), RecordKind::Expense, None),
+                ("Salary".to_string(), RecordKind::Income, None),
+                (
+                    "Sweets".to_string(),
+                    RecordKind::Expense,
+                    "50.5".parse().ok()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_category_parent() {
+        let handler = handler();
+        handler
+            .conn()
+            .execute_batch(
+                "INSERT INTO categories (name, priority) VALUES ('Food', 0);
+                 INSERT INTO categories (name, priority, parent) VALUES ('Dairy', 1, 'Food');",
+            )
+            .unwrap();
+        let mut parents: Vec<_> = handler
+            .categories()
+            .unwrap()
+            .into_iter()
+            .map(|c| (c.name, c.parent))
+            .collect();
+        parents.sort();
+        assert_eq!(
+            parents,
+            vec![
+                ("Dairy".to_string(), Some("Food".to_string())),
+                ("Food".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_accounts_and_record_account() {
+        let mut handler = handler();
+        handler
+            .conn()
+            .execute(
+                "INSERT INTO accounts VALUES ('Visa', 'visa,card')",
+                params![],
+            )
+            .unwrap();
+        let names: Vec<_> = handler
+            .accounts()
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect();
+        assert_eq!(names, vec!["Visa"]);
+
+        let mut paid = record(1, "10", 12);
+        paid.account = Some("Visa".to_string());
+        handler.handle_event(HandlerEvent::AddRecord(paid)).unwrap();
+        let date = NaiveDate::from_ymd(2021, 3, 12);
+        let records = handler.records(date..date.succ()).unwrap();
+        assert_eq!(records[0].account.as_deref(), Some("Visa"));
+    }
+
+    #[test]
+    fn update_record_tags() {
+        let mut handler = handler();
+        let mut tagged = record(1, "10", 12);
+        tagged.tags = vec!["vacation".to_string(), "kids".to_string()];
+        handler
+            .handle_event(HandlerEvent::AddRecord(tagged.clone()))
+            .unwrap();
+        let date = NaiveDate::from_ymd(2021, 3, 12);
+        let records = handler.records(date..date.succ()).unwrap();
+        assert_eq!(records[0].tags, vec!["vacation", "kids"]);
+
+        tagged.tags.clear();
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(tagged))
+            .unwrap();
+        let records = handler.records(date..date.succ()).unwrap();
+        assert!(records[0].tags.is_empty());
+    }
+
+    #[test]
+    fn learn_words() {
+        let mut handler = handler();
+        let word = |word: &str, category: &str| LearnedWord {
+            word: word.to_string(),
+            category: category.to_string(),
+        };
+        handler.learn_word(&word("ashan", "Fruits")).unwrap();
+        handler.learn_word(&word("starbucks", "Cafe")).unwrap();
+        handler.learn_word(&word("ashan", "Sweets")).unwrap();
+
+        assert_eq!(
+            handler.learned_words().unwrap(),
+            vec![word("starbucks", "Cafe"), word("ashan", "Sweets")]
+        );
+    }
+
+    #[test]
+    fn ledgers_are_partitioned() {
+        let path = std::env::temp_dir().join(format!("ledgers-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut default = SqliteEventHandler::open(&path, DEFAULT_LEDGER).unwrap();
+        let mut family = SqliteEventHandler::open(&path, "family").unwrap();
+        default
+            .handle_event(HandlerEvent::AddRecord(record(1, "10", 12)))
+            .unwrap();
+        family
+            .handle_event(HandlerEvent::AddRecord(record(1, "20", 12)))
+            .unwrap();
+        assert!(family.handle_event(HandlerEvent::DeleteRecord(1)).is_err());
+        family.handle_event(HandlerEvent::DeleteRecord(2)).unwrap();
+
+        assert_eq!(amounts(&default), vec![(1, "10".to_string())]);
+        assert!(amounts(&family).is_empty());
+        drop((default, family));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn migrate_records_to_default_ledger() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", &1i64).unwrap();
+        conn.execute_batch(
+            "INSERT INTO records VALUES \
+             (1, '2021-03-12', 'Fruits', '10', 'EUR', 'banana', 'user', '2021-03-12', NULL, NULL)",
+        )
+        .unwrap();
+
+        let handler = SqliteEventHandler::with_connection(conn, DEFAULT_LEDGER).unwrap();
+        let dates = NaiveDate::from_ymd(2021, 3, 1)..NaiveDate::from_ymd(2021, 4, 1);
+        let records = handler.records(dates).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ledger, DEFAULT_LEDGER);
+    }
+
+    #[test]
+    fn update_legacy_record() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", &1i64).unwrap();
+        // Ids were message ids before sources were kept
+        conn.execute_batch(
+            "INSERT INTO records VALUES \
+             (42, '2021-03-12', 'Fruits', '10', 'EUR', 'banana', 'user', '2021-03-12', NULL, NULL)",
+        )
+        .unwrap();
+        let mut handler = SqliteEventHandler::with_connection(conn, DEFAULT_LEDGER).unwrap();
+        let source: SourceRef = "telegram:-100500:42".parse().unwrap();
+
+        let found = handler.record_by_source(&source).unwrap().unwrap();
+        assert_eq!(found.id, 42);
+        let edited = BudgetRecord {
+            amount: "12".parse().unwrap(),
+            source: source.clone(),
+            ..found
+        };
+        handler
+            .handle_event(HandlerEvent::UpdateRecord(edited))
+            .unwrap();
+        let added = handler
+            .handle_event(HandlerEvent::AddRecord(record(0, "20", 12)))
+            .unwrap();
+
+        assert!(matches!(added, HandlerEvent::AddRecord(record) if record.id == 43));
+        assert_eq!(handler.record_by_source(&source).unwrap().unwrap().id, 42);
+        assert_eq!(
+            amounts(&handler),
+            vec![(42, "12".to_string()), (43, "20".to_string())]
+        );
+        let second = SourceRef { part: 1, ..source };
+        assert!(handler.record_by_source(&second).unwrap().is_none());
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+}
@@ -1,88 +1,366 @@
-use chrono::{Duration, Local, TimeZone};
+use chrono::{Local, TimeZone};
 use log::debug;
-use std::ops::Sub;
+use std::ops::Range;
 
+use crate::error::Error;
 use crate::handler::{
-    categorizer::{Categorizer, CategoryProvider},
-    date_parser::{DateShiftParser, DefaultDateShiftParser},
-    events::{Amount, BudgetRecord, HandlerEvent},
-    tokenizer::{tokenize, MessageTokens, Token},
+    accounts::{Account, AccountProvider, UserAccounts},
+    categorizer::{Categorizer, Category, CategoryProvider, LearnedWord, LearnedWordsProvider},
+    date_parser::{DateShiftParser, UserDateShiftParsers},
+    events::{
+        Amount, BudgetRecord, Currency, HandlerEvent, RecordKind, SourceRef, UserCurrencies,
+        DEFAULT_LEDGER,
+    },
+    rates::CurrencyConverter,
+    tokenizer::{tokenize, Token},
 };
 
-mod categorizer;
+pub(crate) mod accounts;
+pub(crate) mod budget;
+pub(crate) mod categorizer;
+pub(crate) mod clock;
 pub mod date_parser;
+pub(crate) mod digest;
 pub(crate) mod events;
+pub(crate) mod rates;
+pub(crate) mod report;
 mod tokenizer;
+pub(crate) mod user_settings;
 
-#[derive(Debug)]
+/// Words between expenses written in one message
+/// Number of categories suggested for a misspelled name
+const MAX_SUGGESTIONS: usize = 3;
+/// Beginnings of words marking income
+const INCOME_WORDS: &[&str] = &["salary", "income", "зарплат", "доход"];
+/// Beginnings of words marking transfers between accounts
+const TRANSFER_WORDS: &[&str] = &["transfer", "перевод", "перевел", "перевёл"];
+
+pub type ChatId = i64;
+
+#[derive(Debug, Deserialize)]
 pub struct Input {
+    /// Message id, unique within the chat only
     pub id: i64,
+    /// Reader the message comes from, like `telegram` or `cli`
+    #[serde(skip)]
+    pub source: String,
+    /// Chat the message comes from, readers without chats leave it empty
+    #[serde(default)]
+    pub chat: Option<ChatId>,
     pub user: String,
     pub text: String,
     pub is_new: bool,
     pub unixtime: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Output {
     pub text: String,
     pub events: Vec<HandlerEvent>,
+    /// Sources of records with categories named by `#name` or `cat:name`,
+    /// words of such records are learned for their categories
+    #[serde(skip)]
+    pub named: Vec<SourceRef>,
 }
 
 pub struct RawMessageParser {
     categorizer: Categorizer,
+    accounts: Vec<Account>,
+    user_accounts: UserAccounts,
+    date_parsers: UserDateShiftParsers,
+    currencies: UserCurrencies,
+    converter: Option<CurrencyConverter>,
 }
 
 impl RawMessageParser {
-    pub fn new<P: CategoryProvider>(provider: &P) -> RawMessageParser {
+    pub fn new<P: CategoryProvider + AccountProvider + LearnedWordsProvider + ?Sized>(
+        provider: &P,
+        date_parsers: UserDateShiftParsers,
+        currencies: UserCurrencies,
+        user_accounts: UserAccounts,
+        converter: Option<CurrencyConverter>,
+    ) -> Result<RawMessageParser, Error> {
         let mut categorizer = Categorizer::new();
-        categorizer.load_categories(provider);
-        RawMessageParser { categorizer }
+        categorizer.load_categories(provider)?;
+        categorizer.load_learned_words(provider)?;
+        Ok(RawMessageParser {
+            categorizer,
+            accounts: provider.accounts()?,
+            user_accounts,
+            date_parsers,
+            currencies,
+            converter,
+        })
     }
 
+    pub(crate) fn category(&self, name: &str) -> Option<&Category> {
+        self.categorizer.category(name)
+    }
+
+    /// Learn words of the record description for its category, so other records
+    /// with them get it when no lexeme matches. Dates and accounts are left out.
+    pub(crate) fn learn(&mut self, record: &BudgetRecord) -> Vec<LearnedWord> {
+        let tokens = tokenize(&record.desc);
+        let date_tokens = self
+            .date_parsers
+            .for_user(&record.user)
+            .parse_date_shift(&tokens, record.create_date)
+            .map_or(0..0, |m| m.tokens);
+        let words: Vec<_> = tokens
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !date_tokens.contains(i))
+            .filter_map(|(_, token)| match token {
+                Token::Word(word) if !self.accounts.iter().any(|a| a.match_word(word)) => {
+                    Some(*word)
+                }
+                _ => None,
+            })
+            .collect();
+        self.categorizer.learn(&words, &record.category)
+    }
+
+    /// Parse the message into a record per expense, an edited message gives updates of them all.
+    ///
+    /// A category named like `cat:groceries` is taken as is, an unknown one gives only a reply.
+    /// Hashtags like `#groceries` name a category too, unless there is no such one,
+    /// then they are tags like `+vacation`. Tags apply to every expense of the message.
     pub fn handle_message(&mut self, input: Input) -> Option<Output> {
         debug!("{:?}", &input);
         let date = Local.timestamp(input.unixtime, 0u32).date();
         let tokens = tokenize(&input.text);
-        let record = BudgetRecord {
-            id: input.id,
-            create_date: date.naive_local(),
-            date: date
-                .naive_local()
-                .sub(DefaultDateShiftParser::parse_date_shift(&tokens).unwrap_or(Duration::zero())),
-            category: self.categorizer.classify(&tokens)?.name.to_owned(),
-            amount: RawMessageParser::extract_amount(&tokens)?,
-            desc: RawMessageParser::extract_description(&tokens),
-            user: input.user,
-        };
-        let event = if input.is_new {
-            HandlerEvent::AddRecord(record)
-        } else {
-            HandlerEvent::UpdateRecord(record)
-        };
-        let reply = RawMessageParser::build_reply_message(&event);
+        let date_match = self
+            .date_parsers
+            .for_user(&input.user)
+            .parse_date_shift(&tokens, date.naive_local());
+        let record_date = date_match
+            .as_ref()
+            .map_or(date.naive_local(), |m| m.shift.apply(date.naive_local()));
+        let date_tokens = date_match.map_or(0..0, |m| m.tokens);
+        let tags = self.extract_tags(&tokens);
+        let mut events = Vec::new();
+        let mut named = Vec::new();
+        for (part, expense) in RawMessageParser::split_expenses(&tokens, &date_tokens)
+            .into_iter()
+            .enumerate()
+        {
+            let tokens = &tokens[expense.clone()];
+            let date_tokens = date_tokens.start.saturating_sub(expense.start)
+                ..date_tokens.end.saturating_sub(expense.start);
+            let (amount, currency) = RawMessageParser::extract_amount(tokens, Some(date_tokens))?;
+            let (category, is_named) = match self.named_category(tokens) {
+                Ok(Some(category)) => (category, true),
+                Ok(None) => (self.categorizer.classify(tokens)?, false),
+                Err(text) => {
+                    return Some(Output {
+                        text,
+                        events: vec![],
+                        named: vec![],
+                    })
+                }
+            };
+            let kind = RawMessageParser::detect_kind(tokens, category);
+            let default_account = self.user_accounts.for_user(&input.user).as_deref();
+            let (account, to_account) = if kind == RecordKind::Transfer {
+                Account::detect_transfer(&self.accounts, tokens, default_account)
+            } else {
+                let account = Account::detect(&self.accounts, tokens)
+                    .map(|account| account.name.as_str())
+                    .or(default_account);
+                (account.map(str::to_owned), None)
+            };
+            let mut record = BudgetRecord {
+                // Ids are assigned by the storage when the record is saved
+                id: 0,
+                source: SourceRef {
+                    source: input.source.clone(),
+                    chat_id: input.chat,
+                    message_id: input.id,
+                    part,
+                },
+                create_date: date.naive_local(),
+                date: record_date,
+                category: category.name.to_owned(),
+                amount,
+                kind,
+                currency: currency.unwrap_or(*self.currencies.for_user(&input.user)),
+                desc: RawMessageParser::extract_description(tokens),
+                user: input.user.clone(),
+                account,
+                to_account,
+                tags: tags.clone(),
+                base_amount: None,
+                base_currency: None,
+                // The ledger is chosen by the controller
+                ledger: DEFAULT_LEDGER.to_string(),
+            };
+            if let Some(converter) = &self.converter {
+                converter.convert_record(&mut record);
+            }
+            if is_named {
+                named.push(record.source.clone());
+            }
+            events.push(if input.is_new {
+                HandlerEvent::AddRecord(record)
+            } else {
+                HandlerEvent::UpdateRecord(record)
+            });
+        }
+        let replies: Vec<_> = events
+            .iter()
+            .map(RawMessageParser::build_reply_message)
+            .collect();
         let output = Output {
-            text: reply,
-            events: vec![event],
+            text: replies.join("\n\n"),
+            events,
+            named,
         };
         debug!("{:?}", &output);
         Some(output)
     }
 
-    fn build_reply_message(event: &HandlerEvent) -> String {
-        match event {
-            HandlerEvent::AddRecord(record) => format!(
-                "Added new record #{}\nDate: {}\nCategory: {}\nAmount: {}",
-                record.id, record.date, record.category, record.amount,
-            ),
-            HandlerEvent::UpdateRecord(record) => format!(
-                "Updated existed record #{}\nDate: {}\nCategory: {}\nAmount: {}",
-                record.id, record.date, record.category, record.amount,
-            ),
+    pub(crate) fn build_reply_message(event: &HandlerEvent) -> String {
+        let (action, record) = match event {
+            HandlerEvent::AddRecord(record) => ("Added new", record),
+            HandlerEvent::UpdateRecord(record) => ("Updated existed", record),
+            HandlerEvent::DeleteRecord(id) => return format!("Deleted record #{}", id),
+        };
+        let mut reply = format!(
+            "{} record #{}\nDate: {}\nCategory: {}\n",
+            action, record.id, record.date, record.category,
+        );
+        if record.kind != RecordKind::Expense {
+            reply.push_str(&format!("Kind: {}\n", record.kind));
+        }
+        if let Some(account) = &record.account {
+            reply.push_str(&format!("Account: {}\n", account));
+        }
+        if let Some(account) = &record.to_account {
+            reply.push_str(&format!("To account: {}\n", account));
+        }
+        if !record.tags.is_empty() {
+            reply.push_str(&format!("Tags: {}\n", record.tags.join(", ")));
+        }
+        reply.push_str(&format!("Amount: {} {}", record.amount, record.currency));
+        if let (Some(amount), Some(currency)) = (record.base_amount, record.base_currency) {
+            if currency != record.currency {
+                reply.push_str(&format!(" ({} {})", amount, currency));
+            }
+        }
+        reply
+    }
+
+    fn unknown_category_reply(&self, name: &str) -> String {
+        let similar: Vec<_> = self
+            .categorizer
+            .similar_categories(name)
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|category| category.name.as_str())
+            .collect();
+        if similar.is_empty() {
+            format!("Unknown category '{}'", name)
+        } else {
+            format!(
+                "Unknown category '{}', did you mean {}?",
+                name,
+                similar.join(" or ")
+            )
+        }
+    }
+
+    /// The last category named with `cat:`, it has to exist,
+    /// or the last one named with a hashtag
+    fn named_category(&self, tokens: &[Token]) -> Result<Option<&Category>, String> {
+        let explicit = tokens.iter().rev().find_map(|token| match token {
+            Token::Category(name) => Some(*name),
+            _ => None,
+        });
+        if let Some(name) = explicit {
+            return match self.categorizer.named_category(name) {
+                Some(category) => Ok(Some(category)),
+                None => Err(self.unknown_category_reply(name)),
+            };
+        }
+        Ok(tokens.iter().rev().find_map(|token| match token {
+            Token::Hashtag(name) => self.categorizer.named_category(name),
+            _ => None,
+        }))
+    }
+
+    /// Tags and hashtags which don't name a category, lowercase without repeats
+    fn extract_tags(&self, tokens: &[Token]) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for token in tokens {
+            let tag = match token {
+                Token::Tag(tag) => tag,
+                Token::Hashtag(tag) if self.categorizer.named_category(tag).is_none() => tag,
+                _ => continue,
+            };
+            let tag = tag.to_lowercase();
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+
+    /// Split the message into expenses on commas, line breaks and "and", each one has an amount.
+    ///
+    /// Parts without an amount are left with their neighbours, like in "bread and butter 5".
+    fn split_expenses(tokens: &[Token], date_tokens: &Range<usize>) -> Vec<Range<usize>> {
+        let has_amount = |part: &Range<usize>| {
+            part.clone()
+                .any(|i| matches!(tokens[i], Token::Amount(..)) && !date_tokens.contains(&i))
+        };
+        let mut expenses: Vec<Range<usize>> = Vec::new();
+        let mut leading_words = None;
+        let mut start = 0;
+        for end in 0..=tokens.len() {
+            let is_separator = tokens.get(end).is_none_or(Token::is_expense_separator);
+            if !is_separator || date_tokens.contains(&end) {
+                continue;
+            }
+            let part = start..end;
+            start = end + 1;
+            if has_amount(&part) {
+                let start = leading_words.take().unwrap_or(part.start);
+                expenses.push(start..part.end);
+            } else if let Some(last) = expenses.last_mut() {
+                last.end = part.end;
+            } else {
+                leading_words.get_or_insert(part.start);
+            }
+        }
+        if expenses.is_empty() {
+            expenses.push(0..tokens.len());
         }
+        expenses
     }
 
-    fn extract_description(tokens: &MessageTokens) -> String {
+    /// Keywords like "salary" or an amount like `+500` take precedence over the category kind
+    fn detect_kind(tokens: &[Token], category: &Category) -> RecordKind {
+        let has_word_of = |beginnings: &[&str]| {
+            tokens.iter().any(|token| match token {
+                Token::Word(word) => {
+                    let word = word.to_lowercase();
+                    beginnings
+                        .iter()
+                        .any(|beginning| word.starts_with(beginning))
+                }
+                _ => false,
+            })
+        };
+        if has_word_of(TRANSFER_WORDS) {
+            RecordKind::Transfer
+        } else if has_word_of(INCOME_WORDS) || tokens.contains(&Token::Plus) {
+            RecordKind::Income
+        } else {
+            category.kind
+        }
+    }
+
+    fn extract_description(tokens: &[Token]) -> String {
         let mut result = String::new();
         let mut trailing_signs_buffer = None;
         for t in tokens {
@@ -108,13 +386,20 @@ impl RawMessageParser {
     }
 
     #[allow(dead_code)]
-    fn parse_amount(text: &str) -> Option<Amount> {
-        RawMessageParser::extract_amount(&tokenize(text))
+    fn parse_amount(text: &str) -> Option<(Amount, Option<Currency>)> {
+        RawMessageParser::extract_amount(&tokenize(text), None)
     }
 
-    fn extract_amount(tokens: &MessageTokens) -> Option<Amount> {
-        tokens.iter().find_map(|t| match t {
-            Token::Amount(amount) => Some(amount.clone()),
+    /// Take the first amount except tokens which the date was parsed from
+    fn extract_amount(
+        tokens: &[Token],
+        date_tokens: Option<Range<usize>>,
+    ) -> Option<(Amount, Option<Currency>)> {
+        let date_tokens = date_tokens.unwrap_or(0..0);
+        tokens.iter().enumerate().find_map(|(i, t)| match t {
+            Token::Amount(amount, currency) if !date_tokens.contains(&i) => {
+                Some((*amount, *currency))
+            }
             _ => None,
         })
     }
@@ -122,15 +407,20 @@ impl RawMessageParser {
 
 #[cfg(test)]
 mod tests {
-    use crate::handler::events::Amount;
+    use crate::handler::categorizer::Category;
+    use crate::handler::events::{Amount, Currency, RecordKind};
     use crate::handler::tokenizer::tokenize;
     use crate::handler::RawMessageParser as MH;
 
+    fn amount(text: &str, currency: Option<Currency>) -> Option<(Amount, Option<Currency>)> {
+        Some((text.parse().unwrap(), currency))
+    }
+
     #[test]
     fn parse_amount_as_first_word() {
         assert_eq!(
             MH::parse_amount("10.25 for banana pie"),
-            Some(Amount(String::from("10.25")))
+            amount("10.25", None)
         );
     }
 
@@ -138,16 +428,31 @@ mod tests {
     fn parse_amount_as_last_word() {
         assert_eq!(
             MH::parse_amount("Chocolate pie for 9,75."),
-            Some(Amount(String::from("9.75")))
+            amount("9.75", None)
         );
     }
 
     #[test]
     fn parse_amount_take_first_matched_number() {
+        assert_eq!(MH::parse_amount("5 for 2 kg of candies"), amount("5", None));
+    }
+
+    #[test]
+    fn parse_amount_with_currency() {
         assert_eq!(
-            MH::parse_amount("5 for 2 kg of candies"),
-            Some(Amount(String::from("5")))
+            MH::parse_amount("Coffee €3,50"),
+            amount("3.50", Some(Currency::EUR))
         );
+        assert_eq!(
+            MH::parse_amount("такси 300 рублей"),
+            amount("300", Some(Currency::RUB))
+        );
+    }
+
+    #[test]
+    fn extract_amount_skip_date_tokens() {
+        let tokens = tokenize("5 days ago banana 2");
+        assert_eq!(MH::extract_amount(&tokens, Some(0..3)), amount("2", None));
     }
 
     #[test]
@@ -165,4 +470,64 @@ mod tests {
             "Chocolate pie".to_string()
         )
     }
+
+    fn expenses(text: &str) -> Vec<String> {
+        let tokens = tokenize(text);
+        MH::split_expenses(&tokens, &(0..0))
+            .into_iter()
+            .map(|expense| MH::extract_description(&tokens[expense]))
+            .collect()
+    }
+
+    #[test]
+    fn split_expenses_on_separators() {
+        assert_eq!(
+            expenses("coffee 3.5, bread 2, milk 1.2"),
+            vec!["coffee", "bread", "milk"]
+        );
+        assert_eq!(
+            expenses("кофе 150 и хлеб 50\nмолоко 80"),
+            vec!["кофе", "хлеб", "молоко"]
+        );
+    }
+
+    #[test]
+    fn split_expenses_with_amounts_like_dates() {
+        let tokens = tokenize("coffee 10.05, bread 2");
+        let amounts: Vec<_> = MH::split_expenses(&tokens, &(0..0))
+            .into_iter()
+            .map(|expense| MH::extract_amount(&tokens[expense], None))
+            .collect();
+        assert_eq!(amounts, vec![amount("10.05", None), amount("2", None)]);
+    }
+
+    #[test]
+    fn split_expenses_keeps_parts_without_amount() {
+        assert_eq!(expenses("Chocolate pie, 9,75."), vec!["Chocolate pie"]);
+        assert_eq!(
+            expenses("bread and butter 5, jam 3, from the market"),
+            vec!["bread and butter", "jam, from the market"]
+        );
+        assert_eq!(expenses("no amount here"), vec!["no amount here"]);
+    }
+
+    #[test]
+    fn split_expenses_skip_date_tokens() {
+        let tokens = tokenize("5 days ago, banana 2");
+        assert_eq!(MH::split_expenses(&tokens, &(0..3)), vec![0..6]);
+    }
+
+    #[test]
+    fn detect_kind_by_keywords() {
+        let others = Category::new("Others".to_string(), 1, "".into());
+        let salary =
+            Category::new("Salary".to_string(), 1, "".into()).with_kind(RecordKind::Income);
+        let kind = |text: &str, category: &Category| MH::detect_kind(&tokenize(text), category);
+        assert_eq!(kind("banana 5", &others), RecordKind::Expense);
+        assert_eq!(kind("Salary 1000", &others), RecordKind::Income);
+        assert_eq!(kind("зарплату 1000", &others), RecordKind::Income);
+        assert_eq!(kind("cashback +500", &others), RecordKind::Income);
+        assert_eq!(kind("bonus 500", &salary), RecordKind::Income);
+        assert_eq!(kind("перевод на карту 500", &salary), RecordKind::Transfer);
+    }
 }
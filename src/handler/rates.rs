@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use chrono::NaiveDate;
+use log::warn;
+use rust_decimal::Decimal;
+
+use crate::error::Error;
+use crate::handler::events::{Amount, BudgetRecord, Currency};
+
+const BASE_CURRENCY_ENV: &str = "BUDGET_BASE_CURRENCY";
+const RATES_FILE_ENV: &str = "BUDGET_RATES_FILE";
+const DEFAULT_RATES_FILE: &str = "rates.json";
+
+/// Source of exchange rates to the base currency
+///
+/// Only the file-backed provider exists for now, a live one should cache
+/// rates per date since every record asks for one.
+pub trait RatesProvider {
+    /// How many units of the base currency one unit of `currency` costs on `date`
+    fn rate(&self, currency: Currency, date: NaiveDate) -> Result<Decimal, Error>;
+}
+
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    date: NaiveDate,
+    currency: Currency,
+    rate: Decimal,
+}
+
+/// Rates read from a JSON file like
+/// `[{"date": "2021-03-12", "currency": "USD", "rate": 73.95}]`
+///
+/// Rates are not published every day, so the latest one on or before the date is used.
+#[derive(Debug, Default)]
+pub struct FileRatesProvider {
+    rates: HashMap<Currency, BTreeMap<NaiveDate, Decimal>>,
+}
+
+impl FileRatesProvider {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| {
+            Error::Config(format!("Can't read rates from {}: {}", path.display(), err))
+        })?;
+        let rows: Vec<RateRow> = serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| Error::Parse(format!("Invalid rates in {}: {}", path.display(), err)))?;
+        Ok(FileRatesProvider::from_rows(rows))
+    }
+
+    fn from_rows(rows: Vec<RateRow>) -> Self {
+        let mut provider = FileRatesProvider::default();
+        for row in rows {
+            provider
+                .rates
+                .entry(row.currency)
+                .or_default()
+                .insert(row.date, row.rate);
+        }
+        provider
+    }
+}
+
+impl RatesProvider for FileRatesProvider {
+    fn rate(&self, currency: Currency, date: NaiveDate) -> Result<Decimal, Error> {
+        self.rates
+            .get(&currency)
+            .and_then(|rates| rates.range(..=date).next_back())
+            .map(|(_, rate)| *rate)
+            .ok_or_else(|| Error::Config(format!("No {} rate on {}", currency, date)))
+    }
+}
+
+/// Converts amounts to the base currency
+pub struct CurrencyConverter {
+    base: Currency,
+    provider: Box<dyn RatesProvider + Send + Sync>,
+}
+
+impl CurrencyConverter {
+    pub fn new(base: Currency, provider: Box<dyn RatesProvider + Send + Sync>) -> Self {
+        CurrencyConverter { base, provider }
+    }
+
+    /// Read config from env vars, conversion is disabled if the base currency is not set:
+    /// * `BUDGET_BASE_CURRENCY` - currency code to convert records to, e.g. `EUR`
+    /// * `BUDGET_RATES_FILE` - JSON file with rates to the base currency, `rates.json` if not set
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let base = match env::var(BASE_CURRENCY_ENV) {
+            Ok(code) => code.parse().map_err(Error::Config)?,
+            Err(..) => return Ok(None),
+        };
+        let path = env::var(RATES_FILE_ENV).unwrap_or_else(|_| DEFAULT_RATES_FILE.to_string());
+        let provider = FileRatesProvider::from_file(path)?;
+        Ok(Some(CurrencyConverter::new(base, Box::new(provider))))
+    }
+
+    pub fn convert(
+        &self,
+        amount: Amount,
+        currency: Currency,
+        date: NaiveDate,
+    ) -> Result<Amount, Error> {
+        if currency == self.base {
+            return Ok(amount);
+        }
+        let rate = self.provider.rate(currency, date)?;
+        amount.scaled(rate).ok_or_else(|| {
+            Error::Parse(format!(
+                "Can't convert {} {} to {}",
+                amount, currency, self.base
+            ))
+        })
+    }
+
+    /// Fill the converted amount of `record`, it is left empty if there is no rate
+    pub fn convert_record(&self, record: &mut BudgetRecord) {
+        match self.convert(record.amount, record.currency, record.date) {
+            Ok(amount) => {
+                record.base_amount = Some(amount);
+                record.base_currency = Some(self.base);
+            }
+            Err(err) => warn!("Record #{} is not converted: {}", record.id, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converter() -> CurrencyConverter {
+        let rows = serde_json::from_str(
+            r#"[
+                {"date": "2021-03-10", "currency": "USD", "rate": 74.1},
+                {"date": "2021-03-12", "currency": "USD", "rate": "73.95"},
+                {"date": "2021-03-12", "currency": "EUR", "rate": 88.3}
+            ]"#,
+        )
+        .unwrap();
+        CurrencyConverter::new(Currency::RUB, Box::new(FileRatesProvider::from_rows(rows)))
+    }
+
+    fn convert(amount: &str, currency: Currency, day: u32) -> Result<String, Error> {
+        let date = NaiveDate::from_ymd(2021, 3, day);
+        converter()
+            .convert(amount.parse().unwrap(), currency, date)
+            .map(|amount| amount.to_string())
+    }
+
+    #[test]
+    fn rate_on_the_date() {
+        assert_eq!(convert("10", Currency::USD, 12), Ok("739.50".to_string()));
+        assert_eq!(convert("2.5", Currency::USD, 10), Ok("185.25".to_string()));
+    }
+
+    #[test]
+    fn latest_rate_before_the_date() {
+        assert_eq!(convert("10", Currency::USD, 11), Ok("741.0".to_string()));
+        assert_eq!(convert("10", Currency::EUR, 14), Ok("883.0".to_string()));
+    }
+
+    #[test]
+    fn base_currency_is_not_converted() {
+        assert_eq!(convert("10", Currency::RUB, 1), Ok("10".to_string()));
+    }
+
+    #[test]
+    fn missing_rate() {
+        assert_eq!(
+            convert("10", Currency::EUR, 11),
+            Err(Error::Config("No EUR rate on 2021-03-11".to_string()))
+        );
+        assert!(convert("10", Currency::GBP, 12).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use chrono::naive::MAX_DATE;
+use chrono::{Datelike, Duration, NaiveDate};
+
+use crate::handler::categorizer::{own_name, PATH_SEPARATOR};
+use crate::handler::events::{Amount, BudgetRecord, Currency, RecordKind};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReportPeriod {
+    CurrentMonth,
+    CurrentWeek,
+    Month(i32, u32),
+}
+
+impl FromStr for ReportPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        match s.as_ref() {
+            "" | "month" => Ok(ReportPeriod::CurrentMonth),
+            "week" => Ok(ReportPeriod::CurrentWeek),
+            // The month after the period has to be a valid date too
+            _ => NaiveDate::parse_from_str(&format!("{}-01", s), "%Y-%m-%d")
+                .ok()
+                .filter(|date| next_month(date.year(), date.month()).is_some())
+                .map(|date| ReportPeriod::Month(date.year(), date.month()))
+                .ok_or_else(|| {
+                    format!("Unknown report period '{}', use month, week or YYYY-MM", s)
+                }),
+        }
+    }
+}
+
+impl ReportPeriod {
+    /// Dates of the period containing `today`, the end is excluded
+    pub fn range(self, today: NaiveDate) -> Range<NaiveDate> {
+        match self {
+            ReportPeriod::CurrentMonth => month_range(today.year(), today.month()),
+            ReportPeriod::CurrentWeek => {
+                let start = today - Duration::days(today.weekday().num_days_from_monday().into());
+                start..start + Duration::weeks(1)
+            }
+            ReportPeriod::Month(year, month) => month_range(year, month),
+        }
+    }
+}
+
+/// The last month of dates chrono supports ends with its last date
+fn month_range(year: i32, month: u32) -> Range<NaiveDate> {
+    let start = NaiveDate::from_ymd(year, month, 1);
+    start..next_month(year, month).unwrap_or(MAX_DATE)
+}
+
+/// The first day of the next month, if it is within the range of dates
+fn next_month(year: i32, month: u32) -> Option<NaiveDate> {
+    if month == 12 {
+        NaiveDate::from_ymd_opt(year.checked_add(1)?, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    }
+}
+
+/// Spending totals per category and per user, income is summed up separately
+/// and transfers between accounts are left out.
+///
+/// Balance of each account is its income minus expenses within the period,
+/// transfers move money from their account to the destination one.
+/// Totals of subcategories are rolled up into their parents, which go right before them.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    period: Range<NaiveDate>,
+    /// Tag of the records the report is limited to
+    tag: Option<String>,
+    by_category: Vec<(String, Totals)>,
+    by_user: Vec<(String, Totals)>,
+    total: Totals,
+    income_by_category: Vec<(String, Totals)>,
+    total_income: Totals,
+    balance_by_account: Vec<(String, Totals)>,
+}
+
+impl Report {
+    pub fn new(period: Range<NaiveDate>, records: &[BudgetRecord]) -> Self {
+        let mut by_category = HashMap::new();
+        let mut by_user = HashMap::new();
+        let mut total = Totals::default();
+        let mut income_by_category = HashMap::new();
+        let mut total_income = Totals::default();
+        let mut balance_by_account = HashMap::new();
+        for record in records.iter().filter(|r| period.contains(&r.date)) {
+            // Converted amounts are summed up together when available
+            let (amount, currency) = record.total_amount();
+            let balance_change = match record.kind {
+                RecordKind::Expense => {
+                    add_to_path(&mut by_category, &record.category, amount, currency);
+                    add_to(&mut by_user, &record.user, amount, currency);
+                    total.add(amount, currency);
+                    -amount
+                }
+                RecordKind::Income => {
+                    add_to_path(&mut income_by_category, &record.category, amount, currency);
+                    total_income.add(amount, currency);
+                    amount
+                }
+                RecordKind::Transfer => {
+                    if let Some(account) = &record.to_account {
+                        add_to(&mut balance_by_account, account, amount, currency);
+                    }
+                    -amount
+                }
+            };
+            if let Some(account) = &record.account {
+                add_to(&mut balance_by_account, account, balance_change, currency);
+            }
+        }
+        Report {
+            period,
+            tag: None,
+            by_category: tree_totals(by_category),
+            by_user: sorted_totals(by_user),
+            total,
+            income_by_category: tree_totals(income_by_category),
+            total_income,
+            balance_by_account: sorted_totals(balance_by_account),
+        }
+    }
+
+    /// Report on the records having the tag only
+    pub fn with_tag(period: Range<NaiveDate>, records: &[BudgetRecord], tag: &str) -> Self {
+        let tagged: Vec<_> = records.iter().filter(|r| r.has_tag(tag)).cloned().collect();
+        Report {
+            tag: Some(tag.to_lowercase()),
+            ..Report::new(period, &tagged)
+        }
+    }
+}
+
+/// Sums per currency, amounts in different currencies are never added up
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct Totals(BTreeMap<Currency, Amount>);
+
+impl Totals {
+    fn add(&mut self, amount: Amount, currency: Currency) {
+        let total = self.0.entry(currency).or_default();
+        *total = total.saturating_add(amount);
+    }
+}
+
+impl fmt::Display for Totals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (currency, amount)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match f.precision() {
+                Some(precision) => write!(f, "{:.*} {}", precision, amount, currency)?,
+                None => write!(f, "{} {}", amount, currency)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_to(totals: &mut HashMap<String, Totals>, key: &str, amount: Amount, currency: Currency) {
+    totals
+        .entry(key.to_owned())
+        .or_default()
+        .add(amount, currency);
+}
+
+/// The category path like `Food/Groceries/Dairy` is added to every level of it
+fn add_to_path(
+    totals: &mut HashMap<String, Totals>,
+    path: &str,
+    amount: Amount,
+    currency: Currency,
+) {
+    for (i, _) in path.match_indices(PATH_SEPARATOR) {
+        add_to(totals, &path[..i], amount, currency);
+    }
+    add_to(totals, path, amount, currency);
+}
+
+/// Biggest totals go first
+fn sorted_totals(totals: HashMap<String, Totals>) -> Vec<(String, Totals)> {
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|(n1, t1), (n2, t2)| t2.cmp(t1).then(n1.cmp(n2)));
+    totals
+}
+
+/// Every category is followed by its subcategories, the biggest totals go first on each level
+fn tree_totals(totals: HashMap<String, Totals>) -> Vec<(String, Totals)> {
+    let sorted = sorted_totals(totals);
+    let mut tree = Vec::with_capacity(sorted.len());
+    add_subtree(&sorted, None, &mut tree);
+    tree
+}
+
+fn add_subtree(
+    sorted: &[(String, Totals)],
+    parent: Option<&str>,
+    tree: &mut Vec<(String, Totals)>,
+) {
+    let children = sorted
+        .iter()
+        .filter(|(path, _)| path.rsplit_once(PATH_SEPARATOR).map(|(p, _)| p) == parent);
+    for (path, total) in children {
+        tree.push((path.clone(), total.clone()));
+        add_subtree(sorted, Some(path), tree);
+    }
+}
+
+/// Subcategories are indented under their parents
+fn write_category_totals(f: &mut fmt::Formatter<'_>, totals: &[(String, Totals)]) -> fmt::Result {
+    for (category, total) in totals {
+        let depth = category.matches(PATH_SEPARATOR).count() + 1;
+        let indent = "  ".repeat(depth);
+        write!(f, "\n{}{}: {:.2}", indent, own_name(category), total)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last_day = self.period.end - Duration::days(1);
+        write!(f, "Report for {} - {}", self.period.start, last_day)?;
+        if let Some(tag) = &self.tag {
+            write!(f, " tagged {}", tag)?;
+        }
+        if self.by_category.is_empty() && self.income_by_category.is_empty() {
+            return write!(f, "\nNo records");
+        }
+        if !self.by_category.is_empty() {
+            write!(f, "\nBy category:")?;
+            write_category_totals(f, &self.by_category)?;
+            write!(f, "\nBy user:")?;
+            for (user, total) in &self.by_user {
+                write!(f, "\n  {}: {:.2}", user, total)?;
+            }
+            write!(f, "\nTotal: {:.2}", self.total)?;
+        }
+        if !self.income_by_category.is_empty() {
+            write!(f, "\nIncome by category:")?;
+            write_category_totals(f, &self.income_by_category)?;
+            write!(f, "\nTotal income: {:.2}", self.total_income)?;
+        }
+        if !self.balance_by_account.is_empty() {
+            write!(f, "\nBalance by account:")?;
+            for (account, balance) in &self.balance_by_account {
+                write!(f, "\n  {}: {:.2}", account, balance)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handler::events::{SourceRef, DEFAULT_LEDGER};
+
+    use super::*;
+
+    fn record(date: NaiveDate, category: &str, user: &str, amount: &str) -> BudgetRecord {
+        record_in(date, category, user, amount, Currency::RUB)
+    }
+
+    fn record_in(
+        date: NaiveDate,
+        category: &str,
+        user: &str,
+        amount: &str,
+        currency: Currency,
+    ) -> BudgetRecord {
+        BudgetRecord {
+            id: 1,
+            source: SourceRef::default(),
+            date,
+            category: category.to_string(),
+            amount: amount.parse().unwrap(),
+            kind: RecordKind::Expense,
+            currency,
+            desc: String::new(),
+            user: user.to_string(),
+            account: None,
+            to_account: None,
+            tags: vec![],
+            create_date: date,
+            base_amount: None,
+            base_currency: None,
+            ledger: DEFAULT_LEDGER.to_string(),
+        }
+    }
+
+    #[test]
+    fn period_from_str() {
+        assert_eq!("".parse(), Ok(ReportPeriod::CurrentMonth));
+        assert_eq!("Week".parse(), Ok(ReportPeriod::CurrentWeek));
+        assert_eq!("2021-03".parse(), Ok(ReportPeriod::Month(2021, 3)));
+        assert!("2021-13".parse::<ReportPeriod>().is_err());
+        // The next month is out of range of dates
+        assert!("+262143-12".parse::<ReportPeriod>().is_err());
+        assert_eq!("+262143-11".parse(), Ok(ReportPeriod::Month(262143, 11)));
+    }
+
+    #[test]
+    fn period_range() {
+        let today = NaiveDate::from_ymd(2021, 12, 16);
+        assert_eq!(
+            ReportPeriod::CurrentMonth.range(today),
+            NaiveDate::from_ymd(2021, 12, 1)..NaiveDate::from_ymd(2022, 1, 1)
+        );
+        assert_eq!(
+            ReportPeriod::CurrentWeek.range(today),
+            NaiveDate::from_ymd(2021, 12, 13)..NaiveDate::from_ymd(2021, 12, 20)
+        );
+    }
+
+    #[test]
+    fn report_totals() {
+        let period = ReportPeriod::Month(2021, 3).range(NaiveDate::from_ymd(2021, 4, 1));
+        let records = vec![
+            record(NaiveDate::from_ymd(2021, 3, 1), "Fruits", "alice", "4.5"),
+            record(NaiveDate::from_ymd(2021, 3, 2), "Sweets", "bob", "10"),
+            record(NaiveDate::from_ymd(2021, 3, 31), "Fruits", "bob", "0.75"),
+            record(NaiveDate::from_ymd(2021, 4, 1), "Fruits", "bob", "100"),
+        ];
+        let report = Report::new(period, &records);
+        assert_eq!(
+            report.to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             By category:\n  Sweets: 10.00 RUB\n  Fruits: 5.25 RUB\n\
+             By user:\n  bob: 10.75 RUB\n  alice: 4.50 RUB\n\
+             Total: 15.25 RUB"
+        );
+    }
+
+    #[test]
+    fn report_totals_per_currency() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let records = vec![
+            record_in(date, "Fruits", "alice", "4.5", Currency::EUR),
+            record_in(date, "Fruits", "alice", "100", Currency::RUB),
+            record_in(date, "Fruits", "alice", "0.5", Currency::EUR),
+        ];
+        assert_eq!(
+            Report::new(period, &records).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             By category:\n  Fruits: 5.00 EUR, 100.00 RUB\n\
+             By user:\n  alice: 5.00 EUR, 100.00 RUB\n\
+             Total: 5.00 EUR, 100.00 RUB"
+        );
+    }
+
+    #[test]
+    fn empty_report() {
+        let period = ReportPeriod::Month(2021, 3).range(NaiveDate::from_ymd(2021, 4, 1));
+        assert_eq!(
+            Report::new(period, &[]).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\nNo records"
+        );
+    }
+
+    #[test]
+    fn report_on_tag() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let mut hotel = record(date, "Rent", "alice", "300");
+        hotel.tags = vec!["vacation".to_string(), "kids".to_string()];
+        let mut museum = record(date, "Fun", "bob", "20");
+        museum.tags = vec!["vacation".to_string()];
+        let records = vec![hotel, museum, record(date, "Rent", "alice", "500")];
+        assert_eq!(
+            Report::with_tag(period, &records, "Vacation").to_string(),
+            "Report for 2021-03-01 - 2021-03-31 tagged vacation\n\
+             By category:\n  Rent: 300.00 RUB\n  Fun: 20.00 RUB\n\
+             By user:\n  alice: 300.00 RUB\n  bob: 20.00 RUB\n\
+             Total: 320.00 RUB"
+        );
+    }
+
+    #[test]
+    fn report_rolls_up_subcategories() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let records = vec![
+            record(date, "Food/Groceries/Dairy", "alice", "5"),
+            record(date, "Food/Groceries", "alice", "20"),
+            record(date, "Food/Cafe", "bob", "30"),
+            record(date, "Rent", "bob", "40"),
+        ];
+        assert_eq!(
+            Report::new(period, &records).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             By category:\n  \
+             Food: 55.00 RUB\n    \
+             Cafe: 30.00 RUB\n    \
+             Groceries: 25.00 RUB\n      \
+             Dairy: 5.00 RUB\n  \
+             Rent: 40.00 RUB\n\
+             By user:\n  bob: 70.00 RUB\n  alice: 25.00 RUB\n\
+             Total: 95.00 RUB"
+        );
+    }
+
+    #[test]
+    fn report_uses_converted_amounts() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let mut converted = record_in(date, "Fruits", "alice", "10", Currency::USD);
+        converted.base_amount = "739.50".parse().ok();
+        converted.base_currency = Some(Currency::RUB);
+        let records = vec![converted, record(date, "Fruits", "alice", "60.50")];
+        assert_eq!(
+            Report::new(period, &records).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             By category:\n  Fruits: 800.00 RUB\n\
+             By user:\n  alice: 800.00 RUB\n\
+             Total: 800.00 RUB"
+        );
+    }
+
+    #[test]
+    fn report_income_separately() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let mut salary = record(date, "Salary", "alice", "1000");
+        salary.kind = RecordKind::Income;
+        let mut transfer = record(date, "Others", "alice", "300");
+        transfer.kind = RecordKind::Transfer;
+        let records = vec![record(date, "Fruits", "alice", "4.5"), salary, transfer];
+        assert_eq!(
+            Report::new(period.clone(), &records).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             By category:\n  Fruits: 4.50 RUB\n\
+             By user:\n  alice: 4.50 RUB\n\
+             Total: 4.50 RUB\n\
+             Income by category:\n  Salary: 1000.00 RUB\n\
+             Total income: 1000.00 RUB"
+        );
+        assert_eq!(
+            Report::new(period, &records[1..]).to_string(),
+            "Report for 2021-03-01 - 2021-03-31\n\
+             Income by category:\n  Salary: 1000.00 RUB\n\
+             Total income: 1000.00 RUB"
+        );
+    }
+
+    #[test]
+    fn report_balance_by_account() {
+        let date = NaiveDate::from_ymd(2021, 3, 1);
+        let period = ReportPeriod::Month(2021, 3).range(date);
+        let paid_by = |account: &str, mut record: BudgetRecord| {
+            record.account = Some(account.to_string());
+            record
+        };
+        let mut salary = record(date, "Salary", "alice", "1000");
+        salary.kind = RecordKind::Income;
+        let mut withdrawal = paid_by("Visa", record(date, "Others", "alice", "100"));
+        withdrawal.kind = RecordKind::Transfer;
+        withdrawal.to_account = Some("Cash".to_string());
+        let records = vec![
+            paid_by("Visa", salary),
+            paid_by("Visa", record(date, "Fruits", "alice", "4.5")),
+            paid_by("Cash", record(date, "Fruits", "bob", "10")),
+            record(date, "Fruits", "bob", "1"),
+            withdrawal,
+        ];
+        let report = Report::new(period, &records).to_string();
+        assert!(
+            report.ends_with(
+                "Total income: 1000.00 RUB\n\
+                 Balance by account:\n  Visa: 895.50 RUB\n  Cash: 90.00 RUB"
+            ),
+            "{}",
+            report
+        );
+    }
+}
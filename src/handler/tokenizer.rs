@@ -1,11 +1,30 @@
-use crate::handler::events::Amount;
+use regex::Regex;
+
+use crate::handler::date_parser::split_numeric_date;
+use crate::handler::events::{Amount, Currency};
 use crate::handler::tokenizer::Token::Word;
 
+lazy_static! {
+    static ref RE_AMBIGUOUS_DATE: Regex = Regex::new(r"^\d{2}\.\d{2}$").unwrap();
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Token<'a> {
     Word(&'a str),
-    Amount(Amount),
+    /// Amount with the currency written next to it, if any
+    Amount(Amount, Option<Currency>),
+    Date(&'a str),
     TrailingSigns(&'a str),
+    /// Start of a non-empty line after the first one
+    LineBreak,
+    /// Plus sign written right before an amount like `+500`, it marks income
+    Plus,
+    /// Category named explicitly like `cat:transport`
+    Category(&'a str),
+    /// Word like `#groceries`, it names either a category or a tag
+    Hashtag(&'a str),
+    /// Tag like `+vacation`
+    Tag(&'a str),
 }
 
 impl Token<'_> {
@@ -25,28 +44,144 @@ impl Token<'_> {
             false
         }
     }
+
+    /// Comma, semicolon, line break or "and" between expenses of one message
+    pub fn is_expense_separator(&self) -> bool {
+        match self {
+            Token::TrailingSigns(signs) => signs.contains(',') || signs.contains(';'),
+            Token::LineBreak => true,
+            token => token.any_of_words(SEPARATOR_WORDS),
+        }
+    }
 }
 
 pub type MessageTokens<'a> = Vec<Token<'a>>;
 
 const TRAILING_SIGNS: &[char] = &['.', ',', ':', ';', '!', '?'];
+const CATEGORY_PREFIX: &str = "cat:";
+const SEPARATOR_WORDS: &[&str] = &["and", "и"];
 
-pub fn tokenize(text: &str) -> MessageTokens {
+pub fn tokenize(text: &str) -> MessageTokens<'_> {
     let mut result = Vec::new();
-    for word in text.split_whitespace() {
-        let original_word = word;
-        let word = word.trim_end_matches(TRAILING_SIGNS);
-        match word.parse() {
-            Ok(amount) => result.push(Token::Amount(amount)),
-            Err(..) => result.push(Word(word)),
+    let mut ambiguous_dates = Vec::new();
+    for line in text.lines() {
+        if !result.is_empty() && !line.trim().is_empty() {
+            result.push(Token::LineBreak);
         }
-        if original_word != word {
-            result.push(Token::TrailingSigns(&original_word[word.len()..]))
+        for word in line.split_whitespace() {
+            let original_word = word;
+            let trimmed_word = word.trim_end_matches(TRAILING_SIGNS);
+            if let Some(label) = label(trimmed_word) {
+                result.push(label);
+                if original_word != trimmed_word {
+                    result.push(Token::TrailingSigns(&original_word[trimmed_word.len()..]))
+                }
+                continue;
+            }
+            let word = match trimmed_word.strip_prefix('+') {
+                Some(amount) if amount.starts_with(|c: char| c.is_ascii_digit()) => {
+                    result.push(Token::Plus);
+                    amount
+                }
+                _ => trimmed_word,
+            };
+            match word.parse() {
+                Ok(amount) => {
+                    // Currency written before the amount, e.g. `USD 12`
+                    let currency = match result.last() {
+                        Some(Word(w)) => Currency::detect(w),
+                        _ => None,
+                    };
+                    if currency.is_some() {
+                        result.pop();
+                    }
+                    if RE_AMBIGUOUS_DATE.is_match(word) && split_numeric_date(word).is_some() {
+                        ambiguous_dates.push((result.len(), word));
+                    }
+                    result.push(Token::Amount(amount, currency))
+                }
+                Err(..) => match split_currency(word) {
+                    Some((amount, currency)) => result.push(Token::Amount(amount, Some(currency))),
+                    None if split_numeric_date(word).is_some() => result.push(Token::Date(word)),
+                    // Currency written after the amount, e.g. `150 руб`
+                    None => match (result.last_mut(), Currency::detect(word)) {
+                        (Some(Token::Amount(_, currency @ None)), Some(detected)) => {
+                            *currency = Some(detected)
+                        }
+                        _ => result.push(Word(word)),
+                    },
+                },
+            }
+            if original_word != trimmed_word {
+                result.push(Token::TrailingSigns(&original_word[trimmed_word.len()..]))
+            }
         }
     }
+    resolve_ambiguous_dates(&mut result, ambiguous_dates);
     result
 }
 
+/// Something like `12.03` is either an amount or a date, so it is treated as a date
+/// only if there is another amount in the same expense of the message
+fn resolve_ambiguous_dates<'a>(tokens: &mut [Token<'a>], ambiguous_dates: Vec<(usize, &'a str)>) {
+    let mut start = 0;
+    for end in 0..=tokens.len() {
+        if !tokens.get(end).is_none_or(Token::is_expense_separator) {
+            continue;
+        }
+        let part = start..end;
+        start = end + 1;
+        let amounts = tokens[part.clone()]
+            .iter()
+            .filter(|t| matches!(t, Token::Amount(..)))
+            .count();
+        let dates: Vec<_> = ambiguous_dates
+            .iter()
+            .filter(|(i, _)| part.contains(i))
+            .collect();
+        if amounts > dates.len() {
+            for &&(i, word) in &dates {
+                if let Token::Amount(_, None) = tokens[i] {
+                    tokens[i] = Token::Date(word);
+                }
+            }
+        }
+    }
+}
+
+/// Category, hashtag or tag marked by `cat:` in any case, `#` or `+`,
+/// the name after the mark starts with a letter
+fn label(word: &str) -> Option<Token<'_>> {
+    let is_name = |name: &str| name.starts_with(char::is_alphabetic);
+    if let Some(name) = word.strip_prefix('#').filter(|name| is_name(name)) {
+        return Some(Token::Hashtag(name));
+    }
+    if let Some(name) = word.strip_prefix('+').filter(|name| is_name(name)) {
+        return Some(Token::Tag(name));
+    }
+    let prefix_len = CATEGORY_PREFIX.len();
+    if word.is_char_boundary(prefix_len)
+        && word[..prefix_len].eq_ignore_ascii_case(CATEGORY_PREFIX)
+        && is_name(&word[prefix_len..])
+    {
+        return Some(Token::Category(&word[prefix_len..]));
+    }
+    None
+}
+
+/// Split an amount with attached currency like `€12`, `12$` or `150руб`
+fn split_currency(word: &str) -> Option<(Amount, Currency)> {
+    let is_amount_char = |c: char| c.is_ascii_digit() || c == '-' || c == '.' || c == ',';
+    let start = word.find(is_amount_char)?;
+    let end = word.rfind(is_amount_char)? + 1;
+    let currency = match (&word[..start], &word[end..]) {
+        ("", "") => return None,
+        (symbol, "") | ("", symbol) => Currency::detect_attached(symbol)?,
+        _ => return None,
+    };
+    Some((word[start..end].parse().ok()?, currency))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,7 +203,7 @@ mod tests {
     fn single_amount() {
         assert_eq!(
             tokenize("-42.35"),
-            vec![Token::Amount(Amount("-42.35".to_string()))]
+            vec![Token::Amount("-42.35".parse().unwrap(), None)]
         )
     }
 
@@ -77,16 +212,115 @@ mod tests {
         assert_eq!(
             tokenize("7.45 an apple and 2 bananas"),
             vec![
-                Token::Amount(Amount("7.45".to_string())),
+                Token::Amount("7.45".parse().unwrap(), None),
                 Token::Word("an"),
                 Token::Word("apple"),
                 Token::Word("and"),
-                Token::Amount(Amount("2".to_string())),
+                Token::Amount("2".parse().unwrap(), None),
                 Token::Word("bananas"),
             ]
         )
     }
 
+    #[test]
+    fn numeric_dates() {
+        assert_eq!(
+            tokenize("banana 3,50 2021-03-12 12/03"),
+            vec![
+                Token::Word("banana"),
+                Token::Amount("3.50".parse().unwrap(), None),
+                Token::Date("2021-03-12"),
+                Token::Date("12/03"),
+            ]
+        )
+    }
+
+    #[test]
+    fn ambiguous_date_with_another_amount() {
+        assert_eq!(
+            tokenize("12.03 banana 3,50"),
+            vec![
+                Token::Date("12.03"),
+                Token::Word("banana"),
+                Token::Amount("3.50".parse().unwrap(), None),
+            ]
+        )
+    }
+
+    #[test]
+    fn ambiguous_date_with_amount_of_another_expense() {
+        assert_eq!(
+            tokenize("pizza 11.09, cola 2"),
+            vec![
+                Token::Word("pizza"),
+                Token::Amount("11.09".parse().unwrap(), None),
+                Token::TrailingSigns(","),
+                Token::Word("cola"),
+                Token::Amount("2".parse().unwrap(), None),
+            ]
+        )
+    }
+
+    #[test]
+    fn ambiguous_date_without_another_amount() {
+        assert_eq!(
+            tokenize("banana 12.03"),
+            vec![
+                Token::Word("banana"),
+                Token::Amount("12.03".parse().unwrap(), None),
+            ]
+        )
+    }
+
+    #[test]
+    fn attached_currency() {
+        assert_eq!(
+            tokenize("€12 coffee 3,50$ 150руб"),
+            vec![
+                Token::Amount("12".parse().unwrap(), Some(Currency::EUR)),
+                Token::Word("coffee"),
+                Token::Amount("3.50".parse().unwrap(), Some(Currency::USD)),
+                Token::Amount("150".parse().unwrap(), Some(Currency::RUB)),
+            ]
+        )
+    }
+
+    #[test]
+    fn separate_currency() {
+        assert_eq!(
+            tokenize("taxi 150 руб. USD 12 lunch"),
+            vec![
+                Token::Word("taxi"),
+                Token::Amount("150".parse().unwrap(), Some(Currency::RUB)),
+                Token::TrailingSigns("."),
+                Token::Amount("12".parse().unwrap(), Some(Currency::USD)),
+                Token::Word("lunch"),
+            ]
+        )
+    }
+
+    #[test]
+    fn words_like_currencies() {
+        assert_eq!(
+            tokenize("to try 5 р 7р"),
+            vec![
+                Token::Word("to"),
+                Token::Word("try"),
+                Token::Amount("5".parse().unwrap(), None),
+                Token::Word("р"),
+                Token::Amount("7".parse().unwrap(), Some(Currency::RUB)),
+            ]
+        )
+    }
+
+    #[test]
+    fn currency_without_amount_is_a_word() {
+        assert_eq!(
+            tokenize("euro trip"),
+            vec![Token::Word("euro"), Token::Word("trip")]
+        )
+    }
+
     #[test]
     fn trailing_signs() {
         assert_eq!(
@@ -101,9 +335,58 @@ mod tests {
             tokenize("banana 3,50."),
             vec![
                 Token::Word("banana"),
-                Token::Amount(Amount("3.50".to_string())),
+                Token::Amount("3.50".parse().unwrap(), None),
                 Token::TrailingSigns("."),
             ]
         );
     }
+
+    #[test]
+    fn line_breaks() {
+        assert_eq!(
+            tokenize("coffee 3\n\n  bread 2\n"),
+            vec![
+                Token::Word("coffee"),
+                Token::Amount("3".parse().unwrap(), None),
+                Token::LineBreak,
+                Token::Word("bread"),
+                Token::Amount("2".parse().unwrap(), None),
+            ]
+        )
+    }
+
+    #[test]
+    fn category_names_and_tags() {
+        assert_eq!(
+            tokenize("banana chocolate #Fruits, CAT:eating_out 5 +Trip #1 cat: # +"),
+            vec![
+                Token::Word("banana"),
+                Token::Word("chocolate"),
+                Token::Hashtag("Fruits"),
+                Token::TrailingSigns(","),
+                Token::Category("eating_out"),
+                Token::Amount("5".parse().unwrap(), None),
+                Token::Tag("Trip"),
+                Token::Word("#1"),
+                Token::Word("cat"),
+                Token::TrailingSigns(":"),
+                Token::Word("#"),
+                Token::Word("+"),
+            ]
+        )
+    }
+
+    #[test]
+    fn plus_before_amount() {
+        assert_eq!(
+            tokenize("salary +500, +"),
+            vec![
+                Token::Word("salary"),
+                Token::Plus,
+                Token::Amount("500".parse().unwrap(), None),
+                Token::TrailingSigns(","),
+                Token::Word("+"),
+            ]
+        )
+    }
 }
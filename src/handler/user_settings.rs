@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+/// Setting configured per user with a common fallback
+#[derive(Debug, Default)]
+pub struct PerUser<T> {
+    default: T,
+    users: HashMap<String, T>,
+}
+
+impl<T> PerUser<T> {
+    pub fn new(default: T) -> Self {
+        PerUser {
+            default,
+            users: HashMap::new(),
+        }
+    }
+
+    /// Add values from config like `alice:ru;bob:en,ru`, where the part after `:`
+    /// is converted with `parse`. Invalid entries are skipped with a warning.
+    pub fn with_users<F>(mut self, config: &str, parse: F) -> Self
+    where
+        F: Fn(&str) -> Option<T>,
+    {
+        for entry in config.split(';') {
+            match entry.split_once(':') {
+                Some((user, value)) => match parse(value.trim()) {
+                    Some(value) => {
+                        self.users.insert(user.trim().to_owned(), value);
+                    }
+                    None => warn!("Invalid user setting: '{}'", entry),
+                },
+                None if entry.trim().is_empty() => {}
+                None => warn!("Invalid user setting: '{}'", entry),
+            }
+        }
+        self
+    }
+
+    pub fn for_user(&self, user: &str) -> &T {
+        self.users.get(user).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_values_with_fallback() {
+        let settings =
+            PerUser::new(1).with_users("alice:2; bob: 3;;carol;dave:x", |v| v.parse().ok());
+        assert_eq!(settings.for_user("alice"), &2);
+        assert_eq!(settings.for_user("bob"), &3);
+        assert_eq!(settings.for_user("carol"), &1);
+        assert_eq!(settings.for_user("dave"), &1);
+    }
+}
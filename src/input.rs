@@ -1,57 +1,637 @@
 use std::io;
+use std::{env, str::FromStr};
 
 use async_trait::async_trait;
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use log::{debug, error, warn};
 
-use crate::handler::events::EventHandler;
-use crate::handler::{Input, RawMessageParser};
+use crate::error::Error;
+use crate::handler::budget::BudgetStatus;
+use crate::handler::clock::{Clock, SystemClock};
+use crate::handler::digest::{Digest, DigestSchedule, Scheduler};
+use crate::handler::events::{
+    BudgetRecord, HandlerEvent, Ledgers, RecordId, RecordKind, SourceRef, Storage, DEFAULT_LEDGER,
+};
+use crate::handler::report::{Report, ReportPeriod};
+use crate::handler::{ChatId, Input, Output, RawMessageParser};
 #[cfg(feature = "cli")]
 use crate::input::cli::CliCommandReader;
+#[cfg(feature = "http")]
+use crate::input::http::HttpCommandReader;
 #[cfg(feature = "telegram")]
 use crate::input::telegram::TelegramCommandReader;
 
 #[cfg(feature = "cli")]
 mod cli;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(any(feature = "cli", test))]
+mod sink;
 #[cfg(feature = "telegram")]
 mod telegram;
+mod webhook;
+
+#[cfg(feature = "telegram")]
+pub use self::telegram::TelegramSender;
+pub use self::webhook::WebhookReader;
+
+const READER_ENV: &str = "BUDGET_READER";
 
 #[async_trait(? Send)]
 pub trait CommandReader {
-    fn new(controller: MainController) -> Self;
     fn name(&self) -> &str;
-    async fn start(self) -> io::Result<()>;
+    async fn start(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Delivers messages which are not replies, e.g. digests
+#[async_trait(? Send)]
+pub trait MessageSender {
+    async fn send_message(&self, chat: ChatId, text: &str) -> Result<(), Error>;
 }
 
 pub struct MainController {
-    pub(crate) parser: RawMessageParser,
-    pub(crate) handler: Box<dyn EventHandler + Send + Sync>,
+    parser: RawMessageParser,
+    ledgers: Ledgers,
+    clock: Box<dyn Clock + Send + Sync>,
+    scheduler: Scheduler,
 }
 
 impl MainController {
+    pub fn new(parser: RawMessageParser, ledgers: Ledgers) -> Self {
+        MainController {
+            parser,
+            ledgers,
+            clock: Box::new(SystemClock),
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock<C: Clock + Send + Sync + 'static>(self, clock: C) -> Self {
+        MainController {
+            clock: Box::new(clock),
+            ..self
+        }
+    }
+
+    /// Digests are sent while a reader checks for them with `send_digests`,
+    /// the first check only starts counting the time
+    pub fn with_digests(self, schedules: Vec<DigestSchedule>) -> Self {
+        MainController {
+            scheduler: Scheduler::new(schedules),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "telegram")]
+    pub fn has_digests(&self) -> bool {
+        !self.scheduler.is_empty()
+    }
+
+    /// Send digests which are due since the previous call, failed ones are only logged
+    pub async fn send_digests(&mut self, sender: &dyn MessageSender) {
+        let due = self.scheduler.due(self.clock.now().naive_local());
+        self.deliver_digests(due, sender).await
+    }
+
+    /// Send digests scheduled within `interval` before now, for an external cron
+    /// firing every `interval` when no controller lives between the runs
+    #[cfg(any(feature = "telegram", test))]
+    pub async fn send_digests_within(
+        &mut self,
+        interval: chrono::Duration,
+        sender: &dyn MessageSender,
+    ) {
+        let now = self.clock.now().naive_local();
+        let due = self.scheduler.due_since(now - interval, now);
+        self.deliver_digests(due, sender).await
+    }
+
+    async fn deliver_digests(&mut self, due: Vec<DigestSchedule>, sender: &dyn MessageSender) {
+        for (chat, text) in self.make_digests(due) {
+            match sender.send_message(chat, &text).await {
+                Ok(()) => debug!("Digest sent to chat {}", chat),
+                Err(err) => error!("Error on sending digest to chat {}: {}", chat, err),
+            }
+        }
+    }
+
+    fn make_digests(&mut self, due: Vec<DigestSchedule>) -> Vec<(ChatId, String)> {
+        let today = self.clock.today();
+        let mut digests = Vec::new();
+        for schedule in due {
+            let ledger = self.ledgers.ledger_of(Some(schedule.chat));
+            let dates = schedule.dates(today);
+            let records = self
+                .ledgers
+                .storage(&ledger)
+                .and_then(|storage| storage.records(dates.clone()));
+            match records {
+                Ok(records) => {
+                    let digest = Digest::new(&schedule, dates, &records);
+                    digests.push((schedule.chat, digest.to_string()))
+                }
+                Err(err) => error!("Can't make digest for chat {}: {}", schedule.chat, err),
+            }
+        }
+        digests
+    }
+
     fn dispatch(&mut self, cmd: Command) -> Option<String> {
+        self.execute(cmd).map(|output| output.text)
+    }
+
+    /// Run the command, the output keeps only events which were saved
+    fn execute(&mut self, cmd: Command) -> Option<Output> {
         match cmd {
-            Command::RecordMessage(input) => match self.parser.handle_message(input) {
-                Some(output) => {
-                    let mut result = Some(output.text);
-                    for event in output.events {
-                        if let Err(err) = self.handler.handle_event(event) {
-                            result.replace(err);
+            Command::RecordMessage(input) => {
+                let ledger = self.ledgers.ledger_of(input.chat);
+                let output = self.parser.handle_message(input)?;
+                if output.events.is_empty() {
+                    return reply(output.text);
+                }
+                let storage = match self.ledgers.storage(&ledger) {
+                    Ok(storage) => storage,
+                    Err(err) => return reply(err.to_string()),
+                };
+                let mut prepared: Vec<Result<HandlerEvent, Error>> = Vec::new();
+                for event in output.events {
+                    prepared.push(match event {
+                        HandlerEvent::AddRecord(mut record) => {
+                            record.ledger = ledger.clone();
+                            Ok(HandlerEvent::AddRecord(record))
+                        }
+                        HandlerEvent::UpdateRecord(mut record) => {
+                            record.ledger = ledger.clone();
+                            keep_identity(storage, record)
+                        }
+                        event => Ok(event),
+                    });
+                }
+                // Expenses removed from the edited message are deleted
+                let edited = prepared.iter().find_map(|event| match event {
+                    Ok(HandlerEvent::UpdateRecord(record)) => Some(record.source.clone()),
+                    _ => None,
+                });
+                if let Some(mut source) = edited {
+                    source.part = prepared.len();
+                    prepared.extend(removed_records(storage, source));
+                }
+                // Replies are built again since ids are known only now
+                let mut replies = Vec::with_capacity(prepared.len());
+                let mut events = Vec::with_capacity(prepared.len());
+                let mut learned = Vec::new();
+                for event in prepared {
+                    let saved = event.and_then(|event| storage.handle_event(event));
+                    match saved {
+                        Ok(event) => {
+                            let mut reply = RawMessageParser::build_reply_message(&event);
+                            match &event {
+                                HandlerEvent::AddRecord(record)
+                                | HandlerEvent::UpdateRecord(record)
+                                    if output.named.contains(&record.source) =>
+                                {
+                                    learned.push(record.clone())
+                                }
+                                _ => {}
+                            }
+                            if let HandlerEvent::AddRecord(record) = &event {
+                                if let Some(status) = budget_status(&self.parser, storage, record) {
+                                    reply.push('\n');
+                                    reply.push_str(&status);
+                                }
+                            }
+                            replies.push(reply);
+                            events.push(event);
                         }
+                        Err(err) => replies.push(err.to_string()),
+                    }
+                }
+                // Only categories named explicitly are learned, the parsed ones may be
+                // just other words of the edited message
+                for record in learned {
+                    if let Some(note) = self.learn(&record) {
+                        replies.push(note);
                     }
-                    result
                 }
-                None => None,
+                Some(Output {
+                    text: replies.join("\n\n"),
+                    events,
+                    named: output.named,
+                })
+            }
+            Command::Report(chat, period, today, tag) => {
+                let ledger = self.ledgers.ledger_of(chat);
+                let dates = period.range(today);
+                let records = self
+                    .ledgers
+                    .storage(&ledger)
+                    .and_then(|storage| storage.records(dates.clone()));
+                let report = records.map(|records| match tag {
+                    Some(tag) => Report::with_tag(dates, &records, &tag),
+                    None => Report::new(dates, &records),
+                });
+                match report {
+                    Ok(report) => reply(report.to_string()),
+                    Err(err) => reply(err.to_string()),
+                }
+            }
+            Command::Undo(chat, user) => {
+                let ledger = self.ledgers.ledger_of(chat);
+                match self.last_record_of(&ledger, &user) {
+                    Ok(Some(record)) => Some(self.delete_record(&ledger, record.id)),
+                    Ok(None) => reply("Nothing to undo".to_string()),
+                    Err(err) => reply(err.to_string()),
+                }
+            }
+            Command::Delete(chat, id) => {
+                let ledger = self.ledgers.ledger_of(chat);
+                Some(self.delete_record(&ledger, id))
+            }
+            Command::Category(chat, user, name) => {
+                let ledger = self.ledgers.ledger_of(chat);
+                match self.last_record_of(&ledger, &user) {
+                    Ok(Some(record)) => Some(self.change_category(&ledger, record, &name)),
+                    Ok(None) => reply("Nothing to change".to_string()),
+                    Err(err) => reply(err.to_string()),
+                }
+            }
+            Command::Link(None, ..) => reply("Ledgers can be linked in chats only".to_string()),
+            Command::Link(Some(chat), _, None) => reply(format!(
+                "This chat uses ledger '{}'. An admin can send /link <ledger> in another chat \
+                 to share it",
+                self.ledgers.ledger_of(Some(chat))
+            )),
+            Command::Link(Some(chat), user, Some(ledger)) => {
+                match self.ledgers.link(chat, &user, &ledger) {
+                    Ok(()) => reply(format!("This chat uses ledger '{}' now", ledger)),
+                    Err(err) => reply(err.to_string()),
+                }
+            }
+            Command::Invalid(text) => reply(text),
+        }
+    }
+
+    /// Record the user added to the ledger last, it is kept by the storage,
+    /// so it is found after restarts and in separate webhook invocations too
+    fn last_record_of(&mut self, ledger: &str, user: &str) -> Result<Option<BudgetRecord>, Error> {
+        self.ledgers.storage(ledger)?.last_record_of(user)
+    }
+
+    /// Move the record to the category and learn its description words for it
+    fn change_category(&mut self, ledger: &str, mut record: BudgetRecord, name: &str) -> Output {
+        let category = match self.parser.category(name) {
+            Some(category) => category.clone(),
+            None => {
+                return Output {
+                    text: format!("Unknown category '{}'", name),
+                    events: vec![],
+                    named: vec![],
+                }
+            }
+        };
+        let parser = &self.parser;
+        let result = self.ledgers.storage(ledger).and_then(|storage| {
+            // Kinds told by keywords are kept, ones of the category are changed with it
+            let category_kind = parser.category(&record.category).map(|c| c.kind);
+            if category_kind.unwrap_or_default() == record.kind {
+                record.kind = category.kind;
+            }
+            record.category = category.name.clone();
+            storage.handle_event(HandlerEvent::UpdateRecord(record))
+        });
+        match result {
+            Ok(event) => {
+                let mut text = RawMessageParser::build_reply_message(&event);
+                if let HandlerEvent::UpdateRecord(record) = &event {
+                    if let Some(note) = self.learn(record) {
+                        text.push_str("\n\n");
+                        text.push_str(&note);
+                    }
+                }
+                Output {
+                    text,
+                    events: vec![event],
+                    named: vec![],
+                }
+            }
+            Err(err) => Output {
+                text: err.to_string(),
+                events: vec![],
+                named: vec![],
+            },
+        }
+    }
+
+    /// Learn words of the record for its category, they are kept by the default ledger
+    /// since categories are common for all ledgers. Failed saves are only logged.
+    fn learn(&mut self, record: &BudgetRecord) -> Option<String> {
+        let learned = self.parser.learn(record);
+        if learned.is_empty() {
+            return None;
+        }
+        match self.ledgers.storage(DEFAULT_LEDGER) {
+            Ok(storage) => {
+                for word in &learned {
+                    if let Err(err) = storage.learn_word(word) {
+                        warn!("Can't save learned word '{}': {}", word.word, err);
+                    }
+                }
+            }
+            Err(err) => warn!("Can't save learned words: {}", err),
+        }
+        let words: Vec<_> = learned.iter().map(|w| w.word.as_str()).collect();
+        Some(format!(
+            "Remembered for {}: {}",
+            record.category,
+            words.join(", ")
+        ))
+    }
+
+    fn delete_record(&mut self, ledger: &str, id: RecordId) -> Output {
+        let event = HandlerEvent::DeleteRecord(id);
+        let text = RawMessageParser::build_reply_message(&event);
+        let result = self
+            .ledgers
+            .storage(ledger)
+            .and_then(|storage| storage.handle_event(event));
+        match result {
+            Ok(event) => Output {
+                text,
+                events: vec![event],
+                named: vec![],
+            },
+            Err(err) => Output {
+                text: err.to_string(),
+                events: vec![],
+                named: vec![],
             },
         }
     }
 }
 
-#[cfg(feature = "cli")]
-pub type DefaultCommandReader = CliCommandReader;
+/// Take id and creation date of the record made from the same message before.
+///
+/// An expense added to the edited message becomes a new record.
+fn keep_identity(storage: &dyn Storage, mut record: BudgetRecord) -> Result<HandlerEvent, Error> {
+    match storage.record_by_source(&record.source)? {
+        Some(saved) => {
+            record.id = saved.id;
+            record.create_date = saved.create_date;
+            Ok(HandlerEvent::UpdateRecord(record))
+        }
+        None if record.source.part > 0 => Ok(HandlerEvent::AddRecord(record)),
+        None => Err(Error::Storage(format!(
+            "Record of message {} is not found",
+            record.source
+        ))),
+    }
+}
 
-#[cfg(feature = "telegram")]
-pub type DefaultCommandReader = TelegramCommandReader;
+/// Deletion of records made from parts of the message starting with `source`
+fn removed_records(
+    storage: &dyn Storage,
+    mut source: SourceRef,
+) -> Vec<Result<HandlerEvent, Error>> {
+    let mut events = Vec::new();
+    loop {
+        match storage.record_by_source(&source) {
+            Ok(Some(record)) => events.push(Ok(HandlerEvent::DeleteRecord(record.id))),
+            Ok(None) => return events,
+            Err(err) => {
+                events.push(Err(err));
+                return events;
+            }
+        }
+        source.part += 1;
+    }
+}
+
+/// Spending of the record category this month, if the category has a limit
+fn budget_status(
+    parser: &RawMessageParser,
+    storage: &dyn Storage,
+    record: &BudgetRecord,
+) -> Option<String> {
+    if record.kind != RecordKind::Expense {
+        return None;
+    }
+    let limit = parser.category(&record.category)?.limit?;
+    let month = ReportPeriod::Month(record.date.year(), record.date.month()).range(record.date);
+    match storage.records(month) {
+        Ok(records) => Some(BudgetStatus::new(record, limit, &records).to_string()),
+        Err(err) => {
+            warn!("Can't check budget of {}: {}", record.category, err);
+            None
+        }
+    }
+}
+
+fn reply(text: String) -> Option<Output> {
+    Some(Output {
+        text,
+        events: vec![],
+        named: vec![],
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReaderKind {
+    Cli,
+    Telegram,
+    Http,
+}
+
+/// Command readers enabled by cargo features, the first one is used by default
+const COMPILED_IN_READERS: &[ReaderKind] = &[
+    #[cfg(feature = "cli")]
+    ReaderKind::Cli,
+    #[cfg(feature = "telegram")]
+    ReaderKind::Telegram,
+    #[cfg(feature = "http")]
+    ReaderKind::Http,
+];
+
+impl FromStr for ReaderKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_ref() {
+            "cli" => Ok(ReaderKind::Cli),
+            "telegram" | "tg" => Ok(ReaderKind::Telegram),
+            "http" => Ok(ReaderKind::Http),
+            _ => Err(Error::Config(format!("Unknown command reader '{}'", s))),
+        }
+    }
+}
+
+impl ReaderKind {
+    /// Take reader kind from `BUDGET_READER` env var or the first compiled in one
+    pub fn from_env() -> Result<Self, Error> {
+        match env::var(READER_ENV) {
+            Ok(value) => value.parse(),
+            Err(..) => COMPILED_IN_READERS
+                .first()
+                .copied()
+                .ok_or_else(|| Error::Config("No command reader is compiled in".to_string())),
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub fn create(self, controller: MainController) -> Result<Box<dyn CommandReader>, Error> {
+        match self {
+            #[cfg(feature = "cli")]
+            ReaderKind::Cli => Ok(Box::new(CliCommandReader::new(controller))),
+            #[cfg(feature = "telegram")]
+            ReaderKind::Telegram => Ok(Box::new(TelegramCommandReader::new(controller)?)),
+            #[cfg(feature = "http")]
+            ReaderKind::Http => Ok(Box::new(HttpCommandReader::new(controller)?)),
+            #[allow(unreachable_patterns)]
+            kind => Err(Error::Config(format!(
+                "Command reader {:?} is not compiled in",
+                kind
+            ))),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Command {
     RecordMessage(Input),
+    /// Report for the period, limited to records with the tag if any
+    Report(Option<ChatId>, ReportPeriod, NaiveDate, Option<String>),
+    /// Delete the last record added by the user
+    Undo(Option<ChatId>, String),
+    Delete(Option<ChatId>, RecordId),
+    /// Move the last record added by the user to the category
+    Category(Option<ChatId>, String, String),
+    /// Show the ledger of the chat or switch it to another one, the latter is for admins
+    Link(Option<ChatId>, String, Option<String>),
+    Invalid(String),
+}
+
+impl From<Input> for Command {
+    fn from(input: Input) -> Self {
+        let text = input.text.trim();
+        if !text.starts_with('/') {
+            return Command::RecordMessage(input);
+        }
+        let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        // In group chats commands are addressed to a bot like `/report@budget_bot`
+        let name = name.split('@').next().unwrap_or_default();
+        match name {
+            "/report" => {
+                // A tag like `+vacation` or `#vacation` goes along with the period
+                let (tags, period): (Vec<_>, Vec<_>) = args
+                    .split_whitespace()
+                    .partition(|arg| arg.starts_with(['+', '#']));
+                let tag = tags.last().map(|tag| tag[1..].to_owned());
+                match period.join(" ").parse() {
+                    Ok(period) => {
+                        let today = Local.timestamp(input.unixtime, 0u32).date();
+                        Command::Report(input.chat, period, today.naive_local(), tag)
+                    }
+                    Err(err) => Command::Invalid(err),
+                }
+            }
+            "/undo" => Command::Undo(input.chat, input.user),
+            "/delete" => match args.trim().trim_start_matches('#').parse() {
+                Ok(id) => Command::Delete(input.chat, id),
+                Err(..) => Command::Invalid("Usage: /delete <record id>".to_string()),
+            },
+            "/category" => match args.trim() {
+                "" => Command::Invalid("Usage: /category <category name>".to_string()),
+                category => Command::Category(input.chat, input.user, category.to_owned()),
+            },
+            "/link" => match args.trim() {
+                "" => Command::Link(input.chat, input.user, None),
+                ledger => Command::Link(input.chat, input.user, Some(ledger.to_owned())),
+            },
+            _ => Command::Invalid(format!("Unknown command {}", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_kind_from_str() {
+        assert_eq!(ReaderKind::from_str("cli"), Ok(ReaderKind::Cli));
+        assert_eq!(ReaderKind::from_str("Telegram"), Ok(ReaderKind::Telegram));
+        assert_eq!(ReaderKind::from_str("http"), Ok(ReaderKind::Http));
+        assert!(ReaderKind::from_str("irc").is_err());
+    }
+
+    fn input(text: &str) -> Input {
+        Input {
+            id: 1,
+            source: "test".to_string(),
+            chat: Some(-100500),
+            user: "user".to_string(),
+            text: text.to_string(),
+            is_new: true,
+            unixtime: 1615550400,
+        }
+    }
+
+    #[test]
+    fn record_message_command() {
+        let cmd = Command::from(input("banana 4.5"));
+        assert!(matches!(cmd, Command::RecordMessage(_)));
+    }
+
+    #[test]
+    fn report_command() {
+        let cmd = Command::from(input("/report@budget_bot 2021-02"));
+        assert!(matches!(
+            cmd,
+            Command::Report(Some(-100500), ReportPeriod::Month(2021, 2), _, None)
+        ));
+        let cmd = Command::from(input("/report"));
+        assert!(matches!(
+            cmd,
+            Command::Report(_, ReportPeriod::CurrentMonth, _, None)
+        ));
+        let cmd = Command::from(input("/report +Vacation week"));
+        assert!(matches!(
+            cmd,
+            Command::Report(_, ReportPeriod::CurrentWeek, _, Some(tag)) if tag == "Vacation"
+        ));
+        let cmd = Command::from(input("/report year"));
+        assert!(matches!(cmd, Command::Invalid(_)));
+    }
+
+    #[test]
+    fn undo_and_delete_commands() {
+        let cmd = Command::from(input("/undo"));
+        assert!(matches!(cmd, Command::Undo(Some(-100500), user) if user == "user"));
+        let cmd = Command::from(input("/delete #42"));
+        assert!(matches!(cmd, Command::Delete(_, 42)));
+        let cmd = Command::from(input("/delete"));
+        assert!(matches!(cmd, Command::Invalid(_)));
+    }
+
+    #[test]
+    fn category_command() {
+        let cmd = Command::from(input("/category  Eating out "));
+        assert!(matches!(
+            cmd,
+            Command::Category(Some(-100500), user, category) if user == "user" && category == "Eating out"
+        ));
+        let cmd = Command::from(input("/category"));
+        assert!(matches!(cmd, Command::Invalid(_)));
+    }
+
+    #[test]
+    fn link_command() {
+        let cmd = Command::from(input("/link family "));
+        assert!(matches!(
+            cmd,
+            Command::Link(Some(-100500), user, Some(ledger)) if user == "user" && ledger == "family"
+        ));
+        let cmd = Command::from(input("/link"));
+        assert!(matches!(cmd, Command::Link(Some(-100500), _, None)));
+    }
 }
@@ -5,6 +5,7 @@ use log::*;
 use async_trait::async_trait;
 
 use crate::handler::Input;
+use crate::input::sink::WriterSink;
 use crate::input::{Command, CommandReader, MainController};
 use std::io::Write;
 
@@ -13,33 +14,41 @@ pub struct CliCommandReader {
     user: String,
 }
 
-#[async_trait(? Send)]
-impl CommandReader for CliCommandReader {
-    fn new(controller: MainController) -> Self {
+impl CliCommandReader {
+    pub fn new(controller: MainController) -> Self {
         CliCommandReader {
             ctrl: controller,
             user: env::var("USER").unwrap_or("console".to_string()),
         }
     }
+}
 
+#[async_trait(? Send)]
+impl CommandReader for CliCommandReader {
     fn name(&self) -> &str {
         "CLI"
     }
 
-    async fn start(mut self) -> io::Result<()> {
+    /// Due digests are printed before each prompt
+    async fn start(mut self: Box<Self>) -> io::Result<()> {
+        let stdout = WriterSink::new(io::stdout());
         loop {
+            self.ctrl.send_digests(&stdout).await;
             print!("-> ");
             io::stdout().flush()?;
             let mut text = String::new();
             if io::stdin().read_line(&mut text).is_ok() && !text.trim().is_empty() {
-                let id = chrono::Utc::now().timestamp();
+                let id = self.ctrl.clock.now().timestamp();
                 let input = Input {
                     id,
+                    source: "cli".to_string(),
+                    chat: None,
                     user: self.user.clone(),
                     text,
                     is_new: true,
+                    unixtime: id,
                 };
-                let cmd = Command::RecordMessage(input);
+                let cmd = Command::from(input);
                 if let Some(response) = self.ctrl.dispatch(cmd) {
                     println!("<- {}", response)
                 } else {
@@ -0,0 +1,418 @@
+use std::convert::Infallible;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use http_server::service::{make_service_fn, service_fn};
+use http_server::{Body, Method, Request, Response, Server, StatusCode};
+use log::*;
+
+use crate::error::Error;
+use crate::handler::events::{Storage, DEFAULT_LEDGER};
+use crate::handler::report::{Report, ReportPeriod};
+use crate::handler::Input;
+use crate::input::{Command, CommandReader, MainController};
+
+const ADDR_ENV: &str = "BUDGET_HTTP_ADDR";
+const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+
+/// JSON API for other frontends:
+/// * `POST /messages` takes an `Input` and returns the reply with saved events
+/// * `GET /records?from=YYYY-MM-DD&to=YYYY-MM-DD` returns records, `to` is excluded
+/// * `GET /report?period=month` returns the report for a period like `/report` command,
+///   `tag` param limits it to records with the tag
+///
+/// Both GET endpoints read the default ledger unless another one is given with `ledger` param.
+pub struct HttpCommandReader {
+    ctrl: MainController,
+    addr: SocketAddr,
+}
+
+impl HttpCommandReader {
+    /// Listen on `BUDGET_HTTP_ADDR`, `127.0.0.1:8080` if not set
+    pub fn new(controller: MainController) -> Result<Self, Error> {
+        let addr = env::var(ADDR_ENV).unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+        let addr = addr
+            .parse()
+            .map_err(|err| Error::Config(format!("Invalid {} '{}': {}", ADDR_ENV, addr, err)))?;
+        Ok(HttpCommandReader {
+            ctrl: controller,
+            addr,
+        })
+    }
+}
+
+#[async_trait(? Send)]
+impl CommandReader for HttpCommandReader {
+    fn name(&self) -> &str {
+        "HTTP"
+    }
+
+    async fn start(self: Box<Self>) -> io::Result<()> {
+        let ctrl = Arc::new(Mutex::new(self.ctrl));
+        let make_service = make_service_fn(move |_conn| {
+            let ctrl = ctrl.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve(ctrl.clone(), req))) }
+        });
+        let server = Server::try_bind(&self.addr)
+            .map_err(|err| io::Error::new(io::ErrorKind::AddrNotAvailable, err))?;
+        info!("Listening on http://{}", self.addr);
+        server.serve(make_service).await.map_err(io::Error::other)
+    }
+}
+
+async fn serve(
+    ctrl: Arc<Mutex<MainController>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let (status, body) = match http_server::body::to_bytes(body).await {
+        Ok(body) => {
+            // A panic in one request must not stop the others
+            let mut ctrl = ctrl.lock().unwrap_or_else(PoisonError::into_inner);
+            route(
+                &mut ctrl,
+                &parts.method,
+                parts.uri.path(),
+                parts.uri.query().unwrap_or_default(),
+                &body,
+            )
+        }
+        Err(err) => error_response(Error::Network(format!("Can't read request: {}", err))),
+    };
+    debug!("{} {} -> {}", parts.method, parts.uri, status);
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .expect("response parts are valid");
+    Ok(response)
+}
+
+/// Handle a request and return the status with JSON body
+fn route(
+    ctrl: &mut MainController,
+    method: &Method,
+    path: &str,
+    query: &str,
+    body: &[u8],
+) -> (StatusCode, String) {
+    let result = match (method, path.trim_end_matches('/')) {
+        (&Method::POST, "/messages") => post_message(ctrl, body),
+        (&Method::GET, "/records") => get_records(ctrl, query),
+        (&Method::GET, "/report") => get_report(ctrl, query),
+        (_, "/messages") | (_, "/records") | (_, "/report") => {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                error_json(&format!("Method {} is not allowed", method)),
+            )
+        }
+        _ => {
+            return (
+                StatusCode::NOT_FOUND,
+                error_json(&format!("Unknown path {}", path)),
+            )
+        }
+    };
+    result
+        .map(|body| (StatusCode::OK, body))
+        .unwrap_or_else(error_response)
+}
+
+fn post_message(ctrl: &mut MainController, body: &[u8]) -> Result<String, Error> {
+    let mut input: Input = serde_json::from_slice(body)
+        .map_err(|err| Error::Parse(format!("Invalid message: {}", err)))?;
+    input.source = "http".to_string();
+    match ctrl.execute(Command::from(input)) {
+        Some(output) => to_json(&output),
+        None => Ok(serde_json::json!({"text": null, "events": []}).to_string()),
+    }
+}
+
+fn get_records(ctrl: &mut MainController, query: &str) -> Result<String, Error> {
+    let dates = match (param(query, "from"), param(query, "to")) {
+        (Some(from), Some(to)) => parse_date(from)?..parse_date(to)?,
+        (None, None) => period(ctrl, query)?,
+        _ => {
+            return Err(Error::Parse(
+                "Both 'from' and 'to' dates are required".to_string(),
+            ))
+        }
+    };
+    to_json(&ledger(ctrl, query)?.records(dates)?)
+}
+
+fn get_report(ctrl: &mut MainController, query: &str) -> Result<String, Error> {
+    let dates = period(ctrl, query)?;
+    let records = ledger(ctrl, query)?.records(dates.clone())?;
+    let report = match param(query, "tag") {
+        Some(tag) => Report::with_tag(dates, &records, tag),
+        None => Report::new(dates, &records),
+    };
+    to_json(&serde_json::json!({
+        "text": report.to_string(),
+        "report": report,
+    }))
+}
+
+fn ledger<'a>(ctrl: &'a mut MainController, query: &str) -> Result<&'a mut dyn Storage, Error> {
+    let ledger = param(query, "ledger").unwrap_or(DEFAULT_LEDGER);
+    ctrl.ledgers.storage(ledger)
+}
+
+/// Dates of `period` param like in `/report` command, the current month by default
+fn period(ctrl: &MainController, query: &str) -> Result<Range<NaiveDate>, Error> {
+    let period: ReportPeriod = param(query, "period")
+        .unwrap_or_default()
+        .parse()
+        .map_err(Error::Parse)?;
+    Ok(period.range(ctrl.clock.today()))
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, Error> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| Error::Parse(format!("Invalid date '{}', use YYYY-MM-DD", value)))
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String, Error> {
+    serde_json::to_string(value)
+        .map_err(|err| Error::Parse(format!("Can't serialize response: {}", err)))
+}
+
+fn error_response(err: Error) -> (StatusCode, String) {
+    let status = match err {
+        Error::Parse(..) => StatusCode::BAD_REQUEST,
+        Error::Network(..) => StatusCode::BAD_GATEWAY,
+        Error::Config(..) | Error::Storage(..) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, error_json(&err.to_string()))
+}
+
+fn error_json(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::handler::accounts::{Account, AccountProvider, UserAccounts};
+    use crate::handler::categorizer::{
+        Category, CategoryProvider, LearnedWord, LearnedWordsProvider,
+    };
+    use crate::handler::date_parser::UserDateShiftParsers;
+    use crate::handler::events::{
+        BudgetRecord, EventHandler, HandlerEvent, Ledgers, RecordsProvider, SourceRef,
+        UserCurrencies,
+    };
+    use crate::handler::RawMessageParser;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        records: Vec<BudgetRecord>,
+    }
+
+    impl EventHandler for MemoryStorage {
+        fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
+            match event {
+                HandlerEvent::AddRecord(mut record) => {
+                    record.id = self.records.len() as i64 + 1;
+                    self.records.push(record.clone());
+                    Ok(HandlerEvent::AddRecord(record))
+                }
+                event => Ok(event),
+            }
+        }
+    }
+
+    impl RecordsProvider for MemoryStorage {
+        fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+            let records = self.records.iter().filter(|r| dates.contains(&r.date));
+            Ok(records.cloned().collect())
+        }
+
+        fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+            let mut records = self.records.iter();
+            Ok(records.find(|record| record.source == *source).cloned())
+        }
+
+        fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error> {
+            let records = self.records.iter().filter(|record| record.user == user);
+            Ok(records.max_by_key(|record| record.id).cloned())
+        }
+    }
+
+    impl CategoryProvider for MemoryStorage {
+        fn categories(&self) -> Result<Vec<Category>, Error> {
+            Ok(vec![Category::new(
+                "Fruits".to_string(),
+                1,
+                "banana".into(),
+            )])
+        }
+    }
+
+    impl AccountProvider for MemoryStorage {
+        fn accounts(&self) -> Result<Vec<Account>, Error> {
+            Ok(vec![])
+        }
+    }
+
+    impl LearnedWordsProvider for MemoryStorage {
+        fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+            Ok(vec![])
+        }
+
+        fn learn_word(&mut self, _word: &LearnedWord) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn controller() -> MainController {
+        let parser = RawMessageParser::new(
+            &MemoryStorage::default(),
+            UserDateShiftParsers::default(),
+            UserCurrencies::default(),
+            UserAccounts::default(),
+            None,
+        )
+        .unwrap();
+        let ledgers = Ledgers::new(|_| Ok(Box::new(MemoryStorage::default()))).with_admins("alice");
+        MainController::new(parser, ledgers)
+    }
+
+    fn request(
+        ctrl: &mut MainController,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let (status, body) = route(ctrl, &method, path, query, body.as_bytes());
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    const MESSAGE: &str = r#"{
+        "id": 42,
+        "user": "alice",
+        "text": "banana 4.5",
+        "unixtime": 1615550400,
+        "is_new": true
+    }"#;
+
+    #[test]
+    fn post_message() {
+        let mut ctrl = controller();
+        let (status, body) = request(&mut ctrl, Method::POST, "/messages", MESSAGE);
+        assert_eq!(status, StatusCode::OK);
+        let text = body["text"].as_str().unwrap();
+        let record = &body["events"][0]["AddRecord"];
+        assert!(text.starts_with(&format!("Added new record #{}", record["id"])));
+        assert_eq!(record["source"], "http::42");
+        assert_eq!(record["category"], "Fruits");
+        assert_eq!(record["amount"], "4.5");
+    }
+
+    #[test]
+    fn get_records_and_report() {
+        let mut ctrl = controller();
+        let (_, added) = request(&mut ctrl, Method::POST, "/messages", MESSAGE);
+
+        let uri = "/records?from=2021-03-01&to=2021-04-01";
+        let (status, body) = request(&mut ctrl, Method::GET, uri, "");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["id"], added["events"][0]["AddRecord"]["id"]);
+
+        let (status, body) = request(&mut ctrl, Method::GET, "/report?period=2021-03", "");
+        assert_eq!(status, StatusCode::OK);
+        let text = body["text"].as_str().unwrap();
+        assert!(
+            text.starts_with("Report for 2021-03-01 - 2021-03-31"),
+            "{}",
+            text
+        );
+        assert_eq!(body["report"]["total"]["RUB"], "4.5");
+
+        let uri = "/records?from=2021-03-01&to=2021-04-01&ledger=family";
+        let (status, body) = request(&mut ctrl, Method::GET, uri, "");
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn messages_go_to_chat_ledgers() {
+        let mut ctrl = controller();
+        let message = |id, text| {
+            serde_json::json!({
+                "id": id, "chat": -100500, "user": "alice", "text": text,
+                "unixtime": 1615550400, "is_new": true,
+            })
+            .to_string()
+        };
+        let sources = |ctrl: &mut MainController, ledger| {
+            let uri = format!("/records?period=2021-03&ledger={}", ledger);
+            let (_, body) = request(ctrl, Method::GET, &uri, "");
+            let records = body.as_array().unwrap().clone();
+            records
+                .iter()
+                .map(|r| r["source"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        request(
+            &mut ctrl,
+            Method::POST,
+            "/messages",
+            &message(1, "banana 5"),
+        );
+        let (_, body) = request(
+            &mut ctrl,
+            Method::POST,
+            "/messages",
+            &message(2, "/link family"),
+        );
+        assert_eq!(body["text"], "This chat uses ledger 'family' now");
+        let (_, body) = request(
+            &mut ctrl,
+            Method::POST,
+            "/messages",
+            &message(3, "banana 7"),
+        );
+        assert_eq!(body["events"][0]["AddRecord"]["ledger"], "family");
+
+        assert_eq!(sources(&mut ctrl, "chat-100500"), vec!["http:-100500:1"]);
+        assert_eq!(sources(&mut ctrl, "family"), vec!["http:-100500:3"]);
+        assert!(sources(&mut ctrl, DEFAULT_LEDGER).is_empty());
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut ctrl = controller();
+        let (status, _) = request(&mut ctrl, Method::POST, "/messages", "{}");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(&mut ctrl, Method::GET, "/records?from=2021-03-01", "");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(&mut ctrl, Method::GET, "/report?period=year", "");
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request(&mut ctrl, Method::GET, "/messages", "");
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, body) = request(&mut ctrl, Method::GET, "/users", "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "Unknown path /users");
+    }
+}
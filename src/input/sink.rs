@@ -0,0 +1,36 @@
+use std::cell::RefCell;
+use std::io::Write;
+
+use async_trait::async_trait;
+
+use crate::error::Error;
+use crate::handler::ChatId;
+use crate::input::MessageSender;
+
+/// Writes messages to stdout, a file or a buffer instead of sending them to chats
+pub struct WriterSink<W: Write> {
+    writer: RefCell<W>,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(writer: W) -> Self {
+        WriterSink {
+            writer: RefCell::new(writer),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+#[async_trait(? Send)]
+impl<W: Write> MessageSender for WriterSink<W> {
+    async fn send_message(&self, chat: ChatId, text: &str) -> Result<(), Error> {
+        let mut writer = self.writer.borrow_mut();
+        writeln!(writer, "=> [{}] {}", chat, text)
+            .and_then(|_| writer.flush())
+            .map_err(|err| Error::Network(format!("Can't write message: {}", err)))
+    }
+}
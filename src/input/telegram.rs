@@ -1,13 +1,24 @@
 use std::{convert::Infallible, env, io, sync::Arc};
 
 use log::*;
-use tbot::{contexts::fields::Text, errors, prelude::*, types::update, Bot};
+use tbot::{
+    contexts::fields::Text,
+    errors,
+    prelude::*,
+    types::{chat, update},
+    Bot,
+};
 use tokio::{select, sync::mpsc, sync::RwLock, time};
 
 use async_trait::async_trait;
 
-use crate::handler::Input;
-use crate::input::{Command, CommandReader, MainController};
+use crate::error::Error;
+use crate::handler::{ChatId, Input};
+use crate::input::{Command, CommandReader, MainController, MessageSender};
+
+const TOKEN_ENV: &str = "BOT_TOKEN";
+/// How often due digests are checked while polling updates
+const DIGESTS_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60);
 
 pub struct TelegramCommandReader {
     ctrl: MainController,
@@ -15,27 +26,37 @@ pub struct TelegramCommandReader {
     tx: Option<mpsc::Sender<update::Id>>,
 }
 
-#[async_trait(? Send)]
-impl CommandReader for TelegramCommandReader {
-    fn new(controller: MainController) -> Self {
-        let timeout =
-            env::var("BOT_TIMEOUT").map_or(5, |v| v.parse().expect("BOT_TIMEOUT must be a number"));
-        TelegramCommandReader {
+impl TelegramCommandReader {
+    pub fn new(controller: MainController) -> Result<Self, Error> {
+        let timeout = match env::var("BOT_TIMEOUT") {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| Error::Config("BOT_TIMEOUT must be a number".to_string()))?,
+            Err(..) => 5,
+        };
+        Ok(TelegramCommandReader {
             ctrl: controller,
             timeout: time::Duration::from_secs(timeout),
             tx: None,
-        }
+        })
     }
+}
 
+#[async_trait(? Send)]
+impl CommandReader for TelegramCommandReader {
     fn name(&self) -> &str {
         "Telegram"
     }
 
-    async fn start(mut self) -> io::Result<()> {
-        let timeout = self.timeout;
+    async fn start(self: Box<Self>) -> io::Result<()> {
+        let mut this = *self;
+        let timeout = this.timeout;
+        let has_digests = this.ctrl.has_digests();
         info!("Start polling updates (timeout: {} sec)", timeout.as_secs());
         let (tx, mut rx) = mpsc::channel(10);
-        self.tx.replace(tx);
+        this.tx.replace(tx);
+        let bot = Bot::from_env(TOKEN_ENV);
+        let this = Arc::new(RwLock::new(this));
 
         let stop = async {
             loop {
@@ -46,6 +67,8 @@ impl CommandReader for TelegramCommandReader {
                         info!("Sender is closed, no more updates will come");
                         break;
                     }
+                } else if has_digests {
+                    debug!("No updates, keep polling to send digests");
                 } else {
                     info!("Timeout");
                     break;
@@ -53,13 +76,16 @@ impl CommandReader for TelegramCommandReader {
             }
         };
 
-        let polling = self.poll_updates();
+        let digests =
+            TelegramCommandReader::send_digests(this.clone(), TelegramSender(bot.clone()));
+        let polling = TelegramCommandReader::poll_updates(this, bot);
 
         select! {
             _ = stop => {
                 info!("Stop polling updates");
                 Ok(())
             },
+            _ = digests => Ok(()),
             result = polling => match result {
                 Ok(_) => Ok(()),
                 Err(errors::PollingSetup::DeleteWebhook(err)) => {
@@ -74,9 +100,12 @@ impl CommandReader for TelegramCommandReader {
 }
 
 impl TelegramCommandReader {
-    async fn poll_updates(self) -> Result<Infallible, errors::PollingSetup> {
-        let timeout = self.timeout.as_secs() + 1;
-        let mut bot = Bot::from_env("BOT_TOKEN").stateful_event_loop(RwLock::new(self));
+    async fn poll_updates(
+        this: Arc<RwLock<Self>>,
+        bot: Bot,
+    ) -> Result<Infallible, errors::PollingSetup> {
+        let timeout = this.read().await.timeout.as_secs() + 1;
+        let mut bot = bot.stateful_event_loop(this);
 
         bot.text(|ctx, this| async move {
             this.write().await.process_text(ctx, false).await;
@@ -86,6 +115,9 @@ impl TelegramCommandReader {
             this.write().await.process_text(ctx, true).await;
         });
 
+        // Bot API doesn't notify bots about deleted messages,
+        // so records can only be removed with `/undo` and `/delete`
+
         bot.after_update(|upd, this| async move {
             let mut this = this.write().await;
             let sender = this.tx.as_mut().unwrap();
@@ -95,6 +127,15 @@ impl TelegramCommandReader {
         bot.polling().timeout(timeout).start().await
     }
 
+    /// The reader doesn't stop on `BOT_TIMEOUT` while there are scheduled digests
+    async fn send_digests(this: Arc<RwLock<Self>>, sender: TelegramSender) {
+        let mut interval = time::interval(DIGESTS_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            this.write().await.ctrl.send_digests(&sender).await;
+        }
+    }
+
     async fn process_text<'s>(&mut self, ctx: Arc<impl Text>, edited: bool) {
         let username = ctx
             .from()
@@ -109,8 +150,10 @@ impl TelegramCommandReader {
             value
         );
 
-        let cmd = Command::RecordMessage(Input {
+        let cmd = Command::from(Input {
             id: ctx.message_id().0 as i64,
+            source: "telegram".to_string(),
+            chat: Some(ctx.chat().id.0),
             unixtime: ctx.date(),
             user: username.to_owned(),
             text: value.clone(),
@@ -127,3 +170,24 @@ impl TelegramCommandReader {
         }
     }
 }
+
+/// Sends messages to chats on behalf of the bot
+pub struct TelegramSender(Bot);
+
+impl TelegramSender {
+    pub fn from_env() -> Self {
+        TelegramSender(Bot::from_env(TOKEN_ENV))
+    }
+}
+
+#[async_trait(? Send)]
+impl MessageSender for TelegramSender {
+    async fn send_message(&self, chat: ChatId, text: &str) -> Result<(), Error> {
+        self.0
+            .send_message(chat::Id(chat), text)
+            .call()
+            .await
+            .map(|_| ())
+            .map_err(|err| Error::Network(format!("Can't send message: {}", err)))
+    }
+}
@@ -0,0 +1,642 @@
+use log::*;
+
+use crate::error::Error;
+use crate::handler::Input;
+use crate::input::{Command, MainController};
+
+/// Part of Telegram `Update` the bot cares about
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+    edited_message: Option<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    message_id: i64,
+    date: i64,
+    chat: Chat,
+    from: Option<User>,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    username: Option<String>,
+}
+
+/// Bot API method call sent back as the webhook response, so no extra request is needed
+#[derive(Debug, Serialize)]
+struct SendMessage {
+    method: &'static str,
+    chat_id: i64,
+    reply_to_message_id: i64,
+    text: String,
+}
+
+/// Handles updates pushed by Telegram one at a time, e.g. from a Lambda invocation
+pub struct WebhookReader {
+    ctrl: MainController,
+}
+
+impl WebhookReader {
+    pub fn new(controller: MainController) -> Self {
+        WebhookReader { ctrl: controller }
+    }
+
+    /// Dispatch an update given as JSON and return the webhook response body, if any
+    pub fn handle_update(&mut self, body: &str) -> Result<Option<String>, Error> {
+        let update: Update = serde_json::from_str(body)
+            .map_err(|err| Error::Parse(format!("Invalid update: {}", err)))?;
+        debug!("Update {}", update.update_id);
+        let (message, edited) = match (update.message, update.edited_message) {
+            (Some(message), _) => (message, false),
+            (None, Some(message)) => (message, true),
+            (None, None) => {
+                debug!("Nothing to handle in update {}", update.update_id);
+                return Ok(None);
+            }
+        };
+        let text = match message.text {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        let username = message.from.and_then(|user| user.username);
+        let cmd = Command::from(Input {
+            id: message.message_id,
+            source: "telegram".to_string(),
+            chat: Some(message.chat.id),
+            user: username.unwrap_or_default(),
+            text,
+            is_new: !edited,
+            unixtime: message.date,
+        });
+        let reply = match self.ctrl.dispatch(cmd) {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        debug!("Reply to message #{}: {:?}", message.message_id, reply);
+        let response = SendMessage {
+            method: "sendMessage",
+            chat_id: message.chat.id,
+            reply_to_message_id: message.message_id,
+            text: reply,
+        };
+        serde_json::to_string(&response)
+            .map(Some)
+            .map_err(|err| Error::Parse(format!("Can't serialize reply: {}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{Local, NaiveDate, TimeZone};
+    use serde_json::{json, Value};
+
+    use crate::handler::accounts::{Account, AccountProvider, UserAccounts};
+    use crate::handler::categorizer::{
+        Category, CategoryProvider, LearnedWord, LearnedWordsProvider,
+    };
+    use crate::handler::clock::FakeClock;
+    use crate::handler::date_parser::UserDateShiftParsers;
+    use crate::handler::digest::DigestSchedule;
+    use crate::handler::events::{
+        BudgetRecord, EventHandler, HandlerEvent, Ledgers, RecordsProvider, SourceRef,
+        UserCurrencies,
+    };
+    use crate::handler::RawMessageParser;
+    use crate::input::sink::WriterSink;
+
+    use super::*;
+
+    const NEW_MESSAGE: &str = r#"{
+        "update_id": 10001,
+        "message": {
+            "message_id": 42,
+            "date": 1615550400,
+            "chat": {"id": -100500, "type": "group", "title": "Budget"},
+            "from": {"id": 7, "is_bot": false, "first_name": "Alice", "username": "alice"},
+            "text": "banana 4.5"
+        }
+    }"#;
+
+    const EDITED_MESSAGE: &str = r#"{
+        "update_id": 10002,
+        "edited_message": {
+            "message_id": 42,
+            "date": 1615550400,
+            "edit_date": 1615550460,
+            "chat": {"id": -100500, "type": "group", "title": "Budget"},
+            "from": {"id": 7, "is_bot": false, "first_name": "Alice", "username": "alice"},
+            "text": "banana 5"
+        }
+    }"#;
+
+    const STICKER: &str = r#"{
+        "update_id": 10003,
+        "message": {
+            "message_id": 43,
+            "date": 1615550400,
+            "chat": {"id": -100500, "type": "group"},
+            "sticker": {"file_id": "abc"}
+        }
+    }"#;
+
+    /// Clones share events, so records outlive a controller like in a real storage
+    #[derive(Default, Clone)]
+    struct MemoryStorage {
+        events: Arc<Mutex<Vec<HandlerEvent>>>,
+        learned: Vec<LearnedWord>,
+    }
+
+    impl EventHandler for MemoryStorage {
+        fn handle_event(&mut self, event: HandlerEvent) -> Result<HandlerEvent, Error> {
+            let mut events = self.events.lock().unwrap();
+            let event = match event {
+                HandlerEvent::AddRecord(mut record) => {
+                    let added = events.iter();
+                    let added = added.filter(|event| matches!(event, HandlerEvent::AddRecord(..)));
+                    record.id = added.count() as i64 + 1;
+                    HandlerEvent::AddRecord(record)
+                }
+                event => event,
+            };
+            events.push(event.clone());
+            Ok(event)
+        }
+    }
+
+    impl RecordsProvider for MemoryStorage {
+        fn records(&self, dates: Range<NaiveDate>) -> Result<Vec<BudgetRecord>, Error> {
+            let mut records = self.added_records();
+            records.retain(|record| dates.contains(&record.date));
+            Ok(records)
+        }
+
+        fn record_by_source(&self, source: &SourceRef) -> Result<Option<BudgetRecord>, Error> {
+            let mut records = self.added_records().into_iter();
+            Ok(records.find(|record| record.source == *source))
+        }
+
+        fn last_record_of(&self, user: &str) -> Result<Option<BudgetRecord>, Error> {
+            let records = self.added_records().into_iter();
+            Ok(records
+                .filter(|record| record.user == user)
+                .max_by_key(|record| record.id))
+        }
+    }
+
+    impl MemoryStorage {
+        /// Added records which are not deleted yet
+        fn added_records(&self) -> Vec<BudgetRecord> {
+            let events = self.events.lock().unwrap();
+            let deleted: Vec<_> = events
+                .iter()
+                .filter_map(|event| match event {
+                    HandlerEvent::DeleteRecord(id) => Some(*id),
+                    _ => None,
+                })
+                .collect();
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    HandlerEvent::AddRecord(record) if !deleted.contains(&record.id) => {
+                        Some(record.clone())
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    impl CategoryProvider for MemoryStorage {
+        fn categories(&self) -> Result<Vec<Category>, Error> {
+            Ok(vec![
+                Category::new("Fruits".to_string(), 1, "banana".into()),
+                Category::new("Sweets".to_string(), 2, "candy".into())
+                    .with_limit("10".parse().ok()),
+            ])
+        }
+    }
+
+    impl AccountProvider for MemoryStorage {
+        fn accounts(&self) -> Result<Vec<Account>, Error> {
+            Ok(vec![])
+        }
+    }
+
+    impl LearnedWordsProvider for MemoryStorage {
+        fn learned_words(&self) -> Result<Vec<LearnedWord>, Error> {
+            Ok(self.learned.clone())
+        }
+
+        fn learn_word(&mut self, word: &LearnedWord) -> Result<(), Error> {
+            self.learned.push(word.clone());
+            Ok(())
+        }
+    }
+
+    fn reader() -> WebhookReader {
+        WebhookReader::new(controller())
+    }
+
+    fn controller() -> MainController {
+        controller_with(MemoryStorage::default())
+    }
+
+    /// Controller with ledgers kept in `storage`
+    fn controller_with(storage: MemoryStorage) -> MainController {
+        let parser = RawMessageParser::new(
+            &MemoryStorage::default(),
+            UserDateShiftParsers::default(),
+            UserCurrencies::default(),
+            UserAccounts::default(),
+            None,
+        )
+        .unwrap();
+        let ledgers = Ledgers::new(move |_| Ok(Box::new(storage.clone())));
+        MainController::new(parser, ledgers)
+    }
+
+    fn message(key: &str, text: &str) -> String {
+        message_with_id(44, key, text)
+    }
+
+    fn message_with_id(id: i64, key: &str, text: &str) -> String {
+        json!({
+            "update_id": 10000 + id,
+            key: {
+                "message_id": id,
+                "date": 1615550400,
+                "chat": {"id": -100500, "type": "group"},
+                "from": {"id": 7, "is_bot": false, "username": "alice"},
+                "text": text
+            }
+        })
+        .to_string()
+    }
+
+    fn reply(body: Option<String>) -> Value {
+        serde_json::from_str(&body.expect("reply is expected")).unwrap()
+    }
+
+    #[test]
+    fn reply_to_new_message() {
+        let response = reply(reader().handle_update(NEW_MESSAGE).unwrap());
+        assert_eq!(response["method"], "sendMessage");
+        assert_eq!(response["chat_id"], -100500);
+        assert_eq!(response["reply_to_message_id"], 42);
+        let text = response["text"].as_str().unwrap();
+        assert!(text.starts_with("Added new record #"), "{}", text);
+    }
+
+    #[test]
+    fn reply_to_edited_message() {
+        let mut reader = reader();
+        let added = reply(reader.handle_update(NEW_MESSAGE).unwrap());
+        let added = added["text"].as_str().unwrap().lines().next().unwrap();
+        let id = added.trim_start_matches("Added new record ").to_string();
+
+        let response = reply(reader.handle_update(EDITED_MESSAGE).unwrap());
+        let text = response["text"].as_str().unwrap();
+        assert!(
+            text.starts_with(&format!("Updated existed record {}\n", id)),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("Amount: 5 RUB"), "{}", text);
+    }
+
+    #[test]
+    fn edited_message_without_record() {
+        let response = reply(reader().handle_update(EDITED_MESSAGE).unwrap());
+        assert_eq!(
+            response["text"],
+            "Storage error: Record of message telegram:-100500:42 is not found"
+        );
+    }
+
+    #[test]
+    fn edit_message_with_several_expenses() {
+        let mut reader = reader();
+        let added = reply(
+            reader
+                .handle_update(&message("message", "banana 4.5, banana 1"))
+                .unwrap(),
+        );
+        let added = added["text"].as_str().unwrap();
+        let ids: Vec<_> = added
+            .lines()
+            .filter_map(|line| line.strip_prefix("Added new record "))
+            .collect();
+        assert_eq!(ids.len(), 2, "{}", added);
+
+        let edit = message("edited_message", "banana 5");
+        let response = reply(reader.handle_update(&edit).unwrap());
+        let text = response["text"].as_str().unwrap();
+        assert!(
+            text.starts_with(&format!("Updated existed record {}\n", ids[0])),
+            "{}",
+            text
+        );
+        assert!(
+            text.ends_with(&format!("\n\nDeleted record {}", ids[1])),
+            "{}",
+            text
+        );
+
+        let edit = message("edited_message", "banana 5 and banana 2\nbanana 3");
+        let response = reply(reader.handle_update(&edit).unwrap());
+        let text = response["text"].as_str().unwrap();
+        assert_eq!(
+            text.matches("Updated existed record").count(),
+            1,
+            "{}",
+            text
+        );
+        assert_eq!(text.matches("Added new record").count(), 2, "{}", text);
+    }
+
+    #[test]
+    fn warn_about_exceeded_budget() {
+        let mut reader = reader();
+        let response = reply(
+            reader
+                .handle_update(&message("message", "candy 6"))
+                .unwrap(),
+        );
+        let text = response["text"].as_str().unwrap();
+        assert!(
+            text.ends_with("\nSweets: 6 of 10 this month (60%)"),
+            "{}",
+            text
+        );
+
+        let response = reply(
+            reader
+                .handle_update(&message("message", "candy 5"))
+                .unwrap(),
+        );
+        let text = response["text"].as_str().unwrap();
+        assert!(
+            text.ends_with(
+                "\nSweets: 11 of 10 this month (110%)\nWarning: Sweets budget is exceeded by 1"
+            ),
+            "{}",
+            text
+        );
+    }
+
+    fn category_of(response: &Value) -> &str {
+        let text = response["text"].as_str().unwrap();
+        text.lines()
+            .find_map(|line| line.strip_prefix("Category: "))
+            .unwrap_or_else(|| panic!("no category in {}", text))
+    }
+
+    #[test]
+    fn learn_category_from_command() {
+        let mut reader = reader();
+        let added = reply(
+            reader
+                .handle_update(&message("message", "ashan 40"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&added), "Sweets");
+
+        let changed = reply(
+            reader
+                .handle_update(&message("message", "/category fruits"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&changed), "Fruits");
+        let text = changed["text"].as_str().unwrap();
+        assert!(
+            text.ends_with("\n\nRemembered for Fruits: ashan"),
+            "{}",
+            text
+        );
+
+        let added = reply(
+            reader
+                .handle_update(&message("message", "Ashan 2"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&added), "Fruits");
+        let unknown = reply(
+            reader
+                .handle_update(&message("message", "/category cars"))
+                .unwrap(),
+        );
+        assert_eq!(unknown["text"], "Unknown category 'cars'");
+    }
+
+    #[test]
+    fn undo_with_controller_per_update() {
+        // Like in Lambda, where every update is handled by a new controller
+        let storage = MemoryStorage::default();
+        let handle = |update: String| {
+            let mut reader = WebhookReader::new(controller_with(storage.clone()));
+            reply(reader.handle_update(&update).unwrap())
+        };
+        handle(message_with_id(50, "message", "banana 4"));
+        handle(message_with_id(51, "message", "candy 3"));
+
+        let changed = handle(message_with_id(52, "message", "/category fruits"));
+        assert_eq!(category_of(&changed), "Fruits");
+        let undone = handle(message_with_id(53, "message", "/undo"));
+        assert_eq!(undone["text"], "Deleted record #2");
+        let descs: Vec<_> = storage
+            .added_records()
+            .into_iter()
+            .map(|r| r.desc)
+            .collect();
+        assert_eq!(descs, vec!["banana"]);
+    }
+
+    #[test]
+    fn learn_category_only_when_named() {
+        let mut reader = reader();
+        reader
+            .handle_update(&message("message", "ashan 4"))
+            .unwrap();
+        let edited = reply(
+            reader
+                .handle_update(&message("edited_message", "ashan banana 4"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&edited), "Fruits");
+        let text = edited["text"].as_str().unwrap();
+        assert!(!text.contains("Remembered"), "{}", text);
+        let added = reply(
+            reader
+                .handle_update(&message_with_id(45, "message", "ashan 3"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&added), "Sweets");
+
+        let edited = reply(
+            reader
+                .handle_update(&message_with_id(45, "edited_message", "ashan 3 #fruits"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&edited), "Fruits");
+        let text = edited["text"].as_str().unwrap();
+        assert!(
+            text.ends_with("\n\nRemembered for Fruits: ashan"),
+            "{}",
+            text
+        );
+        let added = reply(
+            reader
+                .handle_update(&message_with_id(46, "message", "ashan 2"))
+                .unwrap(),
+        );
+        assert_eq!(category_of(&added), "Fruits");
+    }
+
+    #[test]
+    fn override_category() {
+        let cmd = Command::from(Input {
+            id: 1,
+            source: "telegram".to_string(),
+            chat: Some(-100500),
+            user: "alice".to_string(),
+            text: "candy #FRUITS 5".to_string(),
+            is_new: true,
+            unixtime: 1615550400,
+        });
+        let output = controller().execute(cmd).unwrap();
+        match output.events.as_slice() {
+            [HandlerEvent::AddRecord(record)] => {
+                assert_eq!(record.category, "Fruits");
+                assert_eq!(record.desc, "candy");
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+
+        let mut reader = reader();
+        let response = reply(
+            reader
+                .handle_update(&message("message", "candy cat:frutis 5"))
+                .unwrap(),
+        );
+        assert_eq!(
+            response["text"],
+            "Unknown category 'frutis', did you mean Fruits?"
+        );
+    }
+
+    #[test]
+    fn report_on_tag() {
+        let mut reader = reader();
+        let added = reply(
+            reader
+                .handle_update(&message("message", "banana 4 #Fruits +Trip, candy 2 #kids"))
+                .unwrap(),
+        );
+        let text = added["text"].as_str().unwrap();
+        assert_eq!(text.matches("\nTags: trip, kids\n").count(), 2, "{}", text);
+        reader
+            .handle_update(&message("message", "banana 10"))
+            .unwrap();
+
+        let report = reply(
+            reader
+                .handle_update(&message("message", "/report #trip"))
+                .unwrap(),
+        );
+        let text = report["text"].as_str().unwrap();
+        assert!(
+            text.starts_with("Report for 2021-03-01 - 2021-03-31 tagged trip\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("\nTotal: 6.00 RUB"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn send_weekly_digest() {
+        let clock = FakeClock::new(Local.ymd(2021, 3, 12).and_hms(12, 0, 0));
+        let mut controller = controller()
+            .with_clock(clock.clone())
+            .with_digests(DigestSchedule::from_config("-100500:weekly mon 09:00"));
+        for (id, text) in [(1, "banana 4.5"), (2, "candy 3")].iter() {
+            let cmd = Command::from(Input {
+                id: *id,
+                source: "telegram".to_string(),
+                chat: Some(-100500),
+                user: "alice".to_string(),
+                text: text.to_string(),
+                is_new: true,
+                unixtime: 1615550400,
+            });
+            controller.dispatch(cmd);
+        }
+        let sink = WriterSink::new(Vec::new());
+        controller.send_digests(&sink).await;
+        clock.set(Local.ymd(2021, 3, 15).and_hms(10, 0, 0));
+        controller.send_digests(&sink).await;
+        controller.send_digests(&sink).await;
+
+        let sent = String::from_utf8(sink.into_inner()).unwrap();
+        assert!(
+            sent.starts_with("=> [-100500] Weekly digest\nReport for 2021-03-08 - 2021-03-14\n"),
+            "{}",
+            sent
+        );
+        assert!(
+            sent.ends_with(
+                "Top expenses:\n  2021-03-12 Fruits: 4.50 RUB (banana)\n  \
+                 2021-03-12 Sweets: 3.00 RUB (candy)\n"
+            ),
+            "{}",
+            sent
+        );
+        assert_eq!(sent.matches("Weekly digest").count(), 1, "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn send_digests_within_cron_interval() {
+        let clock = FakeClock::new(Local.ymd(2021, 3, 15).and_hms(9, 30, 0));
+        let mut controller = controller()
+            .with_clock(clock.clone())
+            .with_digests(DigestSchedule::from_config("-100500:weekly mon 09:00"));
+        let sink = WriterSink::new(Vec::new());
+        controller
+            .send_digests_within(chrono::Duration::hours(1), &sink)
+            .await;
+        // The next run an hour later doesn't repeat it, even without the previous check
+        clock.set(Local.ymd(2021, 3, 15).and_hms(10, 30, 0));
+        controller
+            .send_digests_within(chrono::Duration::hours(1), &sink)
+            .await;
+
+        let sent = String::from_utf8(sink.into_inner()).unwrap();
+        assert!(
+            sent.starts_with("=> [-100500] Weekly digest\nReport for 2021-03-08 - 2021-03-14\n"),
+            "{}",
+            sent
+        );
+        assert_eq!(sent.matches("Weekly digest").count(), 1, "{}", sent);
+    }
+
+    #[test]
+    fn ignore_message_without_text() {
+        assert_eq!(reader().handle_update(STICKER), Ok(None));
+    }
+
+    #[test]
+    fn invalid_update() {
+        assert!(matches!(reader().handle_update("{}"), Err(Error::Parse(_))));
+    }
+}
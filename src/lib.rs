@@ -3,30 +3,73 @@ extern crate chrono;
 extern crate lazy_static;
 extern crate regex;
 #[macro_use]
-extern crate serde_derive;
+extern crate serde;
 
 use log::*;
 
 use crate::{
-    handler::{events::DefaultEventHandler, RawMessageParser},
-    input::{CommandReader, DefaultCommandReader, MainController},
+    error::Error,
+    handler::{
+        accounts::UserAccounts,
+        date_parser::UserDateShiftParsers,
+        digest::DigestSchedule,
+        events::{Ledgers, StorageKind, UserCurrencies, DEFAULT_LEDGER},
+        rates::CurrencyConverter,
+        RawMessageParser,
+    },
+    input::{MainController, ReaderKind, WebhookReader},
 };
 
+pub mod error;
 pub mod handler;
 mod input;
 
-pub async fn start() -> Result<(), String> {
-    let handler = DefaultEventHandler::new();
-    let command_reader = DefaultCommandReader::new(MainController {
-        parser: RawMessageParser::new(&handler),
-        handler: Box::new(handler),
-    });
+pub async fn start() -> Result<(), Error> {
+    let (storage, controller) = create_controller()?;
+    let command_reader = ReaderKind::from_env()?.create(controller)?;
 
-    info!("Started with {} input handler", command_reader.name());
+    info!(
+        "Started with {} input handler and {:?} storage",
+        command_reader.name(),
+        storage
+    );
     command_reader
         .start()
         .await
-        .map_err(|err| format!("Reader error: {}", err))
+        .map_err(|err| Error::Network(format!("Reader error: {}", err)))
+}
+
+/// Handle a single Telegram update pushed to the webhook and return the response body
+pub fn handle_webhook(update: &str) -> Result<Option<String>, Error> {
+    let (_, controller) = create_controller()?;
+    WebhookReader::new(controller).handle_update(update)
+}
+
+/// Send digests scheduled since the previous run of an external cron, e.g. EventBridge rule,
+/// which has to fire every `BUDGET_DIGESTS_INTERVAL` minutes
+#[cfg(feature = "telegram")]
+pub async fn send_digests() -> Result<(), Error> {
+    let interval = handler::digest::cron_interval_from_env()?;
+    let (_, mut controller) = create_controller()?;
+    controller
+        .send_digests_within(interval, &input::TelegramSender::from_env())
+        .await;
+    Ok(())
+}
+
+fn create_controller() -> Result<(StorageKind, MainController), Error> {
+    let storage = StorageKind::from_env()?;
+    let mut ledgers = Ledgers::from_env(storage)?;
+    // Categories and accounts are common for all ledgers
+    let parser = RawMessageParser::new(
+        ledgers.storage(DEFAULT_LEDGER)?,
+        UserDateShiftParsers::from_env(),
+        UserCurrencies::from_env(),
+        UserAccounts::from_env(),
+        CurrencyConverter::from_env()?,
+    )?;
+    let controller = MainController::new(parser, ledgers).with_digests(DigestSchedule::from_env());
+    Ok((storage, controller))
 }
 
 // Cli/Telegram => parse msg => update db => generate response
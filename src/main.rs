@@ -1,7 +1,7 @@
-use tg_bot_playground::start;
+use tg_bot_playground::{error::Error, start};
 
 #[tokio::main]
-async fn main() -> Result<(), String> {
+async fn main() -> Result<(), Error> {
     env_logger::init();
     start().await
 }